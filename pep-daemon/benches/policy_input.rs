@@ -0,0 +1,32 @@
+//! Compare building policy input through a JSON string with building the
+//! Rego value directly.
+//!
+//! Run with `cargo bench --manifest-path pep-daemon/Cargo.toml`.
+
+use avf_vsock_host::PolicyInput;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 100_000;
+
+fn bench(label: &str, build: impl Fn(&PolicyInput) -> regorus::Value) -> Duration {
+    let url = reqwest::Url::parse("https://api.example.com/v1/items?page=2").expect("url");
+    let input = PolicyInput::from_http_url(&url, "GET");
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(build(black_box(&input)));
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{label:<12} {per_iter:?} per input");
+    per_iter
+}
+
+fn main() {
+    bench("json", |input| {
+        serde_json::to_string(input)
+            .ok()
+            .and_then(|json| regorus::Value::from_json_str(&json).ok())
+            .unwrap_or(regorus::Value::Undefined)
+    });
+    bench("direct", PolicyInput::to_regorus_value);
+}
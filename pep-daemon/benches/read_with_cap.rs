@@ -0,0 +1,29 @@
+//! Compare pre-sized (`Content-Length` hint) and unhinted body reads.
+//!
+//! Run with `cargo bench --manifest-path pep-daemon/Cargo.toml`.
+
+use avf_vsock_host::http_exec::read_with_cap_hint;
+use std::hint::black_box;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+const BODY_BYTES: usize = 10 * 1024 * 1024;
+const ITERATIONS: u32 = 50;
+
+fn bench(label: &str, payload: &[u8], size_hint: Option<u64>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut cursor = Cursor::new(payload);
+        let body = read_with_cap_hint(&mut cursor, BODY_BYTES, size_hint);
+        black_box(body.map(|body| body.len()).unwrap_or(0));
+    }
+    let per_iter = start.elapsed() / ITERATIONS;
+    println!("{label:<12} {per_iter:?} per 10 MB body");
+    per_iter
+}
+
+fn main() {
+    let payload = vec![0xAB_u8; BODY_BYTES];
+    bench("unhinted", &payload, None);
+    bench("presized", &payload, Some(BODY_BYTES as u64));
+}
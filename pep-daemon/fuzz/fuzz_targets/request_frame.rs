@@ -0,0 +1,32 @@
+//! Untrusted VM bytes through the same path as `handle_connection`: frames
+//! are read under a cap and each payload is parsed as a request. Any panic,
+//! or a frame past the cap, is a bug.
+
+#![no_main]
+
+use std::io::Cursor;
+
+use avf_vsock_host::HttpRequest;
+use avf_vsock_host::framing::{frame_too_large, read_frame_with_limit};
+use libfuzzer_sys::fuzz_target;
+
+/// Small enough to keep iterations fast, large enough to cover chunked reads.
+const MAX_FRAME_BYTES: usize = 256 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = Cursor::new(data);
+    loop {
+        match read_frame_with_limit(&mut stream, MAX_FRAME_BYTES) {
+            Ok(frame) => {
+                assert!(frame.len() <= MAX_FRAME_BYTES);
+                let _ = HttpRequest::from_frame(&frame);
+            }
+            Err(err) => {
+                if let Some(too_large) = frame_too_large(&err) {
+                    assert!(too_large.len > too_large.max);
+                }
+                break;
+            }
+        }
+    }
+});
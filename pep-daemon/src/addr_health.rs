@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most addresses handed to the connector per lookup; it tries them in
+/// order, so this caps connect attempts per request.
+pub const MAX_ADDR_ATTEMPTS: usize = 3;
+/// How long a refused address stays behind healthy ones.
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Per-address connect health for hosts that resolve to several public
+/// addresses. Healthy addresses are rotated round-robin and handed out ahead
+/// of ones that recently failed to connect.
+///
+/// Connect outcomes are inferred from which address a response came from, so
+/// with concurrent lookups for one host attribution is best-effort.
+#[derive(Debug, Default)]
+pub struct AddrHealth {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Addresses that failed to connect, and when they become healthy again.
+    failed_until: HashMap<IpAddr, Instant>,
+    /// Round-robin cursor per host.
+    next: HashMap<String, usize>,
+    /// Order of the latest lookup per host not yet matched to an outcome.
+    handed_out: HashMap<String, Vec<IpAddr>>,
+}
+
+impl AddrHealth {
+    /// Order `addrs` for a connect to `host`: healthy ones first, starting at
+    /// the host's round-robin cursor, then failed ones soonest-to-recover
+    /// first, truncated to `MAX_ADDR_ATTEMPTS`.
+    pub fn order(&self, host: &str, addrs: &[SocketAddr], now: Instant) -> Vec<SocketAddr> {
+        let Ok(mut inner) = self.inner.lock() else {
+            return addrs.iter().copied().take(MAX_ADDR_ATTEMPTS).collect();
+        };
+        inner.failed_until.retain(|_, until| *until > now);
+
+        let (mut healthy, mut failed): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+            .iter()
+            .partition(|addr| !inner.failed_until.contains_key(&addr.ip()));
+        if !healthy.is_empty() {
+            let cursor = inner.next.entry(host.to_string()).or_insert(0);
+            let start = *cursor % healthy.len();
+            *cursor = cursor.wrapping_add(1);
+            healthy.rotate_left(start);
+        }
+        failed.sort_by_key(|addr| inner.failed_until.get(&addr.ip()).copied());
+
+        let ordered: Vec<SocketAddr> = healthy
+            .into_iter()
+            .chain(failed)
+            .take(MAX_ADDR_ATTEMPTS)
+            .collect();
+        inner.handed_out.insert(
+            host.to_string(),
+            ordered.iter().map(SocketAddr::ip).collect(),
+        );
+        ordered
+    }
+
+    /// A response for `host` arrived from `addr`. Addresses handed out ahead
+    /// of it were tried first and refused.
+    pub fn record_connected(&self, host: &str, addr: IpAddr, now: Instant) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        inner.failed_until.remove(&addr);
+        // Absent for a pooled connection, which involved no new lookup.
+        let Some(order) = inner.handed_out.remove(host) else {
+            return;
+        };
+        if let Some(position) = order.iter().position(|ip| *ip == addr) {
+            for ip in &order[..position] {
+                inner.failed_until.insert(*ip, now + FAILURE_COOLDOWN);
+            }
+        }
+    }
+
+    /// No address handed out for `host` accepted a connection.
+    pub fn record_connect_failure(&self, host: &str, now: Instant) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        for ip in inner.handed_out.remove(host).unwrap_or_default() {
+            inner.failed_until.insert(ip, now + FAILURE_COOLDOWN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::blocking::Client;
+    use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn addrs(raw: &[&str]) -> Vec<SocketAddr> {
+        raw.iter()
+            .map(|addr| addr.parse().expect("socket addr"))
+            .collect()
+    }
+
+    #[test]
+    fn healthy_addresses_rotate_round_robin() {
+        let health = AddrHealth::default();
+        let set = addrs(&["93.184.216.34:0", "93.184.216.35:0"]);
+        let now = Instant::now();
+        assert_eq!(health.order("example.com", &set, now), set);
+        assert_eq!(health.order("example.com", &set, now), vec![set[1], set[0]]);
+        assert_eq!(health.order("example.com", &set, now), set);
+    }
+
+    #[test]
+    fn refused_address_moves_behind_healthy_ones_until_cooldown() {
+        let health = AddrHealth::default();
+        let set = addrs(&["93.184.216.34:0", "93.184.216.35:0", "93.184.216.36:0"]);
+        let now = Instant::now();
+
+        // The first address refused; the response came from the second.
+        let first = health.order("example.com", &set, now);
+        health.record_connected("example.com", first[1].ip(), now);
+
+        for _ in 0..3 {
+            let ordered = health.order("example.com", &set, now);
+            assert_eq!(ordered.last(), Some(&first[0]));
+        }
+        let later = now + FAILURE_COOLDOWN + Duration::from_secs(1);
+        let recovered = health.order("example.com", &set, later);
+        assert!(recovered.contains(&first[0]));
+        assert!(health.inner.lock().expect("lock").failed_until.is_empty());
+    }
+
+    #[test]
+    fn connect_failure_marks_every_handed_out_address() {
+        let health = AddrHealth::default();
+        let set = addrs(&["93.184.216.34:0", "93.184.216.35:0"]);
+        let now = Instant::now();
+        health.order("example.com", &set, now);
+        health.record_connect_failure("example.com", now);
+
+        let inner = health.inner.lock().expect("lock");
+        assert_eq!(inner.failed_until.len(), 2);
+        assert!(inner.handed_out.is_empty());
+    }
+
+    /// Hands fixed loopback addresses out through `AddrHealth`, standing in
+    /// for `PublicAddrResolver`, whose vetting would reject them.
+    struct FixedResolver {
+        addrs: Vec<SocketAddr>,
+        health: Arc<AddrHealth>,
+    }
+
+    impl Resolve for FixedResolver {
+        fn resolve(&self, name: Name) -> Resolving {
+            let ordered = self
+                .health
+                .order(name.as_str(), &self.addrs, Instant::now());
+            Box::pin(async move {
+                let addrs: Addrs = Box::new(ordered.into_iter());
+                Ok(addrs)
+            })
+        }
+    }
+
+    #[test]
+    fn connector_moves_past_refusing_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let port = listener.local_addr().expect("addr").port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .expect("write");
+        });
+
+        // Nothing listens on the IPv6 loopback, so the first address refuses.
+        let refusing = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        let serving = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let health = Arc::new(AddrHealth::default());
+        let client = Client::builder()
+            .dns_resolver(Arc::new(FixedResolver {
+                addrs: vec![refusing, serving],
+                health: Arc::clone(&health),
+            }))
+            .build()
+            .expect("client");
+
+        let response = client.get("http://multi.test/").send().expect("send");
+        assert_eq!(response.remote_addr(), Some(serving));
+        health.record_connected("multi.test", serving.ip(), Instant::now());
+        assert_eq!(response.text().expect("body"), "ok");
+        server.join().expect("server");
+
+        assert_eq!(
+            health.order("multi.test", &[refusing, serving], Instant::now()),
+            vec![serving, refusing]
+        );
+    }
+
+    #[test]
+    fn attempts_are_capped() {
+        let health = AddrHealth::default();
+        let set = addrs(&[
+            "93.184.216.34:0",
+            "93.184.216.35:0",
+            "93.184.216.36:0",
+            "93.184.216.37:0",
+        ]);
+        let ordered = health.order("example.com", &set, Instant::now());
+        assert_eq!(ordered.len(), MAX_ADDR_ATTEMPTS);
+    }
+}
@@ -1,12 +1,22 @@
-use crate::config::PepConfig;
-use crate::policy::PolicyDecision;
-use crate::types::HttpRequest;
+use crate::audit_http::HttpSink;
+use crate::clock::{Clock, unix_millis};
+use crate::config::{AuditFormat, AuditSinkKind, PepConfig};
+use crate::http_exec::sanitize_url_string;
+use crate::policy::{PolicyDecision, PolicyInput, ShadowDivergence};
+use crate::types::{HttpRequest, RequestContext};
+use reqwest::Url;
 use serde::Serialize;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{Value, json};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct AuditEntry {
     pub ts_unix_ms: u64,
     pub method: String,
@@ -15,18 +25,516 @@ pub struct AuditEntry {
     pub error_code: Option<String>,
     pub request_bytes: usize,
     pub response_bytes: usize,
+    /// Hex SHA-256 of the body delivered to the VM; absent without a body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_sha256: Option<String>,
     pub redirects: u32,
     pub decision: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub policy_hash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub decision_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_cid: Option<u32>,
+    /// True when the shadow policy disagreed; details in `shadow`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub shadow_divergence: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<ShadowDivergence>,
+    /// True when `PEP_ALLOW_PRIVATE_HOSTS` skipped the SSRF guard.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub ssrf_bypassed: bool,
+    /// `PEP_DLP_PATTERNS` category that blocked the body; never its content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dlp_category: Option<String>,
+    /// How the delivered body missed the upstream's `Content-Length`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_length_mismatch: Option<String>,
+    /// True for a preflight check; nothing was sent upstream.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub preflight: bool,
+    /// With `PEP_AUDIT_HEADERS`, the headers as sent by the VM and as
+    /// returned upstream, `SENSITIVE_HEADERS` masked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_headers: Option<Vec<(String, String)>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<Vec<(String, String)>>,
+}
+
+/// Headers whose values are masked wherever headers are audited.
+pub const SENSITIVE_HEADERS: [&str; 7] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+    "x-amz-security-token",
+];
+
+/// `headers` with the values of `SENSITIVE_HEADERS` replaced.
+pub fn redact_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let sensitive = SENSITIVE_HEADERS
+                .iter()
+                .any(|sensitive| name.eq_ignore_ascii_case(sensitive));
+            let value = if sensitive { "redacted" } else { value };
+            (name.clone(), value.to_string())
+        })
+        .collect()
+}
+
+// ── Line formats ────────────────────────────────────────────────────────
+
+/// Turns an `AuditEntry` into a single log line (without trailing newline).
+pub trait AuditFormatter {
+    fn format(&self, entry: &AuditEntry) -> Result<String, serde_json::Error>;
+}
+
+/// Compact `AuditEntry` JSON (the default).
+pub struct JsonlFormatter;
+
+impl AuditFormatter for JsonlFormatter {
+    fn format(&self, entry: &AuditEntry) -> Result<String, serde_json::Error> {
+        serde_json::to_string(entry)
+    }
+}
+
+/// OpenTelemetry log record, using the OTLP/JSON encoding (64-bit integers
+/// as strings, typed `AnyValue` attributes).
+pub struct OtelFormatter;
+
+impl AuditFormatter for OtelFormatter {
+    fn format(&self, entry: &AuditEntry) -> Result<String, serde_json::Error> {
+        let (severity_number, severity_text) = if entry.decision == "deny" {
+            (13, "WARN")
+        } else {
+            (9, "INFO")
+        };
+
+        let mut attributes = vec![
+            otel_string("http.request.method", &entry.method),
+            otel_string("url.full", &entry.url),
+            otel_int("http.response.status_code", entry.status as u64),
+            otel_string("pep.decision", &entry.decision),
+            otel_int("pep.request_bytes", entry.request_bytes as u64),
+            otel_int("pep.response_bytes", entry.response_bytes as u64),
+            otel_int("pep.redirects", entry.redirects as u64),
+        ];
+        if let Some(code) = &entry.error_code {
+            attributes.push(otel_string("pep.error_code", code));
+        }
+        if let Some(digest) = &entry.response_sha256 {
+            attributes.push(otel_string("pep.response_sha256", digest));
+        }
+        if let Some(hash) = &entry.policy_hash {
+            attributes.push(otel_string("pep.policy_hash", hash));
+        }
+        if let Some(id) = &entry.decision_id {
+            attributes.push(otel_string("pep.decision_id", id));
+        }
+        if let Some(id) = &entry.request_id {
+            attributes.push(otel_string("pep.request_id", id));
+        }
+        if let Some(cid) = entry.peer_cid {
+            attributes.push(otel_int("pep.peer_cid", cid as u64));
+        }
+        if entry.ssrf_bypassed {
+            attributes.push(otel_bool("pep.ssrf_bypassed", true));
+        }
+        if let Some(category) = &entry.dlp_category {
+            attributes.push(otel_string("pep.dlp_category", category));
+        }
+        if let Some(mismatch) = &entry.content_length_mismatch {
+            attributes.push(otel_string("pep.content_length_mismatch", mismatch));
+        }
+        if entry.preflight {
+            attributes.push(otel_bool("pep.preflight", true));
+        }
+        let headers = [
+            ("http.request.header", &entry.request_headers),
+            ("http.response.header", &entry.response_headers),
+        ];
+        for (prefix, headers) in headers {
+            for (name, value) in headers.iter().flatten() {
+                let key = format!("{prefix}.{}", name.to_lowercase());
+                attributes.push(otel_string(&key, value));
+            }
+        }
+        if let Some(shadow) = &entry.shadow {
+            attributes.push(otel_bool("pep.shadow_divergence", true));
+            attributes.push(otel_bool("pep.shadow.allow", shadow.shadow_allow));
+            if let Some(reason) = &shadow.active_reason {
+                attributes.push(otel_string("pep.active.reason", reason));
+            }
+            if let Some(reason) = &shadow.shadow_reason {
+                attributes.push(otel_string("pep.shadow.reason", reason));
+            }
+            attributes.push(otel_string(
+                "pep.shadow.policy_hash",
+                &shadow.shadow_policy_hash,
+            ));
+        }
+
+        let record = json!({
+            "timeUnixNano": (entry.ts_unix_ms as u128 * 1_000_000).to_string(),
+            "severityNumber": severity_number,
+            "severityText": severity_text,
+            "body": { "stringValue": format!("http.request {}", entry.decision) },
+            "attributes": attributes,
+        });
+        serde_json::to_string(&record)
+    }
+}
+
+fn otel_string(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn otel_int(key: &str, value: u64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+fn otel_bool(key: &str, value: bool) -> Value {
+    json!({ "key": key, "value": { "boolValue": value } })
+}
+
+pub(crate) fn formatter_for(format: AuditFormat) -> &'static dyn AuditFormatter {
+    match format {
+        AuditFormat::Jsonl => &JsonlFormatter,
+        AuditFormat::Otel => &OtelFormatter,
+    }
+}
+
+// ── Append ──────────────────────────────────────────────────────────────
+
+/// Fail fast if the audit log cannot be opened for append. The audit writer
+/// swallows write errors so it never breaks a request, which makes a bad path
+/// otherwise invisible. No line is written, so the log stays entries-only.
+pub fn verify_audit_writable(path: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(|_| ())
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("audit log {} is not writable: {err}", path.display()),
+            )
+        })
+}
+
+// ── Sinks ───────────────────────────────────────────────────────────────
+
+/// Where audit entries are delivered. Recording must never fail or stall a
+/// request, so sinks swallow their own delivery errors; only
+/// `record_confirmed`, for `PEP_AUDIT_FAIL_CLOSED`, waits and reports them.
+pub trait AuditSink: fmt::Debug + Send + Sync {
+    fn record(&self, entry: &AuditEntry);
+
+    /// Record `entry` and wait until it is persisted, for
+    /// `PEP_AUDIT_FAIL_CLOSED`. Sinks that cannot confirm delivery refuse.
+    fn record_confirmed(&self, _entry: &AuditEntry) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "audit sink cannot confirm writes",
+        ))
+    }
+
+    /// Block until every entry recorded so far has been delivered.
+    fn flush(&self) {}
+
+    /// Time stamped on entries built for this sink.
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// One request's view of the PEP's sink, stamping entries from the PEP's
+/// clock. Under `PEP_AUDIT_FAIL_CLOSED` each entry is confirmed written, and
+/// a failure is remembered so the request's response can be withheld.
+#[derive(Debug)]
+pub struct RequestAudit<'a> {
+    sink: &'a dyn AuditSink,
+    clock: &'a dyn Clock,
+    fail_closed: bool,
+    failed: AtomicBool,
+}
+
+impl<'a> RequestAudit<'a> {
+    pub fn new(sink: &'a dyn AuditSink, clock: &'a dyn Clock, fail_closed: bool) -> Self {
+        Self {
+            sink,
+            clock,
+            fail_closed,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether an entry could not be confirmed (always false when not
+    /// failing closed).
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+}
+
+impl AuditSink for RequestAudit<'_> {
+    fn record(&self, entry: &AuditEntry) {
+        if !self.fail_closed {
+            self.sink.record(entry);
+        } else if let Err(err) = self.sink.record_confirmed(entry) {
+            eprintln!("audit entry not written: {err}");
+            self.failed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn record_confirmed(&self, entry: &AuditEntry) -> io::Result<()> {
+        self.sink.record_confirmed(entry)
+    }
+
+    fn flush(&self) {
+        self.sink.flush();
+    }
+
+    fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+}
+
+/// Sink selected by `PEP_AUDIT_SINK`.
+pub fn audit_sink_for(config: &PepConfig) -> io::Result<Box<dyn AuditSink>> {
+    Ok(match config.audit_sink {
+        AuditSinkKind::File => Box::new(FileSink::spawn(
+            config.audit_log_path.clone(),
+            config.audit_format,
+        )?),
+        AuditSinkKind::Stdout => Box::new(StdoutSink::new(config.audit_format)),
+        AuditSinkKind::Http if config.audit_fail_closed => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PEP_AUDIT_FAIL_CLOSED needs a sink that confirms writes (file or stdout)",
+            ));
+        }
+        AuditSinkKind::Http => {
+            let url = config.audit_http_url.as_deref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "PEP_AUDIT_SINK=http requires PEP_AUDIT_HTTP_URL",
+                )
+            })?;
+            let url = Url::parse(url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "PEP_AUDIT_HTTP_URL must be an http(s) URL",
+                    )
+                })?;
+            Box::new(HttpSink::spawn(
+                url,
+                config.audit_format,
+                config.audit_http_buffer,
+            )?)
+        }
+        AuditSinkKind::Null => Box::new(NullSink),
+    })
+}
+
+/// One line per entry on stdout, for deployments that collect logs there.
+#[derive(Debug)]
+pub struct StdoutSink {
+    format: AuditFormat,
+}
+
+impl StdoutSink {
+    pub fn new(format: AuditFormat) -> Self {
+        Self { format }
+    }
+}
+
+impl AuditSink for StdoutSink {
+    fn record(&self, entry: &AuditEntry) {
+        if let Ok(line) = formatter_for(self.format).format(entry) {
+            let _ = writeln!(io::stdout().lock(), "{line}");
+        }
+    }
+
+    fn record_confirmed(&self, entry: &AuditEntry) -> io::Result<()> {
+        let line = formatter_for(self.format).format(entry)?;
+        let mut out = io::stdout().lock();
+        writeln!(out, "{line}")?;
+        out.flush()
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Discards every entry.
+#[derive(Debug)]
+pub struct NullSink;
+
+impl AuditSink for NullSink {
+    fn record(&self, _entry: &AuditEntry) {}
+
+    /// Discarding is what this sink was configured to do.
+    fn record_confirmed(&self, _entry: &AuditEntry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum AuditMessage {
+    Line(String),
+    /// A line whose sender waits to learn whether it was written and flushed.
+    Confirmed(String, mpsc::SyncSender<bool>),
+    /// Acknowledged once everything sent before it is written and flushed.
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// Appends to the audit log from a dedicated thread (the default sink).
+/// Handlers format the line and hand it over a channel, never waiting on
+/// disk; the thread writes whatever has queued as one batch and flushes it.
+/// Dropping the sink drains the queue and joins the thread, so entries are
+/// not lost on shutdown.
+pub struct FileSink {
+    format: AuditFormat,
+    sender: Option<mpsc::Sender<AuditMessage>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileSink {
+    pub fn spawn(path: PathBuf, format: AuditFormat) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("pep-audit".to_string())
+            .spawn(move || run_audit_writer(&path, receiver))?;
+        Ok(Self {
+            format,
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn record(&self, entry: &AuditEntry) {
+        if let (Some(sender), Ok(line)) = (&self.sender, formatter_for(self.format).format(entry)) {
+            let _ = sender.send(AuditMessage::Line(line));
+        }
+    }
+
+    fn record_confirmed(&self, entry: &AuditEntry) -> io::Result<()> {
+        let line = formatter_for(self.format).format(entry)?;
+        let (ack, done) = mpsc::sync_channel(1);
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| io::Error::other("audit writer stopped"))?;
+        sender
+            .send(AuditMessage::Confirmed(line, ack))
+            .map_err(|_| io::Error::other("audit writer stopped"))?;
+        match done.recv() {
+            Ok(true) => Ok(()),
+            _ => Err(io::Error::other("audit log not writable")),
+        }
+    }
+
+    /// Block until every entry recorded so far is on disk.
+    fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        if let Some(sender) = &self.sender
+            && sender.send(AuditMessage::Flush(ack)).is_ok()
+        {
+            let _ = done.recv();
+        }
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread drain what is queued and exit.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for FileSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileSink")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+fn run_audit_writer(path: &Path, receiver: mpsc::Receiver<AuditMessage>) {
+    // Write errors are swallowed so auditing never breaks a request; the
+    // file is reopened on the next batch after a failure.
+    let mut file: Option<BufWriter<File>> = None;
+    while let Ok(first) = receiver.recv() {
+        let mut acks = Vec::new();
+        let mut confirms = Vec::new();
+        for message in std::iter::once(first).chain(receiver.try_iter()) {
+            match message {
+                AuditMessage::Line(line) => {
+                    write_audit_line(path, &mut file, &line);
+                }
+                AuditMessage::Confirmed(line, ack) => {
+                    let written = write_audit_line(path, &mut file, &line);
+                    confirms.push((ack, written));
+                }
+                AuditMessage::Flush(ack) => acks.push(ack),
+            }
+        }
+        let flushed = match &mut file {
+            Some(out) => out.flush().is_ok(),
+            None => false,
+        };
+        if !flushed {
+            file = None;
+        }
+        for ack in acks {
+            let _ = ack.send(());
+        }
+        for (ack, written) in confirms {
+            let _ = ack.send(written && flushed);
+        }
+    }
+}
+
+/// Append `line`, opening the log first if needed. A failed write drops the
+/// handle so the next line reopens it.
+fn write_audit_line(path: &Path, file: &mut Option<BufWriter<File>>, line: &str) -> bool {
+    if file.is_none() {
+        *file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .ok()
+            .map(BufWriter::new);
+    }
+    let written = file
+        .as_mut()
+        .is_some_and(|out| writeln!(out, "{line}").is_ok());
+    if !written {
+        *file = None;
+    }
+    written
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn append_audit_entry(
-    config: &PepConfig,
+    audit: &dyn AuditSink,
     request: &HttpRequest,
+    ctx: &RequestContext,
     url: String,
     status: u16,
     error_code: Option<&str>,
@@ -34,11 +542,9 @@ pub fn append_audit_entry(
     response_bytes: usize,
     redirects: u32,
     policy_decision: Option<&PolicyDecision>,
+    response_sha256: Option<String>,
 ) {
-    let ts_unix_ms = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|dur| dur.as_millis() as u64)
-        .unwrap_or(0);
+    let ts_unix_ms = unix_millis(audit.now());
 
     let decision = if error_code.is_some() {
         "deny".to_string()
@@ -54,18 +560,265 @@ pub fn append_audit_entry(
         error_code: error_code.map(|code| code.to_string()),
         request_bytes,
         response_bytes,
+        response_sha256,
         redirects,
         decision,
         policy_hash: policy_decision.map(|d| d.policy_hash.clone()),
         decision_id: policy_decision.map(|d| d.decision_id.clone()),
+        request_id: request.request_id.clone(),
+        peer_cid: ctx.peer_cid,
+        shadow_divergence: ctx.shadow.is_some(),
+        shadow: ctx.shadow.clone(),
+        ssrf_bypassed: ctx.ssrf_bypassed,
+        dlp_category: ctx.dlp_category.clone(),
+        content_length_mismatch: ctx.content_length_mismatch.clone(),
+        preflight: request.preflight,
+        request_headers: ctx.audit_headers.then(|| redact_headers(&request.headers)),
+        response_headers: ctx
+            .response_headers
+            .as_deref()
+            .filter(|_| ctx.audit_headers)
+            .map(redact_headers),
     };
 
+    audit.record(&entry);
+}
+
+/// Audit a connection refused before any frame was read. With no request to
+/// describe, the entry names the peer: method `CONNECT`, url `vsock://<cid>`.
+pub fn append_connection_refusal(audit: &dyn AuditSink, ctx: &RequestContext, error_code: &str) {
+    append_peer_entry(audit, ctx, "CONNECT", error_code);
+}
+
+/// Audit a frame that is not a valid request as `invalid_request`. Nothing
+/// in it is trusted, so the entry names the peer: method `FRAME`, url
+/// `vsock://<cid>`.
+pub fn append_invalid_request(audit: &dyn AuditSink, ctx: &RequestContext) {
+    append_peer_entry(audit, ctx, "FRAME", "invalid_request");
+}
+
+fn append_peer_entry(audit: &dyn AuditSink, ctx: &RequestContext, method: &str, error_code: &str) {
+    let url = ctx.peer_cid.map_or_else(
+        || "vsock://unknown".to_string(),
+        |cid| format!("vsock://{cid}"),
+    );
+    let request = HttpRequest::new(method, url.clone());
+    append_audit_entry(
+        audit,
+        &request,
+        ctx,
+        url,
+        0,
+        Some(error_code),
+        0,
+        0,
+        0,
+        None,
+        None,
+    );
+}
+
+// ── Decision log ────────────────────────────────────────────────────────
+
+/// One policy evaluation, independent of what the request did afterwards.
+#[derive(Debug, Serialize)]
+pub struct DecisionLogEntry<'a> {
+    pub ts_unix_ms: u64,
+    pub decision_id: &'a str,
+    pub policy_hash: &'a str,
+    /// The `PolicyInput` as evaluated, with the URL query and fragment removed.
+    pub input: Value,
+    pub decision: &'a PolicyDecision,
+}
+
+/// Append an evaluation to `PEP_DECISION_LOG`, if configured. Like the audit
+/// log, write failures never break a request.
+pub fn append_decision_log(config: &PepConfig, input: &PolicyInput, decision: &PolicyDecision) {
+    let Some(path) = &config.decision_log_path else {
+        return;
+    };
+    let Ok(mut input_json) = serde_json::to_value(input) else {
+        return;
+    };
+    input_json["action"]["resource"]["url"] =
+        Value::String(sanitize_url_string(&input.action.resource.url));
+
+    let entry = DecisionLogEntry {
+        ts_unix_ms: unix_millis(SystemTime::now()),
+        decision_id: &decision.decision_id,
+        policy_hash: &decision.policy_hash,
+        input: input_json,
+        decision,
+    };
     if let Ok(line) = serde_json::to_string(&entry)
-        && let Ok(mut file) = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&config.audit_log_path)
+        && let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path)
     {
         let _ = writeln!(file, "{line}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(error_code: Option<&str>) -> AuditEntry {
+        AuditEntry {
+            ts_unix_ms: 1_700_000_000_123,
+            method: "GET".to_string(),
+            url: "https://example.com/".to_string(),
+            status: if error_code.is_some() { 0 } else { 200 },
+            error_code: error_code.map(|code| code.to_string()),
+            request_bytes: 0,
+            response_bytes: 42,
+            response_sha256: None,
+            redirects: 0,
+            decision: if error_code.is_some() {
+                "deny"
+            } else {
+                "allow"
+            }
+            .to_string(),
+            policy_hash: None,
+            decision_id: Some("d-1".to_string()),
+            request_id: None,
+            peer_cid: None,
+            shadow_divergence: false,
+            shadow: None,
+            ssrf_bypassed: false,
+            dlp_category: None,
+            content_length_mismatch: None,
+            preflight: false,
+            request_headers: None,
+            response_headers: None,
+        }
+    }
+
+    fn attribute<'a>(record: &'a Value, key: &str) -> Option<&'a Value> {
+        record["attributes"]
+            .as_array()?
+            .iter()
+            .find(|attr| attr["key"] == key)
+            .map(|attr| &attr["value"])
+    }
+
+    #[test]
+    fn otel_format_shapes_allow_entry() {
+        let line = OtelFormatter.format(&entry(None)).expect("format");
+        let record: Value = serde_json::from_str(&line).expect("parse");
+        assert_eq!(record["timeUnixNano"], "1700000000123000000");
+        assert_eq!(record["severityText"], "INFO");
+        assert_eq!(
+            attribute(&record, "http.response.status_code"),
+            Some(&json!({ "intValue": "200" }))
+        );
+        assert_eq!(
+            attribute(&record, "pep.decision_id"),
+            Some(&json!({ "stringValue": "d-1" }))
+        );
+        assert!(attribute(&record, "pep.error_code").is_none());
+    }
+
+    #[test]
+    fn otel_format_shapes_deny_entry() {
+        let line = OtelFormatter
+            .format(&entry(Some("DENIED_BY_POLICY")))
+            .expect("format");
+        let record: Value = serde_json::from_str(&line).expect("parse");
+        assert_eq!(record["severityText"], "WARN");
+        assert_eq!(
+            attribute(&record, "pep.error_code"),
+            Some(&json!({ "stringValue": "DENIED_BY_POLICY" }))
+        );
+        assert_eq!(
+            attribute(&record, "pep.decision"),
+            Some(&json!({ "stringValue": "deny" }))
+        );
+    }
+
+    #[test]
+    fn verify_audit_writable_accepts_writable_path() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("audit.jsonl");
+        verify_audit_writable(&path).expect("writable");
+        assert_eq!(std::fs::read(&path).expect("read").len(), 0);
+    }
+
+    #[test]
+    fn file_sink_lands_every_entry() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("audit.jsonl");
+        let writer = FileSink::spawn(path.clone(), AuditFormat::Jsonl).expect("spawn");
+        for status in 0..100 {
+            let mut sent = entry(None);
+            sent.status = status;
+            writer.record(&sent);
+        }
+        writer.flush();
+        assert_eq!(
+            std::fs::read_to_string(&path)
+                .expect("read")
+                .lines()
+                .count(),
+            100
+        );
+
+        writer.record(&entry(Some("DENIED_BY_POLICY")));
+        drop(writer);
+        let log = std::fs::read_to_string(&path).expect("read");
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 101);
+        assert!(lines[0].contains("\"status\":0"));
+        assert!(lines[100].contains("DENIED_BY_POLICY"));
+    }
+
+    #[test]
+    fn file_sink_confirms_only_written_entries() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("audit.jsonl");
+        let writer = FileSink::spawn(path.clone(), AuditFormat::Jsonl).expect("spawn");
+        writer.record_confirmed(&entry(None)).expect("written");
+        assert_eq!(
+            std::fs::read_to_string(&path)
+                .expect("read")
+                .lines()
+                .count(),
+            1
+        );
+
+        let missing = dir.path().join("missing").join("audit.jsonl");
+        let writer = FileSink::spawn(missing, AuditFormat::Jsonl).expect("spawn");
+        assert!(writer.record_confirmed(&entry(None)).is_err());
+    }
+
+    #[test]
+    fn verify_audit_writable_rejects_missing_directory() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let path = dir.path().join("missing").join("audit.jsonl");
+        let err = verify_audit_writable(&path).expect_err("expected failure");
+        assert!(err.to_string().contains(&path.display().to_string()));
+    }
+
+    #[test]
+    fn peer_cid_is_recorded_when_known() {
+        let mut with_cid = entry(None);
+        with_cid.peer_cid = Some(3);
+        let line = JsonlFormatter.format(&with_cid).expect("format");
+        assert!(line.contains("\"peer_cid\":3"));
+        let record: Value =
+            serde_json::from_str(&OtelFormatter.format(&with_cid).expect("format")).expect("parse");
+        assert_eq!(
+            attribute(&record, "pep.peer_cid"),
+            Some(&json!({ "intValue": "3" }))
+        );
+
+        let line = JsonlFormatter.format(&entry(None)).expect("format");
+        assert!(!line.contains("peer_cid"));
+    }
+
+    #[test]
+    fn jsonl_format_is_compact_entry() {
+        let line = JsonlFormatter.format(&entry(None)).expect("format");
+        assert!(line.starts_with("{\"ts_unix_ms\":1700000000123"));
+        assert!(!line.contains('\n'));
+    }
+}
@@ -0,0 +1,296 @@
+//! HTTP-push audit delivery (`PEP_AUDIT_SINK=http`): formatted entries are
+//! POSTed as NDJSON batches to `PEP_AUDIT_HTTP_URL`.
+//!
+//! The SIEM URL is operator configuration, never VM input, so it is pushed to
+//! directly and bypasses the allowlist and SSRF guard that apply to VM
+//! requests.
+
+use crate::audit::{AuditEntry, AuditSink, formatter_for};
+use crate::config::AuditFormat;
+use reqwest::Url;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Most entries sent in one POST.
+const BATCH_MAX: usize = 500;
+/// How long the pusher waits for a full batch before sending a partial one.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// Attempts per batch before it is requeued for the next round.
+const PUSH_ATTEMPTS: u32 = 3;
+const PUSH_BACKOFF: Duration = Duration::from_millis(200);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bounded queue of formatted lines. When full the oldest line is dropped, so
+/// an unreachable SIEM costs old entries rather than daemon memory.
+#[derive(Debug)]
+pub struct AuditBuffer {
+    lines: VecDeque<String>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl AuditBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: String) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        while self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+            self.dropped += 1;
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Remove up to `max` of the oldest lines.
+    pub fn take_batch(&mut self, max: usize) -> Vec<String> {
+        let count = max.min(self.lines.len());
+        self.lines.drain(..count).collect()
+    }
+
+    /// Put an undelivered batch back in front. The batch is older than
+    /// anything queued since, so it is what gets dropped if space ran out.
+    pub fn requeue(&mut self, batch: Vec<String>) {
+        for line in batch.into_iter().rev() {
+            if self.lines.len() >= self.capacity {
+                self.dropped += 1;
+            } else {
+                self.lines.push_front(line);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Lines dropped since the last call.
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+/// One line per entry, each newline-terminated.
+pub fn ndjson_body(lines: &[String]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(lines.iter().map(|line| line.len() + 1).sum());
+    for line in lines {
+        body.extend_from_slice(line.as_bytes());
+        body.push(b'\n');
+    }
+    body
+}
+
+struct Shared {
+    state: Mutex<PushState>,
+    wake: Condvar,
+}
+
+struct PushState {
+    buffer: AuditBuffer,
+    /// Flush callers waiting for the next delivery attempt to finish.
+    acks: Vec<mpsc::SyncSender<()>>,
+    closed: bool,
+}
+
+/// Buffers entries and pushes them from a dedicated thread, retrying a
+/// failed batch with backoff before requeueing it.
+pub struct HttpSink {
+    format: AuditFormat,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HttpSink {
+    pub fn spawn(url: Url, format: AuditFormat, capacity: usize) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(PUSH_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PushState {
+                buffer: AuditBuffer::new(capacity),
+                acks: Vec::new(),
+                closed: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let pusher = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("pep-audit-http".to_string())
+            .spawn(move || run_pusher(&client, &url, &pusher))?;
+        Ok(Self {
+            format,
+            shared,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl AuditSink for HttpSink {
+    fn record(&self, entry: &AuditEntry) {
+        let Ok(line) = formatter_for(self.format).format(entry) else {
+            return;
+        };
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.buffer.push(line);
+            if state.buffer.len() >= BATCH_MAX {
+                self.shared.wake.notify_one();
+            }
+        }
+    }
+
+    /// Block until everything recorded so far has had a delivery attempt.
+    fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        match self.shared.state.lock() {
+            Ok(mut state) => state.acks.push(ack),
+            Err(_) => return,
+        }
+        self.shared.wake.notify_one();
+        let _ = done.recv();
+    }
+}
+
+impl Drop for HttpSink {
+    fn drop(&mut self) {
+        // The pusher makes a last attempt at what is queued, then exits.
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+        }
+        self.shared.wake.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for HttpSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpSink")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+fn run_pusher(client: &Client, url: &Url, shared: &Shared) {
+    loop {
+        let (batch, acks, closed) = {
+            let Ok(mut state) = shared.state.lock() else {
+                return;
+            };
+            if state.buffer.len() < BATCH_MAX && state.acks.is_empty() && !state.closed {
+                state = match shared.wake.wait_timeout(state, BATCH_INTERVAL) {
+                    Ok((state, _)) => state,
+                    Err(_) => return,
+                };
+            }
+            (
+                state.buffer.take_batch(BATCH_MAX),
+                std::mem::take(&mut state.acks),
+                state.closed,
+            )
+        };
+
+        let delivered = batch.is_empty() || push_batch(client, url, &batch);
+
+        let Ok(mut state) = shared.state.lock() else {
+            return;
+        };
+        if !delivered {
+            state.buffer.requeue(batch);
+        }
+        let dropped = state.buffer.take_dropped();
+        if dropped > 0 {
+            eprintln!("audit http sink: dropped {dropped} entries (buffer full)");
+        }
+        // The SIEM is accepting: drain the rest before acking or exiting.
+        if delivered && !state.buffer.is_empty() && (!acks.is_empty() || closed) {
+            state.acks.extend(acks);
+            continue;
+        }
+        drop(state);
+        for ack in acks {
+            let _ = ack.send(());
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+fn push_batch(client: &Client, url: &Url, batch: &[String]) -> bool {
+    let body = ndjson_body(batch);
+    for attempt in 0..PUSH_ATTEMPTS {
+        if attempt > 0 {
+            thread::sleep(PUSH_BACKOFF * 2u32.pow(attempt - 1));
+        }
+        let sent = client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(body.clone())
+            .send();
+        if sent.is_ok_and(|response| response.status().is_success()) {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(range: std::ops::Range<u32>) -> Vec<String> {
+        range.map(|n| format!("{{\"n\":{n}}}")).collect()
+    }
+
+    #[test]
+    fn batches_are_taken_oldest_first_as_ndjson() {
+        let mut buffer = AuditBuffer::new(10);
+        for line in lines(0..5) {
+            buffer.push(line);
+        }
+        let batch = buffer.take_batch(3);
+        assert_eq!(batch, lines(0..3));
+        assert_eq!(ndjson_body(&batch), b"{\"n\":0}\n{\"n\":1}\n{\"n\":2}\n");
+        assert_eq!(buffer.take_batch(3), lines(3..5));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn full_buffer_drops_oldest() {
+        let mut buffer = AuditBuffer::new(3);
+        for line in lines(0..5) {
+            buffer.push(line);
+        }
+        assert_eq!(buffer.take_dropped(), 2);
+        assert_eq!(buffer.take_dropped(), 0);
+
+        // An undelivered batch goes back in front; entries queued meanwhile
+        // are newer, so the batch's oldest lines are the ones dropped.
+        let batch = buffer.take_batch(3);
+        assert_eq!(batch, lines(2..5));
+        buffer.push("{\"n\":5}".to_string());
+        buffer.requeue(batch);
+        assert_eq!(buffer.take_dropped(), 1);
+        assert_eq!(buffer.take_batch(10), lines(3..6));
+    }
+}
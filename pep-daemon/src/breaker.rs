@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Thresholds for the per-host circuit breaker.
+#[derive(Clone, Copy, Debug)]
+pub struct BreakerSettings {
+    /// Consecutive failures that open the circuit (0 disables the breaker).
+    pub failure_threshold: u32,
+    /// Failures further apart than this start a new count.
+    pub window: Duration,
+    /// How long an open circuit fast-fails before allowing a probe.
+    pub cooldown: Duration,
+}
+
+#[derive(Debug)]
+enum Circuit {
+    Closed {
+        failures: u32,
+        first_failure: Instant,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Cooldown elapsed and one probe request is in flight.
+    HalfOpen,
+}
+
+/// Per-upstream-host circuit breakers.
+#[derive(Debug, Default)]
+pub struct CircuitBreakers {
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+    /// `Err` when requests to `host` should fast-fail. After the cooldown a
+    /// single caller is let through as the half-open probe.
+    pub fn check(
+        &self,
+        host: &str,
+        settings: &BreakerSettings,
+        now: Instant,
+    ) -> Result<(), String> {
+        if settings.failure_threshold == 0 {
+            return Ok(());
+        }
+        let mut circuits = self
+            .circuits
+            .lock()
+            .map_err(|_| "circuit breaker poisoned".to_string())?;
+        match circuits.get(host) {
+            Some(Circuit::Open { until }) if now < *until => {
+                Err(format!("circuit open for {host}"))
+            }
+            Some(Circuit::Open { .. }) => {
+                circuits.insert(host.to_string(), Circuit::HalfOpen);
+                Ok(())
+            }
+            Some(Circuit::HalfOpen) => {
+                Err(format!("circuit half-open for {host}; probe in flight"))
+            }
+            Some(Circuit::Closed { .. }) | None => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self, host: &str) {
+        if let Ok(mut circuits) = self.circuits.lock() {
+            circuits.remove(host);
+        }
+    }
+
+    pub fn record_failure(&self, host: &str, settings: &BreakerSettings, now: Instant) {
+        if settings.failure_threshold == 0 {
+            return;
+        }
+        let Ok(mut circuits) = self.circuits.lock() else {
+            return;
+        };
+        let next = match circuits.remove(host) {
+            Some(Circuit::Closed {
+                failures,
+                first_failure,
+            }) if now.duration_since(first_failure) < settings.window => Circuit::Closed {
+                failures: failures + 1,
+                first_failure,
+            },
+            Some(Circuit::HalfOpen) | Some(Circuit::Open { .. }) => Circuit::Open {
+                until: now + settings.cooldown,
+            },
+            Some(Circuit::Closed { .. }) | None => Circuit::Closed {
+                failures: 1,
+                first_failure: now,
+            },
+        };
+        let next = match next {
+            Circuit::Closed { failures, .. } if failures >= settings.failure_threshold => {
+                Circuit::Open {
+                    until: now + settings.cooldown,
+                }
+            }
+            other => other,
+        };
+        circuits.insert(host.to_string(), next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> BreakerSettings {
+        BreakerSettings {
+            failure_threshold: 3,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_and_fast_fails() {
+        let breakers = CircuitBreakers::default();
+        let s = settings();
+        let now = Instant::now();
+        for _ in 0..2 {
+            breakers.record_failure("down.example.com", &s, now);
+            assert!(breakers.check("down.example.com", &s, now).is_ok());
+        }
+        breakers.record_failure("down.example.com", &s, now);
+        let err = breakers
+            .check("down.example.com", &s, now + Duration::from_secs(1))
+            .expect_err("expected open circuit");
+        assert!(err.contains("down.example.com"));
+        assert!(breakers.check("up.example.com", &s, now).is_ok());
+    }
+
+    #[test]
+    fn breaker_allows_single_half_open_probe_after_cooldown() {
+        let breakers = CircuitBreakers::default();
+        let s = settings();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breakers.record_failure("h", &s, now);
+        }
+        let later = now + s.cooldown;
+        assert!(breakers.check("h", &s, later).is_ok());
+        assert!(breakers.check("h", &s, later).is_err());
+
+        breakers.record_success("h");
+        assert!(breakers.check("h", &s, later).is_ok());
+    }
+
+    #[test]
+    fn failed_probe_reopens_circuit() {
+        let breakers = CircuitBreakers::default();
+        let s = settings();
+        let now = Instant::now();
+        for _ in 0..3 {
+            breakers.record_failure("h", &s, now);
+        }
+        let later = now + s.cooldown;
+        assert!(breakers.check("h", &s, later).is_ok());
+        breakers.record_failure("h", &s, later);
+        assert!(
+            breakers
+                .check("h", &s, later + Duration::from_secs(1))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn failures_outside_window_do_not_accumulate() {
+        let breakers = CircuitBreakers::default();
+        let s = settings();
+        let now = Instant::now();
+        breakers.record_failure("h", &s, now);
+        breakers.record_failure("h", &s, now);
+        let later = now + s.window;
+        breakers.record_failure("h", &s, later);
+        assert!(breakers.check("h", &s, later).is_ok());
+    }
+
+    #[test]
+    fn zero_threshold_disables_breaker() {
+        let breakers = CircuitBreakers::default();
+        let s = BreakerSettings {
+            failure_threshold: 0,
+            ..settings()
+        };
+        let now = Instant::now();
+        for _ in 0..10 {
+            breakers.record_failure("h", &s, now);
+        }
+        assert!(breakers.check("h", &s, now).is_ok());
+    }
+}
@@ -0,0 +1,226 @@
+//! Optional response cache (`PEP_RESPONSE_CACHE`) for conditional GETs.
+//!
+//! Bodies are stored with their `ETag`/`Last-Modified` and every later fetch
+//! is still sent upstream, as a conditional request; only a `304` serves the
+//! cached body. The cache saves egress bytes, never a policy or SSRF check.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    stored_at: Instant,
+}
+
+/// URL-keyed bodies bounded by total size and age.
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: Mutex<HashMap<String, CachedResponse>>,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_bytes,
+            ttl,
+        }
+    }
+
+    /// `If-None-Match` / `If-Modified-Since` headers for a fresh entry.
+    pub fn validators(&self, key: &str, now: Instant) -> Vec<(String, String)> {
+        let Some(entry) = self.fresh(key, now) else {
+            return Vec::new();
+        };
+        let mut headers = Vec::new();
+        if let Some(etag) = entry.etag {
+            headers.push(("If-None-Match".to_string(), etag));
+        }
+        if let Some(last_modified) = entry.last_modified {
+            headers.push(("If-Modified-Since".to_string(), last_modified));
+        }
+        headers
+    }
+
+    /// The stored response to serve after upstream answered `304`.
+    pub fn revalidated(&self, key: &str, now: Instant) -> Option<CachedResponse> {
+        self.fresh(key, now)
+    }
+
+    /// Store a `200` that carries a validator and allows storage. Returns
+    /// whether it was cached.
+    pub fn store(
+        &self,
+        key: &str,
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+        now: Instant,
+    ) -> bool {
+        let etag = header(headers, "etag");
+        let last_modified = header(headers, "last-modified");
+        if status != 200
+            || (etag.is_none() && last_modified.is_none())
+            || has_no_store(headers)
+            || body.len() > self.max_bytes
+        {
+            return false;
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return false;
+        };
+        entries.retain(|_, entry| now.duration_since(entry.stored_at) < self.ttl);
+        entries.remove(key);
+        // Evict oldest first until the new body fits.
+        let mut used: usize = entries.values().map(|entry| entry.body.len()).sum();
+        while used + body.len() > self.max_bytes {
+            let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = entries.remove(&oldest) {
+                used -= evicted.body.len();
+            }
+        }
+        entries.insert(
+            key.to_string(),
+            CachedResponse {
+                status,
+                headers: headers.to_vec(),
+                body: body.to_vec(),
+                etag,
+                last_modified,
+                stored_at: now,
+            },
+        );
+        true
+    }
+
+    fn fresh(&self, key: &str, now: Instant) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().ok()?;
+        match entries.get(key) {
+            Some(entry) if now.duration_since(entry.stored_at) < self.ttl => Some(entry.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Only plain GETs go through the cache. Requests carrying credentials or
+/// their own validators bypass it, so one caller's response is never served
+/// to another and client conditionals reach upstream untouched.
+pub fn is_cacheable_request(method: &str, headers: &[(String, String)]) -> bool {
+    method.eq_ignore_ascii_case("GET")
+        && !has_no_store(headers)
+        && !headers.iter().any(|(name, _)| {
+            [
+                "authorization",
+                "cookie",
+                "if-none-match",
+                "if-modified-since",
+            ]
+            .iter()
+            .any(|skip| name.trim().eq_ignore_ascii_case(skip))
+        })
+}
+
+fn has_no_store(headers: &[(String, String)]) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+fn header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn etag_entry_is_revalidated_and_served_on_304() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let now = Instant::now();
+        let key = "https://example.com/data";
+        assert!(cache.validators(key, now).is_empty());
+        assert!(cache.store(key, 200, &headers(&[("etag", "\"v1\"")]), b"payload", now));
+
+        assert_eq!(
+            cache.validators(key, now),
+            headers(&[("If-None-Match", "\"v1\"")])
+        );
+        let hit = cache.revalidated(key, now).expect("cache hit");
+        assert_eq!((hit.status, hit.body.as_slice()), (200, &b"payload"[..]));
+
+        let later = now + Duration::from_secs(61);
+        assert!(cache.validators(key, later).is_empty());
+    }
+
+    #[test]
+    fn no_store_responses_and_requests_bypass_the_cache() {
+        let cache = ResponseCache::new(1024, Duration::from_secs(60));
+        let now = Instant::now();
+        let stored = cache.store(
+            "https://example.com/live",
+            200,
+            &headers(&[("etag", "\"v1\""), ("Cache-Control", "private, no-store")]),
+            b"payload",
+            now,
+        );
+        assert!(!stored);
+        assert!(cache.validators("https://example.com/live", now).is_empty());
+
+        assert!(is_cacheable_request("GET", &[]));
+        assert!(!is_cacheable_request("POST", &[]));
+        assert!(!is_cacheable_request(
+            "GET",
+            &headers(&[("Cache-Control", "no-store")])
+        ));
+        assert!(!is_cacheable_request(
+            "GET",
+            &headers(&[("Authorization", "Bearer x")])
+        ));
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted_to_fit_the_budget() {
+        let cache = ResponseCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+        let etag = headers(&[("etag", "\"v\"")]);
+        assert!(cache.store("a", 200, &etag, &[0; 6], now));
+        assert!(cache.store("b", 200, &etag, &[0; 4], now + Duration::from_secs(1)));
+        assert!(cache.store("c", 200, &etag, &[0; 5], now + Duration::from_secs(2)));
+        let at = now + Duration::from_secs(3);
+        assert!(cache.revalidated("a", at).is_none());
+        assert!(cache.revalidated("b", at).is_some());
+        assert!(cache.revalidated("c", at).is_some());
+        assert!(!cache.store("d", 200, &etag, &[0; 11], at));
+    }
+}
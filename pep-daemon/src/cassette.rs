@@ -0,0 +1,134 @@
+//! VCR-style cassettes for `PEP_RECORD_DIR` / `PEP_REPLAY_DIR`.
+//!
+//! Recording writes each successful response to `<dir>/<key>.json`; replay
+//! serves the matching file instead of contacting the upstream. Policy and
+//! SSRF checks run in both modes. Requests are matched on method, sanitized
+//! URL (no query or fragment), and the SHA-256 of the body, and only those
+//! are stored for the request: its headers may carry credentials.
+
+use crate::http_exec::{decode_request_body, sanitize_url_string};
+use crate::types::{HttpRequest, HttpResponse, PepError};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub body_sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cassette {
+    pub request: RecordedRequest,
+    pub response: HttpResponse,
+}
+
+impl RecordedRequest {
+    pub fn of(request: &HttpRequest) -> Self {
+        let decoded;
+        let body = match (&request.body, &request.body_base64) {
+            (Some(body), _) => body.as_slice(),
+            (None, Some(encoded)) => {
+                decoded = decode_request_body(encoded).unwrap_or_default();
+                decoded.as_slice()
+            }
+            (None, None) => &[],
+        };
+        Self::with_body(request, body)
+    }
+
+    /// Like `of`, for a request whose body is already decoded.
+    pub fn with_body(request: &HttpRequest, body: &[u8]) -> Self {
+        Self {
+            method: request.method.to_uppercase(),
+            url: sanitize_url_string(&request.url),
+            body_sha256: format!("{:x}", Sha256::digest(body)),
+        }
+    }
+
+    /// File name stem shared by every request that matches this one.
+    pub fn key(&self) -> String {
+        let matched = format!("{} {} {}", self.method, self.url, self.body_sha256);
+        format!("{:x}", Sha256::digest(matched.as_bytes()))
+    }
+}
+
+fn cassette_path(dir: &Path, request: &RecordedRequest) -> PathBuf {
+    dir.join(format!("{}.json", request.key()))
+}
+
+/// Store `response` for `request`, replacing an earlier recording.
+pub fn record(
+    dir: &Path,
+    request: &RecordedRequest,
+    response: &HttpResponse,
+) -> Result<(), PepError> {
+    let path = cassette_path(dir, request);
+    // Cassettes hold the body as base64 whichever codec the client spoke.
+    let mut response = response.clone();
+    if let Some(body) = response.body.take() {
+        response.set_body(body, false);
+    }
+    let cassette = serde_json::json!({ "request": request, "response": response });
+    fs::write(path, serde_json::to_vec_pretty(&cassette)?)?;
+    Ok(())
+}
+
+/// The recorded response for `request`, if there is one.
+pub fn replay(dir: &Path, request: &RecordedRequest) -> Result<Option<HttpResponse>, PepError> {
+    let path = cassette_path(dir, request);
+    let raw = match fs::read(&path) {
+        Ok(raw) => raw,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let cassette: Cassette = serde_json::from_slice(&raw)?;
+    Ok(Some(cassette.response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::error_response;
+
+    fn request(url: &str, body: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            headers: vec![("authorization".to_string(), "Bearer secret".to_string())],
+            body_base64: body.map(str::to_string),
+            ..HttpRequest::new("post", url)
+        }
+    }
+
+    #[test]
+    fn key_ignores_query_and_case_but_not_body() {
+        let base = RecordedRequest::of(&request("https://example.com/a?token=1", Some("AA==")));
+        let same = RecordedRequest::of(&request("https://example.com/a#top", Some("AA==")));
+        assert_eq!(base.key(), same.key());
+        assert_eq!(base.method, "POST");
+        assert_ne!(
+            base.key(),
+            RecordedRequest::of(&request("https://example.com/a", Some("AQ=="))).key()
+        );
+        assert_ne!(
+            base.key(),
+            RecordedRequest::of(&request("https://example.com/b", Some("AA=="))).key()
+        );
+    }
+
+    #[test]
+    fn recorded_cassette_omits_request_headers() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let recorded = RecordedRequest::of(&request("https://example.com/a", None));
+        record(dir.path(), &recorded, &error_response("none", "")).expect("record");
+
+        let raw = fs::read_to_string(cassette_path(dir.path(), &recorded)).expect("cassette");
+        assert!(!raw.contains("secret"));
+        assert!(replay(dir.path(), &recorded).expect("replay").is_some());
+        let other = RecordedRequest::of(&request("https://example.com/b", None));
+        assert!(replay(dir.path(), &other).expect("replay").is_none());
+    }
+}
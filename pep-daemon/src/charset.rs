@@ -0,0 +1,115 @@
+//! Opt-in text normalization (`PEP_NORMALIZE_TEXT`): transcode text bodies
+//! to UTF-8 per their declared charset and drop a leading BOM.
+
+use encoding_rs::{Encoding, UTF_8};
+
+/// A normalized body and the `Content-Type` to return with it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NormalizedText {
+    pub body: Vec<u8>,
+    pub content_type: String,
+}
+
+/// Transcode `body` to UTF-8 when `content_type` is `text/*` or
+/// `application/json`. Returns `Ok(None)` when the body is left untouched:
+/// other media types, unknown charsets, bytes that do not decode cleanly, or
+/// a body that is already BOM-less UTF-8. The output is held to `cap`.
+pub fn normalize_text_body(
+    content_type: &str,
+    body: &[u8],
+    cap: usize,
+) -> Result<Option<NormalizedText>, String> {
+    let mut parts = content_type.split(';');
+    let essence = parts.next().unwrap_or_default().trim().to_lowercase();
+    if !(essence.starts_with("text/") || essence == "application/json") {
+        return Ok(None);
+    }
+
+    let mut charset = None;
+    let mut params = Vec::new();
+    for param in parts.map(str::trim).filter(|param| !param.is_empty()) {
+        match param.split_once('=') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("charset") => {
+                charset = Some(value.trim().trim_matches('"').to_string());
+            }
+            _ => params.push(param.to_string()),
+        }
+    }
+
+    let declared = match charset {
+        Some(label) => match Encoding::for_label(label.as_bytes()) {
+            Some(encoding) => encoding,
+            None => return Ok(None),
+        },
+        None => UTF_8,
+    };
+
+    // `decode` sniffs and strips a BOM, which overrides the declared charset.
+    let (text, used, had_errors) = declared.decode(body);
+    if had_errors {
+        return Ok(None);
+    }
+    let has_bom = Encoding::for_bom(body).is_some();
+    if used == UTF_8 && declared == UTF_8 && !has_bom {
+        return Ok(None);
+    }
+    if text.len() > cap {
+        return Err("response body exceeds max bytes".to_string());
+    }
+
+    params.push("charset=utf-8".to_string());
+    Ok(Some(NormalizedText {
+        body: text.into_owned().into_bytes(),
+        content_type: format!("{essence}; {}", params.join("; ")),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latin1_body_is_transcoded() {
+        let body = b"caf\xe9";
+        let normalized = normalize_text_body("text/plain; charset=ISO-8859-1", body, 1024)
+            .expect("normalize")
+            .expect("transcoded");
+        assert_eq!(normalized.body, "café".as_bytes());
+        assert_eq!(normalized.content_type, "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn utf8_bom_is_stripped() {
+        let body = b"\xef\xbb\xbf{\"ok\":true}";
+        let normalized = normalize_text_body("application/json", body, 1024)
+            .expect("normalize")
+            .expect("bom stripped");
+        assert_eq!(normalized.body, b"{\"ok\":true}");
+        assert_eq!(normalized.content_type, "application/json; charset=utf-8");
+    }
+
+    #[test]
+    fn plain_utf8_and_binary_types_are_untouched() {
+        assert_eq!(
+            normalize_text_body("text/html; charset=utf-8", b"hi", 1024),
+            Ok(None)
+        );
+        assert_eq!(
+            normalize_text_body("image/png", b"\xff\xd8", 1024),
+            Ok(None)
+        );
+        assert_eq!(
+            normalize_text_body("text/plain; charset=bogus", b"hi", 1024),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn transcoded_output_is_held_to_cap() {
+        // Each Latin-1 byte above 0x7f becomes two UTF-8 bytes.
+        let body = vec![0xe9u8; 8];
+        let err = normalize_text_body("text/plain; charset=latin1", &body, 10)
+            .expect_err("expected cap error");
+        assert!(err.contains("exceeds max bytes"));
+    }
+}
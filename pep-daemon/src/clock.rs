@@ -0,0 +1,171 @@
+//! Wall-clock time for policy input and audit entries, behind a seam so
+//! tests can pin it.
+
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Always reports the same instant.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Fixed offset from UTC for `time_iso` in policy input. There are no DST
+/// rules: an operator in a zone with DST updates it, or writes policy windows
+/// in UTC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UtcOffset {
+    seconds: i32,
+}
+
+impl UtcOffset {
+    pub const UTC: Self = Self { seconds: 0 };
+
+    /// `Z`, `UTC`, `+HH:MM`, `-HH:MM`, `+HHMM`, or `+HH`.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        if raw.eq_ignore_ascii_case("z") || raw.eq_ignore_ascii_case("utc") {
+            return Some(Self::UTC);
+        }
+        let (sign, rest) = match raw.split_at_checked(1)? {
+            ("+", rest) => (1, rest),
+            ("-", rest) => (-1, rest),
+            _ => return None,
+        };
+        let digits = rest.replace(':', "");
+        if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let hours: i32 = digits[..2].parse().ok()?;
+        let minutes: i32 = if digits.len() == 4 {
+            digits[2..].parse().ok()?
+        } else {
+            0
+        };
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        Some(Self {
+            seconds: sign * (hours * 3600 + minutes * 60),
+        })
+    }
+
+    pub fn seconds(self) -> i32 {
+        self.seconds
+    }
+}
+
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.seconds < 0 { '-' } else { '+' };
+        let abs = self.seconds.unsigned_abs();
+        write!(f, "{sign}{:02}:{:02}", abs / 3600, abs % 3600 / 60)
+    }
+}
+
+impl Serialize for UtcOffset {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Whole seconds since the epoch; times before it count as 0.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// RFC 3339 timestamp of `time` in `offset`, to the second, e.g.
+/// `2024-03-01T09:30:00+01:00`.
+pub fn rfc3339(time: SystemTime, offset: UtcOffset) -> String {
+    let local = unix_secs(time) as i64 + i64::from(offset.seconds);
+    let (days, secs) = (local.div_euclid(86_400), local.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    let suffix = if offset == UtcOffset::UTC {
+        "Z".to_string()
+    } else {
+        offset.to_string()
+    };
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}{suffix}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Proleptic Gregorian date for a count of days since 1970-01-01 (Howard
+/// Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn rfc3339_formats_utc_and_offsets() {
+        assert_eq!(rfc3339(at(0), UtcOffset::UTC), "1970-01-01T00:00:00Z");
+        // 2024-02-29T23:30:00Z, a leap day.
+        let leap = at(1_709_249_400);
+        assert_eq!(rfc3339(leap, UtcOffset::UTC), "2024-02-29T23:30:00Z");
+        let plus_one = UtcOffset::parse("+01:00").expect("offset");
+        assert_eq!(rfc3339(leap, plus_one), "2024-03-01T00:30:00+01:00");
+        let minus = UtcOffset::parse("-0530").expect("offset");
+        assert_eq!(rfc3339(leap, minus), "2024-02-29T18:00:00-05:30");
+    }
+
+    #[test]
+    fn utc_offset_parses_known_forms() {
+        assert_eq!(UtcOffset::parse("Z"), Some(UtcOffset::UTC));
+        assert_eq!(UtcOffset::parse(" utc "), Some(UtcOffset::UTC));
+        assert_eq!(UtcOffset::parse("+02").map(UtcOffset::seconds), Some(7200));
+        assert_eq!(
+            UtcOffset::parse("-03:30").map(UtcOffset::seconds),
+            Some(-12_600)
+        );
+        assert_eq!(UtcOffset::parse("Europe/Oslo"), None);
+        assert_eq!(UtcOffset::parse("+24:00"), None);
+        assert_eq!(UtcOffset::parse("+1:00"), None);
+    }
+}
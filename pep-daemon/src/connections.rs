@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+struct Counts {
+    active: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+/// Open-connection gauge with a high-water mark. Cloning shares the counts,
+/// so handlers on other threads report into the same gauge.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionCounter {
+    counts: Arc<Counts>,
+}
+
+impl ConnectionCounter {
+    /// Count a newly accepted connection until the returned guard is dropped.
+    pub fn open(&self) -> ConnectionGuard {
+        let active = self.counts.active.fetch_add(1, Ordering::SeqCst) + 1;
+        self.counts.peak.fetch_max(active, Ordering::SeqCst);
+        ConnectionGuard {
+            counts: Arc::clone(&self.counts),
+        }
+    }
+
+    /// Like [`open`](Self::open), but `None` when `limit` connections are
+    /// already open (0 means no limit).
+    pub fn try_open(&self, limit: usize) -> Option<ConnectionGuard> {
+        let active = self
+            .counts
+            .active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (limit == 0 || active < limit).then_some(active + 1)
+            })
+            .ok()?
+            + 1;
+        self.counts.peak.fetch_max(active, Ordering::SeqCst);
+        Some(ConnectionGuard {
+            counts: Arc::clone(&self.counts),
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.counts.active.load(Ordering::SeqCst)
+    }
+
+    pub fn peak(&self) -> usize {
+        self.counts.peak.load(Ordering::SeqCst)
+    }
+}
+
+/// Decrements the active count on drop, so early returns and panics in a
+/// handler cannot leak a connection.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counts: Arc<Counts>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counts.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Open connections per peer CID, for `PEP_MAX_CONN_PER_CID`. Cloning
+/// shares the counts.
+#[derive(Clone, Debug, Default)]
+pub struct PeerConnections {
+    open: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+impl PeerConnections {
+    /// Count a connection from `cid` until the guard is dropped, or `None`
+    /// when `cid` already has `limit` open (0 means no limit).
+    pub fn try_open(&self, cid: u32, limit: usize) -> Option<PeerGuard> {
+        let mut open = self.open.lock().ok()?;
+        let count = open.entry(cid).or_insert(0);
+        if limit > 0 && *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(PeerGuard {
+            open: Arc::clone(&self.open),
+            cid,
+        })
+    }
+
+    pub fn open_for(&self, cid: u32) -> usize {
+        self.open
+            .lock()
+            .map(|open| open.get(&cid).copied().unwrap_or(0))
+            .unwrap_or(0)
+    }
+}
+
+/// Releases a peer's connection slot on drop.
+#[derive(Debug)]
+pub struct PeerGuard {
+    open: Arc<Mutex<HashMap<u32, usize>>>,
+    cid: u32,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.open.lock()
+            && let Some(count) = open.get_mut(&self.cid)
+        {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.cid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guards_track_active_and_peak() {
+        let counter = ConnectionCounter::default();
+        let first = counter.open();
+        let second = counter.clone().open();
+        assert_eq!((counter.active(), counter.peak()), (2, 2));
+        drop(first);
+        drop(second);
+        assert_eq!((counter.active(), counter.peak()), (0, 2));
+        let _third = counter.open();
+        assert_eq!((counter.active(), counter.peak()), (1, 2));
+    }
+
+    #[test]
+    fn try_open_refuses_past_the_limit_until_a_guard_drops() {
+        let counter = ConnectionCounter::default();
+        let first = counter.try_open(2).expect("first");
+        let _second = counter.try_open(2).expect("second");
+        assert!(counter.try_open(2).is_none());
+        assert_eq!((counter.active(), counter.peak()), (2, 2));
+
+        drop(first);
+        assert!(counter.try_open(2).is_some());
+        assert!(counter.try_open(0).is_some());
+    }
+
+    #[test]
+    fn peer_over_budget_is_refused_until_a_slot_frees() {
+        let peers = PeerConnections::default();
+        let first = peers.try_open(3, 2).expect("first");
+        let _second = peers.try_open(3, 2).expect("second");
+        assert!(peers.try_open(3, 2).is_none());
+        // Other CIDs have their own budget.
+        assert!(peers.try_open(4, 2).is_some());
+
+        drop(first);
+        assert_eq!(peers.open_for(3), 1);
+        assert!(peers.try_open(3, 2).is_some());
+        assert!(peers.try_open(5, 0).is_some());
+    }
+}
@@ -0,0 +1,118 @@
+//! Decoding of compressed upstream responses for `PEP_DECOMPRESS_ENCODINGS`.
+
+use crate::config::ContentCoding;
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+
+/// Decode `body`, failing with `http_error` when it is not valid `coding`
+/// and `constraint_violation` when it decodes to more than `cap` bytes.
+pub fn decode_body(
+    coding: ContentCoding,
+    body: &[u8],
+    cap: usize,
+) -> Result<Vec<u8>, (&'static str, String)> {
+    let decoder: Box<dyn Read + '_> = match coding {
+        ContentCoding::Gzip => Box::new(GzDecoder::new(body)),
+        // RFC 9110 deflate is zlib-wrapped, but some servers send it raw.
+        ContentCoding::Deflate if is_zlib_header(body) => Box::new(ZlibDecoder::new(body)),
+        ContentCoding::Deflate => Box::new(DeflateDecoder::new(body)),
+        ContentCoding::Br => Box::new(brotli_decompressor::Decompressor::new(body, 4096)),
+    };
+    // One byte past the cap is enough to tell an oversized body apart.
+    let mut decoded = Vec::new();
+    decoder
+        .take(cap as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|err| {
+            (
+                "http_error",
+                format!("invalid {} body: {err}", coding.name()),
+            )
+        })?;
+    if decoded.len() > cap {
+        return Err((
+            "constraint_violation",
+            "decompressed response body exceeds max bytes".to_string(),
+        ));
+    }
+    Ok(decoded)
+}
+
+fn is_zlib_header(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"compressible upstream body, compressible upstream body";
+
+    fn encode<W: Write>(
+        mut encoder: W,
+        finish: impl FnOnce(W) -> std::io::Result<Vec<u8>>,
+    ) -> Vec<u8> {
+        encoder.write_all(TEXT).expect("write");
+        finish(encoder).expect("finish")
+    }
+
+    #[test]
+    fn gzip_and_both_deflate_forms_decode() {
+        let gzip = encode(
+            GzEncoder::new(Vec::new(), Compression::default()),
+            GzEncoder::finish,
+        );
+        assert_eq!(
+            decode_body(ContentCoding::Gzip, &gzip, 1024).expect("gzip"),
+            TEXT
+        );
+        let zlib = encode(
+            ZlibEncoder::new(Vec::new(), Compression::default()),
+            ZlibEncoder::finish,
+        );
+        assert_eq!(
+            decode_body(ContentCoding::Deflate, &zlib, 1024).expect("zlib"),
+            TEXT
+        );
+        let raw = encode(
+            DeflateEncoder::new(Vec::new(), Compression::default()),
+            DeflateEncoder::finish,
+        );
+        assert_eq!(
+            decode_body(ContentCoding::Deflate, &raw, 1024).expect("raw"),
+            TEXT
+        );
+    }
+
+    #[test]
+    fn brotli_decodes() {
+        // "brotli " repeated 64 times.
+        let br = [
+            0x1b, 0xbf, 0x01, 0xf8, 0x8d, 0x54, 0xb5, 0xbf, 0x06, 0x11, 0x93, 0xa3, 0x93, 0x69,
+            0x6c, 0x6f, 0x41, 0x5b, 0x24, 0x70, 0xce, 0x3e, 0x00,
+        ];
+        let decoded = decode_body(ContentCoding::Br, &br, 1024).expect("br");
+        assert_eq!(decoded, b"brotli ".repeat(64));
+    }
+
+    #[test]
+    fn decoded_size_is_capped_and_garbage_is_refused() {
+        let gzip = encode(
+            GzEncoder::new(Vec::new(), Compression::default()),
+            GzEncoder::finish,
+        );
+        assert!(decode_body(ContentCoding::Gzip, &gzip, TEXT.len()).is_ok());
+        let (code, _) = decode_body(ContentCoding::Gzip, &gzip, TEXT.len() - 1).unwrap_err();
+        assert_eq!(code, "constraint_violation");
+        let (code, message) = decode_body(ContentCoding::Gzip, b"not gzip", 1024).unwrap_err();
+        assert_eq!(code, "http_error");
+        assert!(message.starts_with("invalid gzip body"), "{message}");
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Bounded queue of waiting requests that dispatches round-robin across
+/// workspaces, so one busy workspace cannot hold every worker while another
+/// waits. Within a workspace requests leave in arrival order. `Dispatcher`
+/// uses one to order requests waiting for `PEP_MAX_CONCURRENT_REQUESTS`.
+#[derive(Debug)]
+pub struct FairQueue<T> {
+    capacity: usize,
+    len: usize,
+    /// Workspaces with queued requests, in the order they are next served.
+    turns: VecDeque<String>,
+    queues: HashMap<String, VecDeque<T>>,
+}
+
+impl<T> FairQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            len: 0,
+            turns: VecDeque::new(),
+            queues: HashMap::new(),
+        }
+    }
+
+    /// Queue `item` for `workspace`, or hand it back when `capacity` requests
+    /// are already waiting.
+    pub fn push(&mut self, workspace: &str, item: T) -> Result<(), T> {
+        if self.len >= self.capacity {
+            return Err(item);
+        }
+        let queue = self.queues.entry(workspace.to_string()).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(workspace.to_string());
+        }
+        queue.push_back(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// The oldest request of the workspace whose turn it is.
+    pub fn pop(&mut self) -> Option<T> {
+        let workspace = self.turns.pop_front()?;
+        let queue = self.queues.get_mut(&workspace)?;
+        let item = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&workspace);
+        } else {
+            self.turns.push_back(workspace);
+        }
+        self.len -= 1;
+        Some(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Worker slots for `PEP_MAX_CONCURRENT_REQUESTS`. A request that finds
+/// them all taken waits in a `FairQueue` of `PEP_REQUEST_QUEUE_DEPTH`, and a
+/// finishing request hands its slot straight to the next one out of it.
+/// Cloning shares the slots, so Peps serving on several threads draw on one
+/// pool (see `Pep::with_dispatcher`).
+#[derive(Clone, Debug, Default)]
+pub struct Dispatcher {
+    shared: Arc<(Mutex<Slots>, Condvar)>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    running: usize,
+    next_ticket: u64,
+    waiting: FairQueue<u64>,
+    /// Tickets handed a slot whose waiter has not woken to take it yet.
+    granted: HashSet<u64>,
+}
+
+impl Default for Slots {
+    fn default() -> Self {
+        Self {
+            running: 0,
+            next_ticket: 0,
+            waiting: FairQueue::new(0),
+            granted: HashSet::new(),
+        }
+    }
+}
+
+impl Dispatcher {
+    /// Take one of `workers` slots for a request from `workspace`, blocking
+    /// while it waits its turn, or `None` when `depth` requests are already
+    /// waiting. `workers` of 0 means no limit.
+    pub fn admit(&self, workspace: &str, workers: usize, depth: usize) -> Option<DispatchGuard> {
+        if workers == 0 {
+            return Some(DispatchGuard { shared: None });
+        }
+        let (lock, ready) = &*self.shared;
+        let mut slots = lock.lock().ok()?;
+        if slots.running < workers && slots.waiting.is_empty() {
+            slots.running += 1;
+            return Some(self.guard());
+        }
+        let ticket = slots.next_ticket;
+        slots.next_ticket += 1;
+        // Follows reloads; requests already waiting keep their place.
+        slots.waiting.capacity = depth;
+        slots.waiting.push(workspace, ticket).ok()?;
+        // A raised `workers` frees slots no finishing request will hand on.
+        while slots.running < workers
+            && let Some(next) = slots.waiting.pop()
+        {
+            slots.running += 1;
+            slots.granted.insert(next);
+        }
+        ready.notify_all();
+        while !slots.granted.remove(&ticket) {
+            slots = ready.wait(slots).ok()?;
+        }
+        Some(self.guard())
+    }
+
+    /// Requests waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.shared
+            .0
+            .lock()
+            .map(|slots| slots.waiting.len())
+            .unwrap_or(0)
+    }
+
+    fn guard(&self) -> DispatchGuard {
+        DispatchGuard {
+            shared: Some(Arc::clone(&self.shared)),
+        }
+    }
+}
+
+/// Holds a worker slot; on drop it passes to the next waiting request, or
+/// is freed when none is waiting.
+#[derive(Debug)]
+pub struct DispatchGuard {
+    shared: Option<Arc<(Mutex<Slots>, Condvar)>>,
+}
+
+impl Drop for DispatchGuard {
+    fn drop(&mut self) {
+        let Some(shared) = &self.shared else {
+            return;
+        };
+        let (lock, ready) = &**shared;
+        if let Ok(mut slots) = lock.lock() {
+            match slots.waiting.pop() {
+                Some(next) => {
+                    slots.granted.insert(next);
+                    ready.notify_all();
+                }
+                None => slots.running -= 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_workspace_does_not_starve_a_quiet_one() {
+        let mut queue = FairQueue::new(6);
+        for n in 0..4 {
+            queue.push("noisy", format!("noisy-{n}")).expect("queued");
+        }
+        queue.push("quiet", "quiet-0".to_string()).expect("queued");
+        queue.push("quiet", "quiet-1".to_string()).expect("queued");
+        assert_eq!(
+            queue.push("quiet", "quiet-2".to_string()),
+            Err("quiet-2".to_string())
+        );
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(
+            order,
+            [
+                "noisy-0", "quiet-0", "noisy-1", "quiet-1", "noisy-2", "noisy-3"
+            ]
+        );
+        assert!(queue.is_empty());
+        assert!(queue.push("quiet", "again".to_string()).is_ok());
+    }
+
+    #[test]
+    fn contended_workers_alternate_between_workspaces() {
+        let dispatcher = Dispatcher::default();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let busy = dispatcher.admit("noisy", 1, 4).expect("slot");
+
+        // Queue three noisy requests, then a quiet one, in that order.
+        let mut waiters = Vec::new();
+        for (n, workspace) in ["noisy", "noisy", "noisy", "quiet"].into_iter().enumerate() {
+            let waiter = dispatcher.clone();
+            let order = Arc::clone(&order);
+            waiters.push(std::thread::spawn(move || {
+                let _slot = waiter.admit(workspace, 1, 4).expect("queued");
+                order.lock().expect("order").push(workspace);
+            }));
+            while dispatcher.queued() <= n {
+                std::thread::yield_now();
+            }
+        }
+        assert!(dispatcher.admit("quiet", 1, 4).is_none());
+
+        drop(busy);
+        for waiter in waiters {
+            waiter.join().expect("waiter");
+        }
+        assert_eq!(
+            *order.lock().expect("order"),
+            ["noisy", "quiet", "noisy", "noisy"]
+        );
+        assert_eq!(dispatcher.queued(), 0);
+        // The slot is free again once nothing is waiting for it.
+        assert!(dispatcher.admit("quiet", 1, 0).is_some());
+    }
+}
@@ -1,14 +1,238 @@
+use crate::types::{HttpRequest, HttpResponse, PepError, StreamChunk, error_response};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
 use std::io::{self, Read, Write};
 
+/// Payload bytes read (and allocated) per step.
+const FRAME_CHUNK_BYTES: usize = 64 * 1024;
+
+/// First payload byte of a binary frame. A JSON payload starts with `{`, so
+/// this byte tells the codecs apart frame by frame.
+pub const BINARY_CODEC: u8 = 0x01;
+
+/// Fields that carry a message's body as base64 in the JSON codec; the
+/// binary codec sends it raw in the body section instead.
+const BODY_FIELDS: [&str; 2] = ["body_base64", "chunk_base64"];
+
+/// Payload encoding of a frame. The host answers each request in the codec
+/// it arrived in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FrameCodec {
+    #[default]
+    Json,
+    /// `BINARY_CODEC`, then two sections, each a BE u32 length and its bytes:
+    /// the message as JSON without its body field, and the raw body. An empty
+    /// body section means no body.
+    Binary,
+}
+
+impl FrameCodec {
+    /// The codec `payload` was written in.
+    pub fn of(payload: &[u8]) -> Self {
+        if payload.first() == Some(&BINARY_CODEC) {
+            Self::Binary
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// A frame whose length prefix exceeds the reader's limit. Carried inside an
+/// `InvalidData` io error; see `frame_too_large`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame of {} bytes exceeds max {} bytes",
+            self.len, self.max
+        )
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+/// Read one length-prefixed frame.
+///
+/// A read timeout before the first byte of a frame is returned unchanged
+/// (the peer is idle, see `is_timeout`); a timeout after part of a frame
+/// has arrived is reported as `InvalidData` so callers never mistake a
+/// stalled, half-sent frame for an idle connection.
 pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    read_frame_with_limit(stream, u32::MAX as usize)
+}
+
+/// Like `read_frame`, but refuse a frame longer than `max` before allocating
+/// or reading its payload. Below the cap the buffer grows with the bytes that
+/// actually arrive, so a length prefix alone never costs `len` bytes.
+pub fn read_frame_with_limit<R: Read>(stream: &mut R, max: usize) -> io::Result<Vec<u8>> {
     let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
+    read_frame_part(stream, &mut len_buf, true)?;
     let len = u32::from_be_bytes(len_buf) as usize;
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf)?;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            FrameTooLarge { len, max },
+        ));
+    }
+    let mut buf = Vec::with_capacity(len.min(FRAME_CHUNK_BYTES));
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(start + (len - start).min(FRAME_CHUNK_BYTES), 0);
+        read_frame_part(stream, &mut buf[start..], false)?;
+    }
     Ok(buf)
 }
 
+/// The `FrameTooLarge` inside `err`, if that is why the read failed.
+pub fn frame_too_large(err: &io::Error) -> Option<&FrameTooLarge> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<FrameTooLarge>())
+}
+
+/// Answer an oversized frame with a single `frame_too_large` error response.
+/// The payload was never read, so the stream cannot be resynchronized: the
+/// caller must close the connection after this.
+pub fn write_frame_too_large<W: Write>(
+    stream: &mut W,
+    too_large: &FrameTooLarge,
+) -> Result<(), PepError> {
+    let response = error_response("frame_too_large", &too_large.to_string());
+    write_frame(stream, &serde_json::to_vec(&response)?)?;
+    Ok(())
+}
+
+/// Receives the frames of a streamed response ahead of its `end` frame,
+/// which the caller gets back like any other response.
+pub trait StreamSink {
+    fn send_head(&mut self, head: &HttpResponse) -> io::Result<()>;
+    fn send_chunk(&mut self, chunk: &StreamChunk) -> io::Result<()>;
+}
+
+/// Writes streamed frames to a connection in the request's codec.
+pub struct FrameSink<'a, W> {
+    stream: &'a mut W,
+    codec: FrameCodec,
+}
+
+impl<'a, W> FrameSink<'a, W> {
+    pub fn new(stream: &'a mut W, codec: FrameCodec) -> Self {
+        Self { stream, codec }
+    }
+}
+
+impl<W: Write> StreamSink for FrameSink<'_, W> {
+    fn send_head(&mut self, head: &HttpResponse) -> io::Result<()> {
+        write_frame(
+            self.stream,
+            &encode_payload(head, head.body.as_deref(), self.codec)?,
+        )
+    }
+
+    fn send_chunk(&mut self, chunk: &StreamChunk) -> io::Result<()> {
+        write_frame(
+            self.stream,
+            &encode_payload(chunk, chunk.chunk.as_deref(), self.codec)?,
+        )
+    }
+}
+
+/// Encode a frame payload. `body` is the message's raw body, carried outside
+/// its fields (`HttpResponse::body`, `StreamChunk::chunk`): the binary codec
+/// sends it as the body section and the JSON codec as base64 in the message's
+/// `body_base64` or `chunk_base64` field. An empty body stays in the binary
+/// metadata as `""`, since an empty body section means no body.
+pub fn encode_payload<T: Serialize>(
+    message: &T,
+    body: Option<&[u8]>,
+    codec: FrameCodec,
+) -> io::Result<Vec<u8>> {
+    if codec == FrameCodec::Json && body.is_none() {
+        return Ok(serde_json::to_vec(message)?);
+    }
+    let mut meta = serde_json::to_value(message)?;
+    if let Some(body) = body
+        && let Some(fields) = meta.as_object_mut()
+    {
+        let field = BODY_FIELDS
+            .into_iter()
+            .find(|field| fields.contains_key(*field))
+            .unwrap_or(BODY_FIELDS[0]);
+        match codec {
+            FrameCodec::Json => {
+                fields.insert(field.to_string(), Value::String(BASE64.encode(body)));
+            }
+            FrameCodec::Binary if body.is_empty() => {
+                fields.insert(field.to_string(), Value::String(String::new()));
+            }
+            FrameCodec::Binary => {
+                fields.remove(field);
+            }
+        }
+    }
+    let meta = serde_json::to_vec(&meta)?;
+    if codec == FrameCodec::Json {
+        return Ok(meta);
+    }
+    let body = body.unwrap_or_default();
+    let mut payload = Vec::with_capacity(9 + meta.len() + body.len());
+    payload.push(BINARY_CODEC);
+    for section in [&meta[..], body] {
+        payload.extend_from_slice(&(section.len() as u32).to_be_bytes());
+        payload.extend_from_slice(section);
+    }
+    Ok(payload)
+}
+
+/// Split a payload in either codec into its JSON message and, for a binary
+/// payload with a non-empty body section, the raw body.
+pub fn split_payload(payload: &[u8]) -> Result<(&[u8], Option<&[u8]>), String> {
+    let Some(sections) = payload.strip_prefix(&[BINARY_CODEC]) else {
+        return Ok((payload, None));
+    };
+    let (meta, rest) = split_section(sections)?;
+    let (body, rest) = split_section(rest)?;
+    if !rest.is_empty() {
+        return Err(format!("{} bytes after the body section", rest.len()));
+    }
+    Ok((meta, (!body.is_empty()).then_some(body)))
+}
+
+/// Parse a request frame in either codec. A binary frame's body section
+/// becomes `body`, left raw.
+pub fn decode_request(payload: &[u8]) -> Result<HttpRequest, String> {
+    let (message, body) = split_payload(payload)?;
+    let mut request = HttpRequest::from_frame(message)?;
+    if let Some(body) = body {
+        if request.body_base64.is_some() {
+            return Err("body_base64 set alongside a body section".to_string());
+        }
+        request.body = Some(body.to_vec());
+    }
+    Ok(request)
+}
+
+/// Split a BE u32 length-prefixed section off the front of `bytes`.
+fn split_section(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let Some((len, rest)) = bytes.split_first_chunk::<4>() else {
+        return Err("truncated binary frame".to_string());
+    };
+    let len = u32::from_be_bytes(*len) as usize;
+    if len > rest.len() {
+        return Err("binary frame section overruns the frame".to_string());
+    }
+    Ok(rest.split_at(len))
+}
+
 pub fn write_frame<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
     let len = data.len() as u32;
     stream.write_all(&len.to_be_bytes())?;
@@ -16,3 +240,223 @@ pub fn write_frame<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
     stream.flush()?;
     Ok(())
 }
+
+/// Whether `err` is a socket read timeout (platforms report either kind).
+pub fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn read_frame_part<R: Read>(stream: &mut R, buf: &mut [u8], frame_start: bool) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if is_timeout(&err) && (filled > 0 || !frame_start) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "timed out in the middle of a frame",
+                ));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Yields `data`, then fails every read with a timeout.
+    struct StallingReader {
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl Read for StallingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.data.read(buf)? {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[test]
+    fn frame_round_trips() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello").expect("write");
+        let frame = read_frame(&mut Cursor::new(wire)).expect("read");
+        assert_eq!(frame, b"hello");
+    }
+
+    #[test]
+    fn oversized_frame_gets_error_response_without_reading_payload() {
+        // Declares 1 GiB but sends nothing after the prefix.
+        let wire = (1u32 << 30).to_be_bytes().to_vec();
+        let err = read_frame_with_limit(&mut Cursor::new(wire), 1024).expect_err("too large");
+        let too_large = frame_too_large(&err).expect("frame_too_large");
+        assert_eq!(too_large.len, 1 << 30);
+
+        let mut reply = Vec::new();
+        write_frame_too_large(&mut reply, too_large).expect("reply");
+        let frame = read_frame(&mut Cursor::new(reply)).expect("client reads reply");
+        let response: crate::types::HttpResponse = serde_json::from_slice(&frame).expect("json");
+        let error = response.error.expect("error envelope");
+        assert_eq!(error.code, "frame_too_large");
+        assert!(error.message.contains("1073741824"));
+    }
+
+    #[test]
+    fn binary_request_round_trips_to_the_json_form() {
+        let mut request = HttpRequest::new("POST", "https://example.com/");
+        request.headers = vec![(
+            "content-type".to_string(),
+            "application/octet-stream".to_string(),
+        )];
+        let body = [0x00, 0x01, 0xff];
+        let binary = encode_payload(&request, Some(&body), FrameCodec::Binary).expect("binary");
+        let json = encode_payload(&request, Some(&body), FrameCodec::Json).expect("json");
+        assert_eq!(FrameCodec::of(&binary), FrameCodec::Binary);
+        assert_eq!(FrameCodec::of(&json), FrameCodec::Json);
+        // The raw body rides at the end of the frame, not base64-encoded.
+        assert!(binary.ends_with(&body));
+
+        let from_binary = decode_request(&binary).expect("binary");
+        let from_json = decode_request(&json).expect("json");
+        assert_eq!(from_binary.body.as_deref(), Some(&body[..]));
+        assert!(from_binary.body_base64.is_none());
+        assert_eq!(from_json.body_base64.as_deref(), Some("AAH/"));
+        assert!(from_json.body.is_none());
+        assert_eq!(from_binary.method, from_json.method);
+        assert_eq!(from_binary.url, from_json.url);
+        assert_eq!(from_binary.headers, from_json.headers);
+    }
+
+    #[test]
+    fn binary_codec_keeps_empty_and_absent_bodies_apart() {
+        for body in [Some(Vec::new()), None] {
+            let mut response = error_response("none", "");
+            response.error = None;
+            response.status = 204;
+            let binary =
+                encode_payload(&response, body.as_deref(), FrameCodec::Binary).expect("binary");
+            let (meta, section) = split_payload(&binary).expect("decode");
+            assert!(section.is_none());
+            let decoded: HttpResponse = serde_json::from_slice(meta).expect("response");
+            assert_eq!(decoded.body_base64, body.map(|_| String::new()));
+        }
+    }
+
+    #[test]
+    fn binary_response_matches_json_response() {
+        let mut response = error_response("none", "");
+        response.error = None;
+        response.status = 200;
+        response.set_body(vec![7u8; 3000], true);
+        let json =
+            encode_payload(&response, response.body.as_deref(), FrameCodec::Json).expect("json");
+        let binary = encode_payload(&response, response.body.as_deref(), FrameCodec::Binary)
+            .expect("binary");
+        assert!(binary.len() < json.len());
+
+        let (meta, body) = split_payload(&binary).expect("decode");
+        assert_eq!(body, Some(&[7u8; 3000][..]));
+        let mut decoded: Value = serde_json::from_slice(meta).expect("meta");
+        decoded["body_base64"] = Value::String(BASE64.encode(body.unwrap_or_default()));
+        assert_eq!(
+            decoded,
+            serde_json::from_slice::<Value>(&json).expect("value")
+        );
+
+        let chunk = StreamChunk {
+            request_id: Some("r-1".to_string()),
+            chunk_base64: String::new(),
+            chunk: Some(b"data: hi\n\n".to_vec()),
+        };
+        let binary =
+            encode_payload(&chunk, chunk.chunk.as_deref(), FrameCodec::Binary).expect("chunk");
+        assert!(binary.ends_with(b"data: hi\n\n"));
+        let (meta, body) = split_payload(&binary).expect("decode");
+        assert_eq!(body, Some(&b"data: hi\n\n"[..]));
+        let decoded: StreamChunk = serde_json::from_slice(meta).expect("chunk");
+        assert_eq!(decoded.request_id.as_deref(), Some("r-1"));
+        let json = encode_payload(&chunk, chunk.chunk.as_deref(), FrameCodec::Json).expect("json");
+        let decoded: StreamChunk = serde_json::from_slice(&json).expect("chunk");
+        assert_eq!(decoded.chunk_base64, BASE64.encode(b"data: hi\n\n"));
+    }
+
+    fn binary(meta: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut payload = vec![BINARY_CODEC];
+        for section in [meta, body] {
+            payload.extend_from_slice(&(section.len() as u32).to_be_bytes());
+            payload.extend_from_slice(section);
+        }
+        payload
+    }
+
+    #[test]
+    fn malformed_binary_payloads_are_rejected() {
+        let get = br#"{"method":"GET","url":"https://example.com/","headers":[]}"#;
+        assert!(decode_request(&binary(get, b"x")).is_ok());
+        let twice = binary(
+            br#"{"method":"GET","url":"https://example.com/","headers":[],"body_base64":"AA=="}"#,
+            b"x",
+        );
+        assert!(decode_request(&twice).is_err());
+        assert!(decode_request(&binary(b"[1]", b"x")).is_err());
+
+        let valid = binary(get, b"x");
+        assert!(split_payload(&valid[..valid.len() - 1]).is_err());
+        let mut trailing = valid.clone();
+        trailing.push(0);
+        assert!(split_payload(&trailing).is_err());
+        assert!(split_payload(&[BINARY_CODEC, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn idle_client_surfaces_timeout() {
+        let mut reader = StallingReader {
+            data: Cursor::new(Vec::new()),
+        };
+        let err = read_frame(&mut reader).expect_err("expected timeout");
+        assert!(is_timeout(&err));
+    }
+
+    #[test]
+    fn stalled_mid_frame_is_an_error_not_idle() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello").expect("write");
+        wire.truncate(6);
+        let mut reader = StallingReader {
+            data: Cursor::new(wire),
+        };
+        let err = read_frame(&mut reader).expect_err("expected error");
+        assert!(!is_timeout(&err));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut reader = StallingReader {
+            data: Cursor::new(vec![0, 0]),
+        };
+        let err = read_frame(&mut reader).expect_err("expected error");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn huge_length_prefix_without_payload_fails_cleanly() {
+        // Would have allocated 4GB up front before the payload was read.
+        let mut input = Cursor::new([0xff, 0xff, 0xff, 0xff, b'{', b'}'].to_vec());
+        let err = read_frame(&mut input).expect_err("expected eof");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let payload = vec![b'x'; 3 * FRAME_CHUNK_BYTES + 7];
+        let mut framed = Vec::new();
+        write_frame(&mut framed, &payload).expect("frame");
+        assert_eq!(read_frame(&mut Cursor::new(framed)).expect("read"), payload);
+    }
+}
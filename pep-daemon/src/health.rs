@@ -1,5 +1,8 @@
 use crate::config::PepConfig;
+use crate::connections::ConnectionCounter;
+use crate::metrics::Metrics;
 use serde::Serialize;
+use std::io::{self, Read};
 
 #[derive(Debug, Serialize)]
 pub struct HealthStatus {
@@ -8,15 +11,163 @@ pub struct HealthStatus {
     pub allowed_domains_count: usize,
     pub max_request_bytes: usize,
     pub max_response_bytes: usize,
+    /// Connections currently being handled, and the most seen at once.
+    pub active_connections: usize,
+    pub peak_connections: usize,
 }
 
-/// Build a health status snapshot from the current config.
-pub fn health_check(config: &PepConfig) -> HealthStatus {
+/// Build a health status snapshot from the current config and connections.
+pub fn health_check(config: &PepConfig, connections: &ConnectionCounter) -> HealthStatus {
     HealthStatus {
         status: "ok",
         version: env!("CARGO_PKG_VERSION"),
         allowed_domains_count: config.allowed_domains.len(),
         max_request_bytes: config.max_request_bytes,
         max_response_bytes: config.max_response_bytes,
+        active_connections: connections.active(),
+        peak_connections: connections.peak(),
+    }
+}
+
+// ── Plain-HTTP health probe (macOS TCP stub only) ───────────────────────
+
+/// Upper bound on an HTTP probe's request line plus headers.
+pub const MAX_HTTP_HEAD_BYTES: usize = 8192;
+
+/// Whether the first bytes on a connection are an HTTP `GET` rather than a
+/// frame length prefix. As a prefix, `GET ` would announce a ~1.2 GB frame,
+/// so no real client frame starts this way.
+pub fn looks_like_http(prefix: &[u8]) -> bool {
+    prefix.starts_with(b"GET ")
+}
+
+/// Read an HTTP request head up to and including the blank line.
+pub fn read_http_head<R: Read>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "http request head too large",
+            ));
+        }
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.push(byte[0]);
+    }
+    Ok(head)
+}
+
+/// Build a complete HTTP/1.1 response: the health JSON for `GET /healthz`,
+/// Prometheus text for `GET /metrics`, 404 for anything else.
+pub fn http_health_response(
+    head: &[u8],
+    config: &PepConfig,
+    connections: &ConnectionCounter,
+    metrics: &Metrics,
+) -> Result<Vec<u8>, serde_json::Error> {
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let path = target.split(|b| *b == b'?').next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        (b"GET", b"/healthz") => (
+            "200 OK",
+            "application/json",
+            serde_json::to_vec(&health_check(config, connections))?,
+        ),
+        (b"GET", b"/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render().into_bytes(),
+        ),
+        _ => (
+            "404 Not Found",
+            "application/json",
+            b"{\"error\":\"not found\"}".to_vec(),
+        ),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn http_prefix_is_detected() {
+        assert!(looks_like_http(b"GET /healthz HTTP/1.1"));
+        assert!(!looks_like_http(&[0, 0, 0, 42]));
+    }
+
+    #[test]
+    fn healthz_returns_health_json() {
+        let config = PepConfig::for_tests(PathBuf::from("audit.jsonl"));
+        let connections = ConnectionCounter::default();
+        let _conn = connections.open();
+        let raw = b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let head =
+            read_http_head(&mut Cursor::new(raw.to_vec()), MAX_HTTP_HEAD_BYTES).expect("head");
+        let response = http_health_response(&head, &config, &connections, &Metrics::default())
+            .expect("response");
+        let response = String::from_utf8(response).expect("utf8");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).expect("body");
+        let json: serde_json::Value = serde_json::from_str(body).expect("json");
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["active_connections"], 1);
+        assert_eq!(json["peak_connections"], 1);
+    }
+
+    #[test]
+    fn other_paths_return_not_found() {
+        let config = PepConfig::for_tests(PathBuf::from("audit.jsonl"));
+        let connections = ConnectionCounter::default();
+        let response = http_health_response(
+            b"GET / HTTP/1.1\r\n\r\n",
+            &config,
+            &connections,
+            &Metrics::default(),
+        )
+        .expect("response");
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn metrics_path_returns_prometheus_text() {
+        let config = PepConfig::for_tests(PathBuf::from("audit.jsonl"));
+        let metrics = Metrics::default();
+        metrics
+            .policy_eval
+            .observe(std::time::Duration::from_micros(300));
+        let response = http_health_response(
+            b"GET /metrics HTTP/1.1\r\n\r\n",
+            &config,
+            &ConnectionCounter::default(),
+            &metrics,
+        )
+        .expect("response");
+        let response = String::from_utf8(response).expect("utf8");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\ncontent-type: text/plain"));
+        assert!(response.contains("pep_policy_eval_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn oversized_http_head_is_rejected() {
+        let raw = vec![b'a'; 64];
+        let err = read_http_head(&mut Cursor::new(raw), 16).expect_err("expected cap");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
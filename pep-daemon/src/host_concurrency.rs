@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Upstream requests in flight per host, for `PEP_PER_HOST_CONCURRENCY`.
+/// Cloning shares the counts, so Peps serving on several threads hold to one
+/// limit (see `Pep::with_host_concurrency`).
+#[derive(Clone, Debug, Default)]
+pub struct HostConcurrency {
+    inner: Arc<(Mutex<HashMap<String, usize>>, Condvar)>,
+}
+
+impl HostConcurrency {
+    /// Take one of `host`'s `limit` slots (0 means no limit) until the guard
+    /// is dropped, waiting up to `wait` for one to free. `None` when none did.
+    pub fn acquire(&self, host: &str, limit: usize, wait: Duration) -> Option<HostSlot> {
+        let (open, freed) = &*self.inner;
+        let open = open.lock().ok()?;
+        let full = |open: &mut HashMap<String, usize>| {
+            limit > 0 && open.get(host).is_some_and(|count| *count >= limit)
+        };
+        let (mut open, _) = freed.wait_timeout_while(open, wait, full).ok()?;
+        if full(&mut open) {
+            return None;
+        }
+        *open.entry(host.to_string()).or_insert(0) += 1;
+        Some(HostSlot {
+            inner: Arc::clone(&self.inner),
+            host: host.to_string(),
+        })
+    }
+
+    pub fn in_flight(&self, host: &str) -> usize {
+        self.inner
+            .0
+            .lock()
+            .map(|open| open.get(host).copied().unwrap_or(0))
+            .unwrap_or(0)
+    }
+}
+
+/// Frees a host slot on drop and wakes requests waiting for one.
+#[derive(Debug)]
+pub struct HostSlot {
+    inner: Arc<(Mutex<HashMap<String, usize>>, Condvar)>,
+    host: String,
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        let (open, freed) = &*self.inner;
+        if let Ok(mut open) = open.lock()
+            && let Some(count) = open.get_mut(&self.host)
+        {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.host);
+            }
+        }
+        freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_host_is_refused_until_a_slot_frees() {
+        let hosts = HostConcurrency::default();
+        let first = hosts
+            .acquire("api.example.com", 2, Duration::ZERO)
+            .expect("first");
+        let _second = hosts
+            .clone()
+            .acquire("api.example.com", 2, Duration::ZERO)
+            .expect("second");
+        assert!(
+            hosts
+                .acquire("api.example.com", 2, Duration::ZERO)
+                .is_none()
+        );
+        // Other hosts have their own slots.
+        assert!(
+            hosts
+                .acquire("cdn.example.com", 2, Duration::ZERO)
+                .is_some()
+        );
+
+        drop(first);
+        assert_eq!(hosts.in_flight("api.example.com"), 1);
+        assert!(
+            hosts
+                .acquire("api.example.com", 2, Duration::ZERO)
+                .is_some()
+        );
+        assert!(
+            hosts
+                .acquire("api.example.com", 0, Duration::ZERO)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn waiting_request_gets_the_slot_when_it_frees() {
+        let hosts = HostConcurrency::default();
+        let held = hosts
+            .acquire("api.example.com", 1, Duration::ZERO)
+            .expect("held");
+        std::thread::scope(|scope| {
+            let waiter =
+                scope.spawn(|| hosts.acquire("api.example.com", 1, Duration::from_secs(5)));
+            std::thread::sleep(Duration::from_millis(50));
+            drop(held);
+            assert!(waiter.join().expect("waiter").is_some());
+        });
+    }
+}
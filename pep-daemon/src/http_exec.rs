@@ -1,21 +1,328 @@
-use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD as BASE64};
+use base64::engine::{DecodePaddingMode, Engine};
 use bytes::Bytes;
-use reqwest::Method;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use reqwest::Url;
-use reqwest::blocking::Client;
-use std::io::Read;
+use reqwest::blocking::{Client, ClientBuilder, RequestBuilder, Response};
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, Proxy, StatusCode, tls};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
-use crate::audit::append_audit_entry;
-use crate::config::PepConfig;
-use crate::policy::{PolicyEvaluator, PolicyInput};
-use crate::ssrf::{ensure_public_host, is_scheme_allowed};
-use crate::types::{HttpRequest, HttpResponse, PepError, error_response};
+use crate::addr_health::AddrHealth;
+use crate::audit::{AuditSink, RequestAudit, append_audit_entry, append_decision_log};
+use crate::cache::is_cacheable_request;
+use crate::cassette::{self, RecordedRequest};
+use crate::charset::normalize_text_body;
+use crate::config::{
+    ContentCoding, DenyReasonMode, Http2Mode, MethodOverrideMode, PepConfig, RedirectMode,
+    TlsVersion,
+};
+use crate::decompress;
+use crate::dlp;
+use crate::framing::StreamSink;
+use crate::idempotency::{IdempotencyCache, Lookup, Reservation};
+use crate::metrics::Metrics;
+use crate::policy::{
+    Constraints, PolicyDecision, PolicyEvaluator, PolicyInput, ShadowDivergence, shadow_divergence,
+};
+use crate::rate_limit::RATE_LIMIT_WINDOW;
+use crate::ssrf::{
+    PublicAddrResolver, SsrfError, closest_allowlist_entry, is_host_allowed, is_ip_allowed,
+    is_port_allowed, is_public_ip, is_scheme_allowed, vet_host,
+};
+use crate::state::PepState;
+use crate::types::{
+    ErrorEnvelope, HttpRequest, HttpResponse, MAX_REQUEST_ID_LEN, PROTOCOL_VERSION, PepError,
+    RequestContext, SUPPORTED_PROTOCOL_VERSIONS, StreamChunk, StreamPhase, error_response,
+};
 
+/// The upstream client for `config`. Without a proxy, connections go through
+/// `PublicAddrResolver`, pinned to addresses the SSRF guard vetted and
+/// ordered by `health`, which should also be given to the `Pep`. With
+/// `PEP_SOCKS_PROXY` the proxy connects (and, for `socks5h`, resolves) on our
+/// behalf, so pinning does not apply: the SSRF guard still checks the real
+/// destination before every request, but the proxy's own lookup is trusted.
+/// `PEP_ALLOW_PRIVATE_HOSTS` also drops the pinned resolver.
+pub fn build_client(
+    config: &PepConfig,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    health: &Arc<AddrHealth>,
+) -> Result<Client, PepError> {
+    let builder = Client::builder()
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .redirect(reqwest::redirect::Policy::none());
+    let builder = match &config.socks_proxy {
+        Some(raw) => {
+            let url = Url::parse(raw)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "socks5" | "socks5h"))
+                .ok_or_else(|| {
+                    PepError::Config(
+                        "PEP_SOCKS_PROXY must be a socks5:// or socks5h:// URL".to_string(),
+                    )
+                })?;
+            builder.proxy(Proxy::all(url)?)
+        }
+        // The system resolver, so names like `localhost` reach the connector.
+        None if config.allow_private_hosts => builder,
+        None => builder.dns_resolver(Arc::new(PublicAddrResolver::new(Arc::clone(health)))),
+    };
+    // Port 0 keeps the URL's port. These bypass the resolver; requests vet
+    // the pinned address instead (see `vet_destination`).
+    let builder = config
+        .host_overrides
+        .iter()
+        .fold(builder, |builder, (host, ip)| {
+            builder.resolve(host, SocketAddr::new(*ip, 0))
+        });
+    let builder = match config.http2 {
+        Http2Mode::Auto => builder,
+        Http2Mode::Always => builder.http2_prior_knowledge(),
+        Http2Mode::Never => builder.http1_only(),
+    };
+    Ok(with_tls_versions(builder, config)?.build()?)
+}
+
+/// Bound the TLS versions offered upstream by `PEP_MIN_TLS_VERSION` and
+/// `PEP_MAX_TLS_VERSION`.
+fn with_tls_versions(
+    builder: ClientBuilder,
+    config: &PepConfig,
+) -> Result<ClientBuilder, PepError> {
+    let builder = builder.tls_version_min(tls_version(config.min_tls_version));
+    match config.max_tls_version {
+        Some(max) if max < config.min_tls_version => Err(PepError::Config(
+            "PEP_MAX_TLS_VERSION must not be below PEP_MIN_TLS_VERSION".to_string(),
+        )),
+        Some(max) => Ok(builder.tls_version_max(tls_version(max))),
+        None => Ok(builder),
+    }
+}
+
+fn tls_version(version: TlsVersion) -> tls::Version {
+    match version {
+        TlsVersion::Tls12 => tls::Version::TLS_1_2,
+        TlsVersion::Tls13 => tls::Version::TLS_1_3,
+    }
+}
+
+/// Whether a failed send was a TLS handshake that found no version both
+/// sides accept. rustls reports this only through its error text.
+fn is_tls_version_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    const MARKERS: [&str; 3] = ["ProtocolVersion", "Tls12Or13", "TlsVersionIsDisabled"];
+    let mut source = Some(err);
+    while let Some(err) = source {
+        let text = err.to_string();
+        if MARKERS.iter().any(|marker| text.contains(marker)) {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// One client per distinct `PEP_CONNECT_TIMEOUTS` value, otherwise built
+/// like `build_client`, for `Pep::with_connect_timeout_clients`.
+pub fn build_connect_timeout_clients(
+    config: &PepConfig,
+    request_timeout: Duration,
+    health: &Arc<AddrHealth>,
+) -> Result<HashMap<Duration, Client>, PepError> {
+    let mut clients = HashMap::new();
+    for (_, ms) in &config.connect_timeouts {
+        let timeout = Duration::from_millis(*ms);
+        if let Entry::Vacant(slot) = clients.entry(timeout) {
+            slot.insert(build_client(config, timeout, request_timeout, health)?);
+        }
+    }
+    Ok(clients)
+}
+
+/// Evaluate and (if allowed) execute `request`. Every response, including
+/// error envelopes, echoes the request's `request_id`, generated if absent.
+/// With a `stream` sink, an event-stream response to a request that set
+/// `stream` is forwarded to it chunk by chunk, and the returned response is
+/// the `end` frame.
+#[allow(clippy::too_many_arguments)]
 pub fn execute_request(
     client: &Client,
-    request: HttpRequest,
+    mut request: HttpRequest,
+    ctx: &RequestContext,
+    config: &PepConfig,
+    evaluator: &dyn PolicyEvaluator,
+    shadow_evaluator: Option<&dyn PolicyEvaluator>,
+    state: &PepState,
+    stream: Option<&mut dyn StreamSink>,
+) -> Result<HttpResponse, PepError> {
+    let request_id = ensure_request_id(&mut request);
+    let audit = &RequestAudit::new(
+        state.audit.as_ref(),
+        state.clock.as_ref(),
+        config.audit_fail_closed,
+    );
+    let ctx = &RequestContext {
+        audit_headers: config.audit_headers,
+        ..ctx.clone()
+    };
+    let mut response = if request_id.len() > MAX_REQUEST_ID_LEN {
+        let response = error_response(
+            "invalid_request_id",
+            &format!("request_id exceeds {MAX_REQUEST_ID_LEN} bytes"),
+        );
+        // Never log the oversized id itself.
+        request.request_id = None;
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url_string(&request.url),
+            0,
+            Some("invalid_request_id"),
+            0,
+            0,
+            0,
+            None,
+            None,
+        );
+        response
+    } else if !SUPPORTED_PROTOCOL_VERSIONS.contains(&request.protocol_version) {
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url_string(&request.url),
+            0,
+            Some("unsupported_protocol"),
+            0,
+            0,
+            0,
+            None,
+            None,
+        );
+        error_response(
+            "unsupported_protocol",
+            &format!(
+                "protocol_version {} not supported (accepted: {SUPPORTED_PROTOCOL_VERSIONS:?})",
+                request.protocol_version
+            ),
+        )
+    } else {
+        let recording = config
+            .record_dir
+            .as_deref()
+            .filter(|_| config.replay_dir.is_none() && !request.preflight)
+            .map(|dir| (dir, RecordedRequest::of(&request)));
+        let mut idempotency = None;
+        let response = execute_checked(
+            client,
+            request,
+            ctx,
+            config,
+            evaluator,
+            shadow_evaluator,
+            state,
+            audit,
+            stream,
+            &mut idempotency,
+        )?;
+        if let Some((dir, recorded)) = recording
+            && response.error.is_none()
+            && response.stream.is_none()
+            && let Err(err) = cassette::record(dir, &recorded, &response)
+        {
+            eprintln!("recording cassette failed: {err}");
+        }
+        if let Some(reservation) = idempotency
+            && response.error.is_none()
+            && response.stream.is_none()
+        {
+            reservation.complete(&response, config.idempotency_ttl(), Instant::now());
+        }
+        response
+    };
+    // Every entry was written (or failed) before this point, so a response
+    // is only ever released after its audit.
+    if audit.failed() {
+        response = error_response("audit_unavailable", "audit entry could not be written");
+    }
+    response.request_id = Some(request_id);
+    Ok(response)
+}
+
+/// The answer to an allowed preflight, modelled on `100 Continue`: send the
+/// request again, with its body.
+fn preflight_response() -> HttpResponse {
+    HttpResponse {
+        status: StatusCode::CONTINUE.as_u16(),
+        status_text: StatusCode::CONTINUE.canonical_reason().map(str::to_string),
+        headers: Vec::new(),
+        body_base64: None,
+        body: None,
+        error: None,
+        request_id: None,
+        rate_limit_limit: None,
+        rate_limit_remaining: None,
+        rate_limit_reset_secs: None,
+        retry_after_ms: None,
+        protocol_version: PROTOCOL_VERSION,
+        stream: None,
+        truncated: false,
+    }
+}
+
+/// Back-off bounds for `retry_after_ms`.
+const MIN_RETRY_AFTER: Duration = Duration::from_millis(100);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Advisory wait for an `overloaded`, `host_busy` or `idempotency_in_progress`
+/// refusal: the mean time upstreams have taken to answer, about when
+/// in-flight requests start freeing their share. One second before any upstream has answered.
+fn retry_after_ms(metrics: &Metrics) -> u64 {
+    let wait = metrics
+        .upstream_response
+        .mean()
+        .unwrap_or(Duration::from_secs(1))
+        .clamp(MIN_RETRY_AFTER, MAX_RETRY_AFTER);
+    wait.as_millis() as u64
+}
+
+/// Fill in a generated `request_id` when the client sent none (or an empty
+/// one) and return it.
+pub fn ensure_request_id(request: &mut HttpRequest) -> String {
+    match &request.request_id {
+        Some(id) if !id.is_empty() => id.clone(),
+        _ => {
+            let id = Uuid::new_v4().to_string();
+            request.request_id = Some(id.clone());
+            id
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_checked<'s>(
+    client: &Client,
+    mut request: HttpRequest,
+    ctx: &RequestContext,
     config: &PepConfig,
     evaluator: &dyn PolicyEvaluator,
+    shadow_evaluator: Option<&dyn PolicyEvaluator>,
+    state: &'s PepState,
+    audit: &dyn AuditSink,
+    mut stream: Option<&mut dyn StreamSink>,
+    idempotency: &mut Option<Reservation<'s>>,
 ) -> Result<HttpResponse, PepError> {
     // ── Parse method ────────────────────────────────────────────────
     let method: Method = match request.method.parse() {
@@ -23,8 +330,9 @@ pub fn execute_request(
         Err(_) => {
             let response = error_response("invalid_method", "invalid HTTP method");
             append_audit_entry(
-                config,
+                audit,
                 &request,
+                ctx,
                 sanitize_url_string(&request.url),
                 0,
                 Some("invalid_method"),
@@ -32,19 +340,41 @@ pub fn execute_request(
                 0,
                 0,
                 None,
+                None,
             );
             return Ok(response);
         }
     };
 
     // ── Parse URL ───────────────────────────────────────────────────
+    // Bounded before parsing: the URL is copied into the policy input and
+    // the audit log, so only a prefix of an oversized one is logged.
+    if request.url.len() > config.max_url_bytes {
+        let response = error_response("invalid_url", "URL exceeds max bytes");
+        let logged = sanitize_url_string(truncate_utf8(&request.url, config.max_url_bytes));
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            logged,
+            0,
+            Some("invalid_url"),
+            0,
+            0,
+            0,
+            None,
+            None,
+        );
+        return Ok(response);
+    }
     let mut url = match Url::parse(&request.url) {
         Ok(parsed) => parsed,
         Err(err) => {
             let response = error_response("invalid_url", &err.to_string());
             append_audit_entry(
-                config,
+                audit,
                 &request,
+                ctx,
                 sanitize_url_string(&request.url),
                 0,
                 Some("invalid_url"),
@@ -52,6 +382,7 @@ pub fn execute_request(
                 0,
                 0,
                 None,
+                None,
             );
             return Ok(response);
         }
@@ -61,8 +392,9 @@ pub fn execute_request(
     if !is_scheme_allowed(url.scheme()) {
         let response = error_response("invalid_url", "unsupported URL scheme");
         append_audit_entry(
-            config,
+            audit,
             &request,
+            ctx,
             sanitize_url(&url),
             0,
             Some("invalid_url"),
@@ -70,299 +402,1870 @@ pub fn execute_request(
             0,
             0,
             None,
+            None,
         );
         return Ok(response);
     }
 
-    // ── Policy evaluation ───────────────────────────────────────────
-    let policy_input = PolicyInput::from_http_url(&url, method.as_str());
-    let decision = evaluator.evaluate(&policy_input)?;
+    // ── Port check ──────────────────────────────────────────────────
+    if !is_port_allowed(&url, config.allowed_ports.as_deref()) {
+        let response = error_response("port_blocked", "destination port not allowed");
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("port_blocked"),
+            0,
+            0,
+            0,
+            None,
+            None,
+        );
+        return Ok(response);
+    }
 
-    if !decision.allow {
-        let reason = decision.reason.as_deref().unwrap_or("denied by policy");
-        let response = error_response("DENIED_BY_POLICY", reason);
+    // ── Header validation ───────────────────────────────────────────
+    // Catches CRLF injection up front instead of an opaque reqwest error.
+    if let Err(err) = validate_headers(&request.headers) {
+        let response = error_response("invalid_header", &err);
         append_audit_entry(
-            config,
+            audit,
             &request,
+            ctx,
             sanitize_url(&url),
             0,
-            Some("DENIED_BY_POLICY"),
+            Some("invalid_header"),
             0,
             0,
             0,
-            Some(&decision),
+            None,
+            None,
         );
         return Ok(response);
     }
 
-    // ── SSRF guard (defense in depth — always runs) ─────────────────
-    if let Err(err) = ensure_public_host(&url) {
-        let response = error_response("ssrf_blocked", &err);
+    // ── Method override headers ─────────────────────────────────────
+    // Upstreams that honor these would execute a method the policy never saw.
+    if config.method_override_mode == MethodOverrideMode::Reject
+        && let Some((name, _)) = request
+            .headers
+            .iter()
+            .find(|(name, _)| is_method_override_header(name))
+    {
+        let response = error_response(
+            "invalid_header",
+            &format!("method override header not allowed: {name}"),
+        );
         append_audit_entry(
-            config,
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("invalid_header"),
+            0,
+            0,
+            0,
+            None,
+            None,
+        );
+        return Ok(response);
+    }
+    let mut forward_headers = strip_method_override_headers(&request.headers);
+    forward_headers.retain(|(name, _)| !is_stripped_header(name, &config.stripped_request_headers));
+    apply_user_agent(&mut forward_headers, config);
+
+    // ── Policy evaluation ───────────────────────────────────────────
+    // Runs before any DNS so a host the policy rejects is never resolved.
+    let mut policy_input = PolicyInput::from_http_url_at(
+        &url,
+        method.as_str(),
+        state.clock.now(),
+        config.policy_utc_offset,
+    );
+    let (decision, shadow) = evaluate_policy(
+        config,
+        &state.metrics,
+        evaluator,
+        shadow_evaluator,
+        &policy_input,
+    )?;
+    let ctx = &RequestContext {
+        shadow,
+        ..ctx.clone()
+    };
+
+    if !decision.allow {
+        let mut reason = deny_message(
+            decision.reason.as_deref(),
+            config.deny_reason,
+            "denied by policy",
+        );
+        if let Some(hint) = allowlist_hint(config, evaluator, &policy_input) {
+            reason = format!("{reason}; {hint}");
+        }
+        let response = policy_deny_response(config, "DENIED_BY_POLICY", &reason, ctx.raw_body);
+        append_audit_entry(
+            audit,
             &request,
+            ctx,
             sanitize_url(&url),
             0,
-            Some("ssrf_blocked"),
+            Some("DENIED_BY_POLICY"),
             0,
             0,
             0,
             Some(&decision),
+            None,
         );
         return Ok(response);
     }
 
-    // ── Decode request body ─────────────────────────────────────────
-    let body_bytes = if let Some(body_base64) = request.body_base64.as_ref() {
-        let body = match BASE64.decode(body_base64.as_str()) {
-            Ok(body) => body,
-            Err(err) => {
-                let response = error_response("invalid_body", &format!("base64 decode: {err}"));
-                append_audit_entry(
-                    config,
-                    &request,
-                    sanitize_url(&url),
-                    0,
-                    Some("invalid_body"),
-                    0,
-                    0,
-                    0,
-                    Some(&decision),
-                );
-                return Ok(response);
-            }
-        };
-        if body.len() > config.max_request_bytes {
-            let response = error_response("constraint_violation", "request body exceeds max bytes");
+    // ── SSRF guard (defense in depth) ───────────────────────────────
+    // Only `PEP_ALLOW_PRIVATE_HOSTS` skips the public-address check, and
+    // every entry from here on records that it did.
+    let ctx = &RequestContext {
+        ssrf_bypassed: config.allow_private_hosts,
+        ..ctx.clone()
+    };
+    let resolved_ip = match vet_destination(config, &url) {
+        Ok(ip) => ip,
+        Err(err) => {
+            let response = error_response(err.code(), &err.to_string());
             append_audit_entry(
-                config,
+                audit,
                 &request,
+                ctx,
                 sanitize_url(&url),
                 0,
-                Some("constraint_violation"),
+                Some(err.code()),
                 0,
                 0,
                 0,
                 Some(&decision),
+                None,
             );
             return Ok(response);
         }
-        Some(Bytes::from(body))
-    } else {
-        None
     };
-    let request_bytes = body_bytes.as_ref().map(|body| body.len()).unwrap_or(0);
 
-    // ── Response size cap (prefer policy constraint over config) ─────
-    let max_response = decision
-        .constraints
-        .as_ref()
-        .and_then(|c| c.max_bytes)
-        .unwrap_or(config.max_response_bytes);
+    // ── Policy re-check against the vetted address ──────────────────
+    policy_input.action.resource.ip = Some(resolved_ip.to_string());
+    let (decision, shadow) = evaluate_policy(
+        config,
+        &state.metrics,
+        evaluator,
+        shadow_evaluator,
+        &policy_input,
+    )?;
+    let ctx = &RequestContext {
+        shadow,
+        ..ctx.clone()
+    };
 
-    // ── Execute with redirect handling ──────────────────────────────
-    let mut redirects = 0;
-    loop {
-        let mut builder = client.request(method.clone(), url.clone());
-        for (key, value) in &request.headers {
-            builder = builder.header(key, value);
-        }
-        if let Some(body) = &body_bytes {
-            builder = builder.body(body.clone());
-        }
+    if !decision.allow {
+        let reason = deny_message(
+            decision.reason.as_deref(),
+            config.deny_reason,
+            "denied by policy",
+        );
+        let response = policy_deny_response(config, "DENIED_BY_POLICY", &reason, ctx.raw_body);
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("DENIED_BY_POLICY"),
+            0,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    }
+
+    // ── Preflight: the checks above passed, stop before the body ────
+    if request.preflight {
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            StatusCode::CONTINUE.as_u16(),
+            None,
+            0,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(preflight_response());
+    }
 
-        let response = match builder.send() {
-            Ok(resp) => resp,
-            Err(err) => {
-                let error = error_response("http_error", &err.to_string());
+    // ── Decode request body ─────────────────────────────────────────
+    // A binary frame's body section arrives raw; only `body_base64` needs
+    // decoding.
+    let body = match (request.body.take(), request.body_base64.as_ref()) {
+        (Some(body), _) => Some(body),
+        (None, Some(body_base64)) => {
+            // Reject on encoded length first so an oversized payload is never
+            // decoded into memory.
+            if base64_decoded_len(body_base64) > config.max_request_bytes {
+                let response =
+                    error_response("constraint_violation", "request body exceeds max bytes");
                 append_audit_entry(
-                    config,
+                    audit,
                     &request,
+                    ctx,
                     sanitize_url(&url),
                     0,
-                    Some("http_error"),
-                    request_bytes,
+                    Some("constraint_violation"),
+                    0,
                     0,
-                    redirects,
-                    Some(&decision),
-                );
-                return Ok(error);
-            }
-        };
-
-        if response.status().is_redirection() {
-            if redirects >= config.max_redirects {
-                let error = error_response("redirect_blocked", "redirect limit exceeded");
-                append_audit_entry(
-                    config,
-                    &request,
-                    sanitize_url(&url),
-                    response.status().as_u16(),
-                    Some("redirect_blocked"),
-                    request_bytes,
                     0,
-                    redirects,
                     Some(&decision),
+                    None,
                 );
-                return Ok(error);
+                return Ok(response);
             }
-
-            let location = match response.headers().get(reqwest::header::LOCATION) {
-                Some(loc) => loc.to_str().unwrap_or_default().to_string(),
-                None => {
-                    let error = error_response("redirect_blocked", "missing Location header");
+            match decode_request_body(body_base64) {
+                Ok(body) => Some(body),
+                Err(err) => {
+                    let response = error_response("invalid_body", &format!("base64 decode: {err}"));
                     append_audit_entry(
-                        config,
+                        audit,
                         &request,
+                        ctx,
                         sanitize_url(&url),
-                        response.status().as_u16(),
-                        Some("redirect_blocked"),
-                        request_bytes,
                         0,
-                        redirects,
-                        Some(&decision),
-                    );
-                    return Ok(error);
-                }
-            };
-
-            let next_url = match url.join(&location) {
-                Ok(next) => next,
-                Err(_) => {
-                    let error = error_response("redirect_blocked", "invalid redirect URL");
-                    append_audit_entry(
-                        config,
-                        &request,
-                        sanitize_url(&url),
-                        response.status().as_u16(),
-                        Some("redirect_blocked"),
-                        request_bytes,
+                        Some("invalid_body"),
+                        0,
+                        0,
                         0,
-                        redirects,
                         Some(&decision),
+                        None,
                     );
-                    return Ok(error);
+                    return Ok(response);
                 }
-            };
-
-            if next_url.scheme() != url.scheme() {
-                let error = error_response("redirect_blocked", "scheme change blocked");
-                append_audit_entry(
-                    config,
-                    &request,
-                    sanitize_url(&url),
-                    response.status().as_u16(),
-                    Some("redirect_blocked"),
-                    request_bytes,
-                    0,
-                    redirects,
-                    Some(&decision),
-                );
-                return Ok(error);
             }
-
-            // Re-evaluate policy for the redirect target.
-            let redirect_input = PolicyInput::from_http_url(&next_url, method.as_str());
-            let redirect_decision = evaluator.evaluate(&redirect_input)?;
-            if !redirect_decision.allow {
-                let reason = redirect_decision
-                    .reason
-                    .as_deref()
-                    .unwrap_or("redirect domain denied by policy");
-                let error = error_response("redirect_blocked", reason);
-                append_audit_entry(
-                    config,
-                    &request,
-                    sanitize_url(&url),
-                    response.status().as_u16(),
-                    Some("redirect_blocked"),
+        }
+        (None, None) => None,
+    };
+    if body
+        .as_ref()
+        .is_some_and(|body| body.len() > config.max_request_bytes)
+    {
+        let response = error_response("constraint_violation", "request body exceeds max bytes");
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("constraint_violation"),
+            0,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    }
+    let body_bytes = body.map(Bytes::from);
+    // Cassettes match the body as sent, before the optional gzip below.
+    let replay = config.replay_dir.as_deref().map(|dir| {
+        (
+            dir,
+            RecordedRequest::with_body(&request, body_bytes.as_deref().unwrap_or_default()),
+        )
+    });
+    let request_bytes = body_bytes.as_ref().map(|body| body.len()).unwrap_or(0);
+
+    // ── Data-loss prevention (`PEP_DLP_PATTERNS`) ───────────────────
+    if let Some(category) = body_bytes
+        .as_deref()
+        .and_then(|body| dlp::scan(&config.dlp_patterns, body, config.dlp_scan_bytes))
+    {
+        let ctx = &RequestContext {
+            dlp_category: Some(category.to_string()),
+            ..ctx.clone()
+        };
+        let response = error_response(
+            "dlp_blocked",
+            &format!("request body matches blocked pattern {category}"),
+        );
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("dlp_blocked"),
+            request_bytes,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    }
+
+    // ── Policy-injected request headers ─────────────────────────────
+    if let Some(injected) = decision
+        .constraints
+        .as_ref()
+        .and_then(|c| c.inject_headers.as_ref())
+    {
+        inject_headers(&mut forward_headers, injected);
+    }
+
+    // ── Optional gzip of the request body (caps apply uncompressed) ──
+    let body_bytes = match body_bytes {
+        Some(body) if should_compress_request(config, &url, &forward_headers, &body) => {
+            let compressed = gzip_body(&body)?;
+            forward_headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+            forward_headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+            Some(Bytes::from(compressed))
+        }
+        other => other,
+    };
+
+    // ── Response size cap (prefer policy constraint over config) ─────
+    let max_response = decision
+        .constraints
+        .as_ref()
+        .and_then(|c| c.max_bytes)
+        .unwrap_or(config.max_response_bytes);
+
+    // ── Per-host byte quota ─────────────────────────────────────────
+    // Charged to the originally requested host, even across redirects.
+    let quota_window = Duration::from_secs(config.quota_window_secs);
+    let quota = url.host_str().and_then(|host| {
+        quota_for(
+            host,
+            &config.host_byte_quotas,
+            decision.constraints.as_ref(),
+        )
+    });
+    if let Some((key, limit)) = &quota
+        && let Err(err) = state
+            .quotas
+            .check(key, *limit, quota_window, Instant::now())
+    {
+        let response = error_response("quota_exceeded", &err);
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("quota_exceeded"),
+            0,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    }
+
+    // ── Per-host request rate (policy `rate_limit_per_min`) ─────────
+    if let Some(limit) = decision
+        .constraints
+        .as_ref()
+        .and_then(|c| c.rate_limit_per_min)
+        && let Some(host) = url.host_str()
+        && let Err(limited) = state.rate_limits.acquire(
+            &host.to_lowercase(),
+            limit,
+            RATE_LIMIT_WINDOW,
+            Instant::now(),
+        )
+    {
+        let response = HttpResponse {
+            rate_limit_limit: Some(limited.limit),
+            rate_limit_remaining: Some(limited.remaining),
+            rate_limit_reset_secs: Some(limited.reset_secs),
+            ..error_response(
+                "rate_limited",
+                &format!("rate limit of {limit} requests per minute reached for {host}"),
+            )
+        };
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("rate_limited"),
+            0,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    }
+
+    // ── Retry of a request already answered (`idempotency_key`) ─────
+    // The key stays reserved until this request finishes, so a retry sent
+    // while it is in flight cannot reach the upstream a second time.
+    let lookup = IdempotencyCache::key(&request, ctx.peer_cid)
+        .filter(|_| !config.idempotency_ttl().is_zero())
+        .and_then(|key| {
+            state
+                .idempotency
+                .begin(key, config.idempotency_ttl(), Instant::now())
+        });
+    match lookup {
+        Some(Lookup::InProgress) => {
+            let response = HttpResponse {
+                retry_after_ms: Some(retry_after_ms(&state.metrics)),
+                ..error_response(
+                    "idempotency_in_progress",
+                    "a request with this idempotency_key is still in flight",
+                )
+            };
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                0,
+                Some("idempotency_in_progress"),
+                request_bytes,
+                0,
+                0,
+                Some(&decision),
+                None,
+            );
+            return Ok(response);
+        }
+        Some(Lookup::Replay(mut response)) => {
+            response.protocol_version = PROTOCOL_VERSION;
+            // Stored in the first caller's form; answer in this caller's.
+            let body = response.take_body();
+            let delivered = body.as_deref().unwrap_or_default();
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                response.status,
+                None,
+                request_bytes,
+                delivered.len(),
+                0,
+                Some(&decision),
+                Some(format!("{:x}", Sha256::digest(delivered))),
+            );
+            if let Some(body) = body {
+                response.set_body(body, ctx.raw_body);
+            }
+            return Ok(response);
+        }
+        Some(Lookup::Reserved(reservation)) => *idempotency = Some(reservation),
+        None => {}
+    }
+
+    // ── Worker slot (`PEP_MAX_CONCURRENT_REQUESTS`) ──────────────────
+    // Waits its turn, round-robin across workspaces, when every slot is
+    // taken.
+    let workspace = request.workspace_id.clone().unwrap_or_else(|| {
+        ctx.peer_cid
+            .map_or_else(|| "default".to_string(), |cid| cid.to_string())
+    });
+    let Some(worker) = state
+        .dispatcher
+        .admit(
+            &workspace,
+            config.max_concurrent_requests,
+            config.request_queue_depth,
+        )
+        .map(Arc::new)
+    else {
+        let response = HttpResponse {
+            retry_after_ms: Some(retry_after_ms(&state.metrics)),
+            ..error_response(
+                "overloaded",
+                &format!(
+                    "{} requests already waiting for one of {} workers",
+                    config.request_queue_depth, config.max_concurrent_requests
+                ),
+            )
+        };
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("overloaded"),
+            request_bytes,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    };
+
+    // ── In-flight memory budget (`PEP_MAX_TOTAL_INFLIGHT_BYTES`) ─────
+    // The body is held across redirects and the response may grow to its
+    // cap, so both are reserved up front.
+    let reservation = request_bytes.saturating_add(max_response);
+    let Some(inflight) = state
+        .inflight
+        .try_reserve(reservation, config.max_total_inflight_bytes)
+        .map(Arc::new)
+    else {
+        let response = HttpResponse {
+            retry_after_ms: Some(retry_after_ms(&state.metrics)),
+            ..error_response(
+                "overloaded",
+                &format!(
+                    "{reservation} more in-flight bytes would exceed max {} bytes",
+                    config.max_total_inflight_bytes
+                ),
+            )
+        };
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            0,
+            Some("overloaded"),
+            request_bytes,
+            0,
+            0,
+            Some(&decision),
+            None,
+        );
+        return Ok(response);
+    };
+
+    // ── Replay a recorded response instead of the upstream ──────────
+    if let Some((dir, recorded)) = replay {
+        let (mut response, error_code) = match cassette::replay(dir, &recorded) {
+            Ok(Some(response)) => (response, None),
+            Ok(None) => (
+                error_response("replay_miss", "no recorded response matches this request"),
+                Some("replay_miss"),
+            ),
+            Err(err) => (
+                error_response("replay_miss", &format!("reading cassette: {err}")),
+                Some("replay_miss"),
+            ),
+        };
+        response.protocol_version = PROTOCOL_VERSION;
+        let body = response.take_body();
+        let delivered = body.as_deref().unwrap_or_default();
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            response.status,
+            error_code,
+            request_bytes,
+            delivered.len(),
+            0,
+            Some(&decision),
+            error_code
+                .is_none()
+                .then(|| format!("{:x}", Sha256::digest(delivered))),
+        );
+        if let Some(body) = body {
+            response.set_body(body, ctx.raw_body);
+        }
+        return Ok(response);
+    }
+
+    // ── Execute with redirect handling ──────────────────────────────
+    let mut redirects = 0;
+    let breaker = config.breaker_settings();
+    let use_cache =
+        config.response_cache && is_cacheable_request(method.as_str(), &forward_headers);
+    let mut host_slot = None;
+    loop {
+        let breaker_host = url.host_str().unwrap_or_default().to_lowercase();
+        if let Err(err) = state
+            .breakers
+            .check(&breaker_host, &breaker, Instant::now())
+        {
+            let error = error_response("circuit_open", &err);
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                0,
+                Some("circuit_open"),
+                request_bytes,
+                0,
+                redirects,
+                Some(&decision),
+                None,
+            );
+            return Ok(error);
+        }
+
+        // ── Per-host concurrency (`PEP_PER_HOST_CONCURRENCY`) ───────
+        // Held until the body is read; a redirect gives it up first, so a
+        // hop to the same host cannot wait on itself.
+        host_slot.take();
+        let limit = config.concurrency_limit_for(&breaker_host);
+        host_slot = state
+            .host_concurrency
+            .acquire(&breaker_host, limit, config.host_busy_wait())
+            .map(Arc::new);
+        if host_slot.is_none() {
+            let error = HttpResponse {
+                retry_after_ms: Some(retry_after_ms(&state.metrics)),
+                ..error_response(
+                    "host_busy",
+                    &format!("{breaker_host} already has {limit} requests in flight"),
+                )
+            };
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                0,
+                Some("host_busy"),
+                request_bytes,
+                0,
+                redirects,
+                Some(&decision),
+                None,
+            );
+            return Ok(error);
+        }
+
+        let client = url
+            .host_str()
+            .and_then(|host| config.connect_timeout_for(host))
+            .and_then(|timeout| state.connect_clients.get(&timeout))
+            .unwrap_or(client);
+        let mut builder = client.request(method.clone(), url.clone());
+        for (key, value) in &forward_headers {
+            builder = builder.header(key, value);
+        }
+        if let Some(body) = &body_bytes {
+            builder = builder.body(body.clone());
+        }
+        let cache_key = use_cache.then(|| url.to_string());
+        let mut revalidating = false;
+        if let Some(key) = &cache_key {
+            for (name, value) in state.cache.validators(key, Instant::now()) {
+                builder = builder.header(name, value);
+                revalidating = true;
+            }
+        }
+
+        let sent = Instant::now();
+        let slots = (
+            Arc::clone(&worker),
+            Arc::clone(&inflight),
+            host_slot.clone(),
+        );
+        let mut response = match send_within(builder, config.ttfb_timeout(), slots) {
+            Ok(resp) => {
+                state.metrics.upstream_response.observe(sent.elapsed());
+                state.breakers.record_success(&breaker_host);
+                if let Some(addr) = resp.remote_addr() {
+                    state
+                        .addr_health
+                        .record_connected(&breaker_host, addr.ip(), Instant::now());
+                }
+                resp
+            }
+            Err(failure) => {
+                state
+                    .breakers
+                    .record_failure(&breaker_host, &breaker, Instant::now());
+                let error = match failure {
+                    SendFailure::Ttfb(limit) => error_response(
+                        "ttfb_timeout",
+                        &format!("no response from upstream within {}s", limit.as_secs()),
+                    ),
+                    SendFailure::Upstream(err) => {
+                        if err.is_connect() {
+                            state
+                                .addr_health
+                                .record_connect_failure(&breaker_host, Instant::now());
+                        }
+                        if is_tls_version_error(&err) {
+                            error_response(
+                                "tls_version",
+                                "upstream offers no TLS version within PEP_MIN_TLS_VERSION/PEP_MAX_TLS_VERSION",
+                            )
+                        } else {
+                            error_response("http_error", &err.to_string())
+                        }
+                    }
+                    SendFailure::Other(message) => error_response("http_error", &message),
+                };
+                let code = error.error.as_ref().map(|error| error.code.clone());
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    0,
+                    code.as_deref(),
                     request_bytes,
                     0,
                     redirects,
-                    Some(&redirect_decision),
+                    Some(&decision),
+                    None,
+                );
+                return Ok(error);
+            }
+        };
+
+        let disposition = redirect_disposition(config.redirect_mode, response.status());
+        if disposition == RedirectDisposition::Reject {
+            let error = error_response("redirect_blocked", "redirects are disabled");
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                response.status().as_u16(),
+                Some("redirect_blocked"),
+                request_bytes,
+                0,
+                redirects,
+                Some(&decision),
+                None,
+            );
+            return Ok(error);
+        }
+
+        if disposition == RedirectDisposition::Follow {
+            if redirects >= config.max_redirects {
+                let error = error_response("redirect_blocked", "redirect limit exceeded");
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    response.status().as_u16(),
+                    Some("redirect_blocked"),
+                    request_bytes,
+                    0,
+                    redirects,
+                    Some(&decision),
+                    None,
+                );
+                return Ok(error);
+            }
+
+            let location = match response.headers().get(reqwest::header::LOCATION) {
+                Some(loc) => loc.to_str().unwrap_or_default().to_string(),
+                None => {
+                    let error = error_response("redirect_blocked", "missing Location header");
+                    append_audit_entry(
+                        audit,
+                        &request,
+                        ctx,
+                        sanitize_url(&url),
+                        response.status().as_u16(),
+                        Some("redirect_blocked"),
+                        request_bytes,
+                        0,
+                        redirects,
+                        Some(&decision),
+                        None,
+                    );
+                    return Ok(error);
+                }
+            };
+
+            let next_url = match url.join(&location) {
+                Ok(next) => next,
+                Err(_) => {
+                    let error = error_response("redirect_blocked", "invalid redirect URL");
+                    append_audit_entry(
+                        audit,
+                        &request,
+                        ctx,
+                        sanitize_url(&url),
+                        response.status().as_u16(),
+                        Some("redirect_blocked"),
+                        request_bytes,
+                        0,
+                        redirects,
+                        Some(&decision),
+                        None,
+                    );
+                    return Ok(error);
+                }
+            };
+
+            if let Some((code, message)) = redirect_scheme_error(
+                url.scheme(),
+                next_url.scheme(),
+                config.allow_redirect_upgrade,
+            ) {
+                let error = error_response(code, message);
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    response.status().as_u16(),
+                    Some(code),
+                    request_bytes,
+                    0,
+                    redirects,
+                    Some(&decision),
+                    None,
                 );
                 return Ok(error);
             }
 
-            // SSRF guard on redirect target.
-            if let Err(err) = ensure_public_host(&next_url) {
-                let error = error_response("ssrf_blocked", &err);
+            if !is_port_allowed(&next_url, config.allowed_ports.as_deref()) {
+                let error = error_response("port_blocked", "redirect port not allowed");
                 append_audit_entry(
-                    config,
+                    audit,
                     &request,
+                    ctx,
                     sanitize_url(&url),
                     response.status().as_u16(),
-                    Some("ssrf_blocked"),
+                    Some("port_blocked"),
+                    request_bytes,
+                    0,
+                    redirects,
+                    Some(&decision),
+                    None,
+                );
+                return Ok(error);
+            }
+
+            // Re-evaluate policy for the redirect target, before and after
+            // resolving it, as for the initial request.
+            let mut redirect_input = PolicyInput::from_http_url_at(
+                &next_url,
+                method.as_str(),
+                state.clock.now(),
+                config.policy_utc_offset,
+            );
+            let (mut redirect_decision, _) =
+                evaluate_policy(config, &state.metrics, evaluator, None, &redirect_input)?;
+            let mut redirect_ip = None;
+            if redirect_decision.allow {
+                // SSRF guard on redirect target.
+                match vet_destination(config, &next_url) {
+                    Ok(ip) => redirect_ip = Some(ip),
+                    Err(err) => {
+                        let error = error_response(err.code(), &err.to_string());
+                        append_audit_entry(
+                            audit,
+                            &request,
+                            ctx,
+                            sanitize_url(&url),
+                            response.status().as_u16(),
+                            Some(err.code()),
+                            request_bytes,
+                            0,
+                            redirects,
+                            Some(&decision),
+                            None,
+                        );
+                        return Ok(error);
+                    }
+                }
+            }
+            if let Some(ip) = redirect_ip {
+                redirect_input.action.resource.ip = Some(ip.to_string());
+                (redirect_decision, _) =
+                    evaluate_policy(config, &state.metrics, evaluator, None, &redirect_input)?;
+            }
+            if !redirect_decision.allow {
+                let reason = deny_message(
+                    redirect_decision.reason.as_deref(),
+                    config.deny_reason,
+                    "redirect domain denied by policy",
+                );
+                let error = policy_deny_response(config, "redirect_blocked", &reason, ctx.raw_body);
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    response.status().as_u16(),
+                    Some("redirect_blocked"),
+                    request_bytes,
+                    0,
+                    redirects,
+                    Some(&redirect_decision),
+                    None,
+                );
+                return Ok(error);
+            }
+
+            // Only the Location matters; never read a large hop body.
+            drain_redirect_body(&mut response, MAX_REDIRECT_BODY_BYTES);
+
+            redirects += 1;
+            url = next_url;
+            continue;
+        }
+
+        // ── Success path ────────────────────────────────────────────
+        let upstream_status = response.status();
+
+        // Server-Sent Events go to the VM as they arrive, never cached.
+        if request.stream
+            && let Some(sink) = stream.as_deref_mut()
+            && is_event_stream(response.headers())
+        {
+            let status = upstream_status.as_u16();
+            let mut headers = response
+                .headers()
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+                .collect::<Vec<_>>();
+            // Chunk frames carry the body; the upstream's framing is moot.
+            headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("transfer-encoding")
+                    && !name.eq_ignore_ascii_case("content-length")
+            });
+            if config.sort_headers {
+                sort_headers(&mut headers);
+            }
+            let head = HttpResponse {
+                status,
+                status_text: upstream_status.canonical_reason().map(str::to_string),
+                headers,
+                body_base64: None,
+                body: None,
+                error: None,
+                request_id: request.request_id.clone(),
+                rate_limit_limit: None,
+                rate_limit_remaining: None,
+                rate_limit_reset_secs: None,
+                retry_after_ms: None,
+                protocol_version: PROTOCOL_VERSION,
+                stream: Some(StreamPhase::Head),
+                truncated: false,
+            };
+            let streamed = sink.send_head(&head).and_then(|()| {
+                stream_body(
+                    &mut response,
+                    sink,
+                    request.request_id.as_deref(),
+                    max_response,
+                    ctx.raw_body,
+                )
+            });
+            let streamed = match streamed {
+                Ok(streamed) => streamed,
+                Err(err) => {
+                    // The VM is gone; nothing more can be delivered.
+                    append_audit_entry(
+                        audit,
+                        &request,
+                        ctx,
+                        sanitize_url(&url),
+                        status,
+                        Some("stream_aborted"),
+                        request_bytes,
+                        0,
+                        redirects,
+                        Some(&decision),
+                        None,
+                    );
+                    return Err(PepError::Io(err));
+                }
+            };
+            if let Some((key, _)) = &quota {
+                let used = (request_bytes + streamed.bytes) as u64;
+                state.quotas.record(key, used, quota_window, Instant::now());
+            }
+            let error_code = streamed.error.as_ref().map(|(code, _)| *code);
+            let ctx = &RequestContext {
+                response_headers: ctx.audit_headers.then(|| head.headers.clone()),
+                ..ctx.clone()
+            };
+            append_audit_entry(
+                audit,
+                &request,
+                ctx,
+                sanitize_url(&url),
+                status,
+                error_code,
+                request_bytes,
+                streamed.bytes,
+                redirects,
+                Some(&decision),
+                streamed.error.is_none().then_some(streamed.digest),
+            );
+            return Ok(HttpResponse {
+                status,
+                status_text: None,
+                headers: Vec::new(),
+                body_base64: None,
+                body: None,
+                error: streamed.error.map(|(code, message)| ErrorEnvelope {
+                    code: code.to_string(),
+                    message,
+                }),
+                request_id: None,
+                rate_limit_limit: None,
+                rate_limit_remaining: None,
+                rate_limit_reset_secs: None,
+                retry_after_ms: None,
+                protocol_version: PROTOCOL_VERSION,
+                stream: Some(StreamPhase::End),
+                truncated: false,
+            });
+        }
+
+        // A HEAD response has no body to read; its `Content-Length`
+        // describes the GET and is passed through as is.
+        let head = method == Method::HEAD;
+
+        // A 304 to our own validators is served from the cache.
+        let cached = match &cache_key {
+            Some(key) if revalidating && upstream_status == StatusCode::NOT_MODIFIED => {
+                state.cache.revalidated(key, Instant::now())
+            }
+            _ => None,
+        };
+        let mut length_mismatch = None;
+        let fetched = match cached {
+            Some(mut hit) if hit.body.len() > max_response => {
+                if config.truncate_oversized {
+                    hit.body.truncate(max_response);
+                    Ok((hit.status, hit.headers, hit.body, None, true))
+                } else {
+                    Err((
+                        "constraint_violation",
+                        "response body exceeds max bytes".to_string(),
+                    ))
+                }
+            }
+            Some(hit) => Ok((hit.status, hit.headers, hit.body, None, false)),
+            None => {
+                let status = upstream_status.as_u16();
+                let headers = response
+                    .headers()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+                    .collect::<Vec<_>>();
+                if head {
+                    Ok((status, headers, Vec::new(), None, false))
+                } else {
+                    read_body_with_cap(
+                        response,
+                        max_response,
+                        config.truncate_oversized,
+                        config.strict_content_length,
+                    )
+                    .map_err(|failed| {
+                        length_mismatch = failed.length_mismatch;
+                        (failed.code, failed.message)
+                    })
+                    .map(|read| {
+                        length_mismatch = read.length_mismatch;
+                        if let Some(key) = cache_key
+                            .as_ref()
+                            .filter(|_| !read.truncated && length_mismatch.is_none())
+                        {
+                            state
+                                .cache
+                                .store(key, status, &headers, &read.body, Instant::now());
+                        }
+                        (
+                            status,
+                            headers,
+                            read.body,
+                            Some(read.digest),
+                            read.truncated,
+                        )
+                    })
+                }
+            }
+        };
+        let (status, mut headers, body, digest, truncated) = match fetched {
+            Ok(fetched) => fetched,
+            Err((code, err)) => {
+                let error = error_response(code, &err);
+                let ctx = &RequestContext {
+                    content_length_mismatch: length_mismatch,
+                    ..ctx.clone()
+                };
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    upstream_status.as_u16(),
+                    Some(code),
+                    request_bytes,
+                    0,
+                    redirects,
+                    Some(&decision),
+                    None,
+                );
+                return Ok(error);
+            }
+        };
+        let status_text = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|status| status.canonical_reason())
+            .map(|reason| reason.to_string());
+
+        // ── Optional decompression (cap applies to the decoded body) ─
+        // A truncated body cannot be decoded, so it passes through as is.
+        let decoded = if head || truncated {
+            Ok(None)
+        } else {
+            decompress_response(
+                &mut headers,
+                &body,
+                &config.decompress_encodings,
+                max_response,
+            )
+        };
+        let (body, digest) = match decoded {
+            // As with transcoding, the streamed digest no longer applies.
+            Ok(Some(decoded)) => (decoded, None),
+            Ok(None) => (body, digest),
+            Err((code, err)) => {
+                let error = error_response(code, &err);
+                append_audit_entry(
+                    audit,
+                    &request,
+                    ctx,
+                    sanitize_url(&url),
+                    status,
+                    Some(code),
                     request_bytes,
                     0,
                     redirects,
                     Some(&decision),
+                    None,
                 );
                 return Ok(error);
             }
+        };
+
+        // ── Optional text normalization (cap applies to the output) ─
+        let (body, digest) = if config.normalize_text && !head {
+            match normalize_response_text(&mut headers, body, max_response) {
+                // The streamed digest covers the upstream bytes, not the
+                // transcoded ones.
+                Ok(body) => (body, None),
+                Err(err) => {
+                    let error = error_response("constraint_violation", &err);
+                    append_audit_entry(
+                        audit,
+                        &request,
+                        ctx,
+                        sanitize_url(&url),
+                        status,
+                        Some("constraint_violation"),
+                        request_bytes,
+                        0,
+                        redirects,
+                        Some(&decision),
+                        None,
+                    );
+                    return Ok(error);
+                }
+            }
+        } else {
+            (body, digest)
+        };
+        let response_sha256 = digest.unwrap_or_else(|| format!("{:x}", Sha256::digest(&body)));
+
+        // The VM gets the body whole; describe it rather than the
+        // upstream's framing.
+        if !head {
+            set_body_framing_headers(&mut headers, body.len());
+        }
+        if config.sort_headers {
+            sort_headers(&mut headers);
+        }
+
+        if let Some((key, _)) = &quota {
+            let used = (request_bytes + body.len()) as u64;
+            state.quotas.record(key, used, quota_window, Instant::now());
+        }
+
+        let ctx = &RequestContext {
+            response_headers: ctx.audit_headers.then(|| headers.clone()),
+            content_length_mismatch: length_mismatch,
+            ..ctx.clone()
+        };
+        append_audit_entry(
+            audit,
+            &request,
+            ctx,
+            sanitize_url(&url),
+            status,
+            None,
+            request_bytes,
+            body.len(),
+            redirects,
+            Some(&decision),
+            Some(response_sha256),
+        );
+
+        let mut response = HttpResponse {
+            status,
+            status_text,
+            headers,
+            body_base64: None,
+            body: None,
+            error: None,
+            request_id: None,
+            rate_limit_limit: None,
+            rate_limit_remaining: None,
+            rate_limit_reset_secs: None,
+            retry_after_ms: None,
+            protocol_version: PROTOCOL_VERSION,
+            stream: None,
+            truncated,
+        };
+        if !head {
+            response.set_body(body, ctx.raw_body);
+        }
+        return Ok(response);
+    }
+}
+
+enum SendFailure {
+    Upstream(reqwest::Error),
+    /// No response head within `PEP_TTFB_TIMEOUT_SECS`.
+    Ttfb(Duration),
+    Other(String),
+}
+
+/// Send `builder`, giving up when no response head arrives within `ttfb`.
+/// A blocking send cannot be cancelled, so a late one finishes on its
+/// thread and its response is dropped, as in `resolve_with_timeout`. That
+/// thread holds `slots`, the request's worker, in-flight and host slots,
+/// until it does: an abandoned send still counts against every limit.
+fn send_within<S: Send + 'static>(
+    builder: RequestBuilder,
+    ttfb: Option<Duration>,
+    slots: S,
+) -> Result<Response, SendFailure> {
+    let Some(ttfb) = ttfb else {
+        return builder.send().map_err(SendFailure::Upstream);
+    };
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::Builder::new()
+        .name("pep-send".to_string())
+        .spawn(move || {
+            let _ = tx.send(builder.send());
+            drop(slots);
+        })
+        .map_err(|err| SendFailure::Other(format!("send failed: {err}")))?;
+    match rx.recv_timeout(ttfb) {
+        Ok(sent) => sent.map_err(SendFailure::Upstream),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(SendFailure::Ttfb(ttfb)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            Err(SendFailure::Other("send failed: sender exited".to_string()))
+        }
+    }
+}
+
+/// Decode a body sent with one `Content-Encoding` listed in `encodings`,
+/// dropping the header. `None` leaves the body and headers untouched: no
+/// encoding, one not listed, or several stacked.
+fn decompress_response(
+    headers: &mut Vec<(String, String)>,
+    body: &[u8],
+    encodings: &[ContentCoding],
+    cap: usize,
+) -> Result<Option<Vec<u8>>, (&'static str, String)> {
+    let mut declared = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-encoding"));
+    let (Some((_, value)), None) = (declared.next(), declared.next()) else {
+        return Ok(None);
+    };
+    let Some(coding) = ContentCoding::parse(value).filter(|coding| encodings.contains(coding))
+    else {
+        return Ok(None);
+    };
+    let decoded = decompress::decode_body(coding, body, cap)?;
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-encoding"));
+    Ok(Some(decoded))
+}
+
+/// Transcode a text body to UTF-8, rewriting `Content-Type` and dropping the
+/// now-stale `Content-Length` when the body changes.
+fn normalize_response_text(
+    headers: &mut Vec<(String, String)>,
+    body: Vec<u8>,
+    cap: usize,
+) -> Result<Vec<u8>, String> {
+    let Some(content_type) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.clone())
+    else {
+        return Ok(body);
+    };
+    match normalize_text_body(&content_type, &body, cap)? {
+        Some(normalized) => {
+            headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("content-type")
+                    && !name.eq_ignore_ascii_case("content-length")
+            });
+            headers.push(("content-type".to_string(), normalized.content_type));
+            Ok(normalized.body)
+        }
+        None => Ok(body),
+    }
+}
+
+/// Drop `Transfer-Encoding` and any stale `Content-Length`, then set
+/// `Content-Length` to the length of the delivered body.
+fn set_body_framing_headers(headers: &mut Vec<(String, String)>, body_len: usize) {
+    headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("transfer-encoding")
+            && !name.eq_ignore_ascii_case("content-length")
+    });
+    headers.push(("content-length".to_string(), body_len.to_string()));
+}
+
+/// Set each of `injected`, dropping client headers with the same name.
+fn inject_headers(headers: &mut Vec<(String, String)>, injected: &[(String, String)]) {
+    headers.retain(|(name, _)| {
+        !injected
+            .iter()
+            .any(|(injected, _)| injected.trim().eq_ignore_ascii_case(name.trim()))
+    });
+    headers.extend(injected.iter().cloned());
+}
+
+/// Order headers by lowercased name for `PEP_SORT_HEADERS`. The sort is
+/// stable, so repeated headers such as `Set-Cookie` keep their order.
+fn sort_headers(headers: &mut [(String, String)]) {
+    headers.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+}
+
+/// `vet_host`, except that a host pinned by `PEP_HOST_OVERRIDES` is checked
+/// by its pinned address, which is where the connection goes. The vetted
+/// address must also be inside `PEP_ALLOWED_CIDRS`, when set.
+fn vet_destination(config: &PepConfig, url: &Url) -> Result<IpAddr, SsrfError> {
+    let ip = match url
+        .host_str()
+        .and_then(|host| config.host_override_for(host))
+    {
+        Some(ip) if !config.allow_private_hosts && !is_public_ip(ip) => {
+            return Err(SsrfError::Blocked(format!("blocked ip {ip}")));
+        }
+        Some(ip) => ip,
+        None => vet_host(url, config.dns_timeout(), config.allow_private_hosts)?,
+    };
+    if !is_ip_allowed(ip, &config.allowed_cidrs) {
+        return Err(SsrfError::Blocked(format!(
+            "ip {ip} is not in PEP_ALLOWED_CIDRS"
+        )));
+    }
+    Ok(ip)
+}
+
+/// Evaluate the active policy, logging the decision, and the shadow policy if
+/// any. The shadow decision is never enforced; a divergence is only audited.
+fn evaluate_policy(
+    config: &PepConfig,
+    metrics: &Metrics,
+    evaluator: &dyn PolicyEvaluator,
+    shadow_evaluator: Option<&dyn PolicyEvaluator>,
+    input: &PolicyInput,
+) -> Result<(PolicyDecision, Option<ShadowDivergence>), PepError> {
+    let started = Instant::now();
+    let decision = evaluator.evaluate(input);
+    metrics.policy_eval.observe(started.elapsed());
+    let decision = decision?;
+    append_decision_log(config, input, &decision);
+    let shadow = shadow_evaluator.and_then(|shadow| match shadow.evaluate(input) {
+        Ok(shadow_decision) => shadow_divergence(&decision, &shadow_decision),
+        Err(err) => {
+            eprintln!("shadow policy evaluation failed: {err}");
+            None
+        }
+    });
+    Ok((decision, shadow))
+}
+
+/// Length `encoded` decodes to, assuming it is valid padded base64. Computed
+/// without decoding so size caps can be enforced up front.
+pub fn base64_decoded_len(encoded: &str) -> usize {
+    encoded.trim_end_matches('=').len() * 3 / 4
+}
+
+/// Request bodies must be canonical padded base64: no whitespace, no missing
+/// or extra padding, no stray trailing bits. Spelled out rather than relying on
+/// `STANDARD`'s defaults so the wire contract cannot loosen with a dependency
+/// bump.
+const STRICT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::RequireCanonical)
+        .with_decode_allow_trailing_bits(false),
+);
+
+/// Decode `body_base64` with the strict engine.
+pub fn decode_request_body(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    STRICT_BASE64.decode(encoded)
+}
+
+/// Gzip only for opted-in hosts, non-empty bodies, and requests the client
+/// has not already encoded.
+fn should_compress_request(
+    config: &PepConfig,
+    url: &Url,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> bool {
+    !body.is_empty()
+        && url
+            .host_str()
+            .is_some_and(|host| is_host_allowed(host, &config.compress_request_hosts))
+        && !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+}
+
+pub fn gzip_body(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+/// Resolve the byte quota for `host`: a policy `quota_bytes` constraint
+/// applies to the exact host; otherwise the first matching configured entry
+/// applies and is shared by its subdomains.
+fn quota_for(
+    host: &str,
+    configured: &[(String, u64)],
+    constraints: Option<&Constraints>,
+) -> Option<(String, u64)> {
+    let host = host.to_lowercase();
+    if let Some(limit) = constraints.and_then(|c| c.quota_bytes) {
+        return Some((host, limit));
+    }
+    configured
+        .iter()
+        .find(|(entry, _)| is_host_allowed(&host, std::slice::from_ref(entry)))
+        .map(|(entry, limit)| (entry.clone(), *limit))
+}
+
+/// Reject header names that are not valid tokens and values containing
+/// control characters (CR/LF included). Messages name the header, never the
+/// value, which may carry credentials.
+pub fn validate_headers(headers: &[(String, String)]) -> Result<(), String> {
+    for (name, value) in headers {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!(
+                "invalid header name: {:?}",
+                name.escape_debug().to_string()
+            ));
+        }
+        if HeaderValue::from_str(value).is_err() {
+            return Err(format!("invalid value for header {name}"));
+        }
+    }
+    Ok(())
+}
+
+/// Longest policy reason passed through to the client.
+const MAX_DENY_REASON_CHARS: usize = 200;
+
+/// Client-facing message for a policy deny: the policy's `reason` with
+/// control characters dropped and its length capped, or `fallback` when there
+/// is none or `PEP_DENY_REASON=generic` hides it.
+pub fn deny_message(reason: Option<&str>, mode: DenyReasonMode, fallback: &str) -> String {
+    let cleaned = match (mode, reason) {
+        (DenyReasonMode::Passthrough, Some(reason)) => reason
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_DENY_REASON_CHARS)
+            .collect::<String>(),
+        _ => String::new(),
+    };
+    match cleaned.trim() {
+        "" => fallback.to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Error response for a policy deny. With `PEP_DENY_BODY_TEMPLATE` it also
+/// carries the rendered template as a JSON body, under `PEP_DENY_STATUS`.
+fn policy_deny_response(
+    config: &PepConfig,
+    code: &str,
+    message: &str,
+    raw_body: bool,
+) -> HttpResponse {
+    let mut response = error_response(code, message);
+    if let Some(template) = &config.deny_body_template {
+        let body = render_deny_body(template, code, message);
+        response.status = config.deny_status;
+        response.headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("content-length".to_string(), body.len().to_string()),
+        ];
+        response.set_body(body.into_bytes(), raw_body);
+    }
+    response
+}
+
+/// `template` with `{code}` and `{message}` replaced by their values escaped
+/// for a JSON string, so placeholders inside quotes render valid JSON.
+pub fn render_deny_body(template: &str, code: &str, message: &str) -> String {
+    let escape = |value: &str| {
+        let quoted = serde_json::Value::from(value).to_string();
+        quoted
+            .strip_prefix('"')
+            .and_then(|inner| inner.strip_suffix('"'))
+            .unwrap_or_default()
+            .to_string()
+    };
+    // The message goes in last, so text in it is never taken as a placeholder.
+    template
+        .replace("{code}", &escape(code))
+        .replace("{message}", &escape(message))
+}
+
+/// With `PEP_DENY_HINTS`, a note naming the allowlist entry closest to a
+/// denied host, for a deny that is an allowlist miss: one the policy lifts
+/// when `input` names that entry instead. `PEP_DENY_REASON=generic` wins.
+fn allowlist_hint(
+    config: &PepConfig,
+    evaluator: &dyn PolicyEvaluator,
+    input: &PolicyInput,
+) -> Option<String> {
+    if !config.deny_hints || config.deny_reason == DenyReasonMode::Generic {
+        return None;
+    }
+    let host = input.action.resource.host.as_str();
+    if is_host_allowed(host, &config.allowed_domains) {
+        return None;
+    }
+    let entry = closest_allowlist_entry(host, &config.allowed_domains)?;
+    let mut corrected = input.clone();
+    corrected.action.resource.host = entry.to_lowercase();
+    if !evaluator
+        .evaluate(&corrected)
+        .is_ok_and(|decision| decision.allow)
+    {
+        return None;
+    }
+    Some(format!("host {host} not allowed; did you mean {entry}?"))
+}
+
+const METHOD_OVERRIDE_HEADERS: &[&str] = &[
+    "x-http-method-override",
+    "x-http-method",
+    "x-method-override",
+];
+
+pub fn is_method_override_header(name: &str) -> bool {
+    METHOD_OVERRIDE_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name.trim()))
+}
+
+pub fn strip_method_override_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| !is_method_override_header(name))
+        .cloned()
+        .collect()
+}
 
-            redirects += 1;
-            url = next_url;
-            continue;
+/// Case-insensitive match against `PEP_STRIPPED_REQUEST_HEADERS`.
+pub fn is_stripped_header(name: &str, stripped: &[String]) -> bool {
+    stripped
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name.trim()))
+}
+
+/// `PEP_FORCE_USER_AGENT` replaces any client `User-Agent`;
+/// `PEP_DEFAULT_USER_AGENT` fills one in when the client sent none.
+pub fn apply_user_agent(headers: &mut Vec<(String, String)>, config: &PepConfig) {
+    let is_agent = |name: &str| name.trim().eq_ignore_ascii_case("user-agent");
+    let agent = match (&config.force_user_agent, &config.default_user_agent) {
+        (Some(forced), _) => {
+            headers.retain(|(name, _)| !is_agent(name));
+            forced
         }
+        (None, Some(default)) if !headers.iter().any(|(name, _)| is_agent(name)) => default,
+        _ => return,
+    };
+    headers.push(("User-Agent".to_string(), agent.clone()));
+}
 
-        // ── Success path ────────────────────────────────────────────
-        let status = response.status().as_u16();
-        let headers = response
-            .headers()
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
-            .collect::<Vec<_>>();
+#[derive(Debug, PartialEq, Eq)]
+pub enum RedirectDisposition {
+    /// Not a redirect; handle as a normal response.
+    NotRedirect,
+    Follow,
+    /// Hand the 3xx back to the client unchanged.
+    Return,
+    Reject,
+}
 
-        let body = match read_body_with_cap(response, max_response) {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                let error = error_response("constraint_violation", &err);
-                append_audit_entry(
-                    config,
-                    &request,
-                    sanitize_url(&url),
-                    status,
-                    Some("constraint_violation"),
-                    request_bytes,
-                    0,
-                    redirects,
-                    Some(&decision),
-                );
-                return Ok(error);
+/// Only these carry a `Location` to follow. Other 3xx (300, 304, 305, 306)
+/// are ordinary responses; a `304 Not Modified` must reach the client.
+pub fn is_followable_redirect(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308)
+}
+
+pub fn redirect_disposition(mode: RedirectMode, status: StatusCode) -> RedirectDisposition {
+    if !is_followable_redirect(status) {
+        return RedirectDisposition::NotRedirect;
+    }
+    match mode {
+        RedirectMode::Follow => RedirectDisposition::Follow,
+        RedirectMode::Return => RedirectDisposition::Return,
+        RedirectMode::Error => RedirectDisposition::Reject,
+    }
+}
+
+/// Error code and message for a redirect that changes scheme, or `None` when
+/// the hop may be followed. An `https` → `http` downgrade gets its own code;
+/// an `http` → `https` upgrade passes with `PEP_ALLOW_REDIRECT_UPGRADE`.
+pub fn redirect_scheme_error(
+    from: &str,
+    to: &str,
+    allow_upgrade: bool,
+) -> Option<(&'static str, &'static str)> {
+    match (from, to) {
+        _ if from == to => None,
+        ("https", "http") => Some((
+            "redirect_downgrade_blocked",
+            "https to http redirect blocked",
+        )),
+        ("http", "https") if allow_upgrade => None,
+        _ => Some(("redirect_blocked", "scheme change blocked")),
+    }
+}
+
+/// Cap on how much of a followed redirect's body is read (and discarded).
+pub const MAX_REDIRECT_BODY_BYTES: u64 = 64 * 1024;
+
+/// Read and discard up to `cap` bytes of a redirect body so small bodies
+/// leave the connection reusable. Returns `false` if the body was larger;
+/// the caller then drops the response, closing the connection rather than
+/// streaming the rest.
+pub fn drain_redirect_body<R: Read>(reader: &mut R, cap: u64) -> bool {
+    let mut limited = reader.take(cap + 1);
+    match io::copy(&mut limited, &mut io::sink()) {
+        Ok(read) => read <= cap,
+        Err(_) => false,
+    }
+}
+
+/// Whether the upstream answered with Server-Sent Events.
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// How a streamed body ended.
+struct Streamed {
+    bytes: usize,
+    /// Hex SHA-256 of the bytes forwarded.
+    digest: String,
+    /// Code and message when the stream was cut short.
+    error: Option<(&'static str, String)>,
+}
+
+/// Forward `reader` to `sink` as chunk frames until it ends, `cap` bytes
+/// would be exceeded, or a read fails (including the client's per-read
+/// timeout, reported as `stream_idle_timeout`). Chunks carry their bytes raw
+/// when `raw` is set. Errs only when the sink does.
+fn stream_body<R: Read>(
+    reader: &mut R,
+    sink: &mut dyn StreamSink,
+    request_id: Option<&str>,
+    cap: usize,
+    raw: bool,
+) -> io::Result<Streamed> {
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    let mut chunk = [0u8; 8192];
+    let error = loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break None,
+            Ok(read) => read,
+            Err(err) if is_read_timeout(&err) => {
+                break Some((
+                    "stream_idle_timeout",
+                    format!("no data for too long: {err}"),
+                ));
             }
+            Err(err) => break Some(("http_error", format!("read error: {err}"))),
         };
+        if bytes + read > cap {
+            break Some((
+                "constraint_violation",
+                "response body exceeds max bytes".to_string(),
+            ));
+        }
+        bytes += read;
+        hasher.update(&chunk[..read]);
+        let data = &chunk[..read];
+        sink.send_chunk(&StreamChunk {
+            request_id: request_id.map(str::to_string),
+            chunk_base64: if raw {
+                String::new()
+            } else {
+                BASE64.encode(data)
+            },
+            chunk: raw.then(|| data.to_vec()),
+        })?;
+    };
+    Ok(Streamed {
+        bytes,
+        digest: format!("{:x}", hasher.finalize()),
+        error,
+    })
+}
 
-        append_audit_entry(
-            config,
-            &request,
-            sanitize_url(&url),
-            status,
-            None,
-            request_bytes,
-            body.len(),
-            redirects,
-            Some(&decision),
-        );
+/// A blocking body read that outlasted the client's timeout.
+fn is_read_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::TimedOut
+        || err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+            .is_some_and(reqwest::Error::is_timeout)
+}
 
-        return Ok(HttpResponse {
-            status,
-            headers,
-            body_base64: Some(BASE64.encode(body)),
-            error: None,
-        });
+/// An upstream body as read by `read_body_with_cap`.
+struct ReadBody {
+    body: Vec<u8>,
+    /// Hex SHA-256, hashed as the chunks arrived.
+    digest: String,
+    /// Cut at the cap (`PEP_TRUNCATE_OVERSIZED`).
+    truncated: bool,
+    /// How the body missed its `Content-Length`, when it did.
+    length_mismatch: Option<String>,
+}
+
+/// A failed `read_body_with_cap`.
+struct ReadFailure {
+    code: &'static str,
+    message: String,
+    /// How the body missed its `Content-Length`, for the audit entry.
+    length_mismatch: Option<String>,
+}
+
+impl ReadFailure {
+    fn mismatch(mismatch: String) -> Self {
+        Self {
+            code: "content_length_mismatch",
+            message: mismatch.clone(),
+            length_mismatch: Some(mismatch),
+        }
     }
 }
 
+/// Read the upstream body under `cap`. An oversized body is an error unless
+/// `truncate` is set. A body that misses its `Content-Length` is noted for
+/// the audit entry and, with `strict`, refused as `content_length_mismatch`.
 fn read_body_with_cap(
     mut response: reqwest::blocking::Response,
     cap: usize,
-) -> Result<Vec<u8>, String> {
-    read_with_cap(&mut response, cap)
+    truncate: bool,
+    strict: bool,
+) -> Result<ReadBody, ReadFailure> {
+    let declared = response.content_length();
+    let mut hasher = Sha256::new();
+    let mut received = 0u64;
+    let read = read_capped(&mut response, cap, declared, truncate, |chunk| {
+        received += chunk.len() as u64;
+        hasher.update(chunk)
+    });
+    let mismatch = content_length_mismatch(declared, received);
+    match read {
+        // A body cut at the cap says nothing about the upstream's honesty.
+        Ok((body, truncated)) => {
+            let length_mismatch = mismatch.filter(|_| !truncated);
+            if strict && let Some(mismatch) = length_mismatch {
+                return Err(ReadFailure::mismatch(mismatch));
+            }
+            Ok(ReadBody {
+                body,
+                digest: format!("{:x}", hasher.finalize()),
+                truncated,
+                length_mismatch,
+            })
+        }
+        // The client stops reading at `Content-Length`, so a short body
+        // surfaces as a read error. An over-cap declaration stays a cap error.
+        Err(err) => match mismatch.filter(|_| declared.is_some_and(|len| len <= cap as u64)) {
+            Some(mismatch) if strict => Err(ReadFailure::mismatch(mismatch)),
+            length_mismatch => Err(ReadFailure {
+                code: "constraint_violation",
+                message: err,
+                length_mismatch,
+            }),
+        },
+    }
+}
+
+/// Why a body of `received` bytes does not match the declared
+/// `Content-Length`, if it does not.
+pub fn content_length_mismatch(declared: Option<u64>, received: u64) -> Option<String> {
+    let declared = declared?;
+    (declared != received)
+        .then(|| format!("upstream declared Content-Length {declared} but sent {received} bytes"))
 }
 
 pub fn read_with_cap<R: Read>(reader: &mut R, cap: usize) -> Result<Vec<u8>, String> {
-    let mut buf = Vec::new();
+    read_with_cap_hint(reader, cap, None)
+}
+
+/// Like `read_with_cap`, but pre-reserves `size_hint` bytes (typically the
+/// `Content-Length`) when it fits under `cap`. Without a usable hint the
+/// buffer grows geometrically, never beyond `cap`. The hint only sizes the
+/// allocation; the cap is enforced on bytes actually read.
+pub fn read_with_cap_hint<R: Read>(
+    reader: &mut R,
+    cap: usize,
+    size_hint: Option<u64>,
+) -> Result<Vec<u8>, String> {
+    read_capped(reader, cap, size_hint, false, |_| {}).map(|(body, _)| body)
+}
+
+/// `read_with_cap_hint`, handing each chunk to `on_chunk` as it is kept. With
+/// `truncate`, a body over `cap` is cut there instead of failing, and the
+/// flag returned alongside it is set.
+fn read_capped<R: Read>(
+    reader: &mut R,
+    cap: usize,
+    size_hint: Option<u64>,
+    truncate: bool,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> Result<(Vec<u8>, bool), String> {
+    let initial = size_hint
+        .and_then(|len| usize::try_from(len).ok())
+        .filter(|len| *len <= cap)
+        .unwrap_or(0);
+    let mut buf = Vec::with_capacity(initial);
     let mut chunk = [0u8; 8192];
     loop {
         let read = reader
@@ -372,11 +2275,22 @@ pub fn read_with_cap<R: Read>(reader: &mut R, cap: usize) -> Result<Vec<u8>, Str
             break;
         }
         if buf.len() + read > cap {
-            return Err("response body exceeds max bytes".to_string());
+            if !truncate {
+                return Err("response body exceeds max bytes".to_string());
+            }
+            let kept = &chunk[..cap - buf.len()];
+            on_chunk(kept);
+            buf.extend_from_slice(kept);
+            return Ok((buf, true));
         }
+        if buf.capacity() - buf.len() < read {
+            let target = (buf.capacity() * 2).max(buf.len() + read).min(cap);
+            buf.reserve_exact(target - buf.len());
+        }
+        on_chunk(&chunk[..read]);
         buf.extend_from_slice(&chunk[..read]);
     }
-    Ok(buf)
+    Ok((buf, false))
 }
 
 pub fn sanitize_url(url: &Url) -> String {
@@ -386,6 +2300,18 @@ pub fn sanitize_url(url: &Url) -> String {
     sanitized.to_string()
 }
 
+/// Longest prefix of `raw` within `max` bytes that ends on a char boundary.
+fn truncate_utf8(raw: &str, max: usize) -> &str {
+    if raw.len() <= max {
+        return raw;
+    }
+    let mut end = max;
+    while !raw.is_char_boundary(end) {
+        end -= 1;
+    }
+    &raw[..end]
+}
+
 pub fn sanitize_url_string(raw: &str) -> String {
     let trimmed = raw.split('#').next().unwrap_or(raw);
     trimmed.split('?').next().unwrap_or(trimmed).to_string()
@@ -404,9 +2330,567 @@ mod tests {
         assert!(err.contains("exceeds max bytes"));
     }
 
+    #[test]
+    fn base64_decoded_len_matches_actual_decode() {
+        for len in 0..8 {
+            let encoded = BASE64.encode(vec![0u8; len]);
+            assert_eq!(base64_decoded_len(&encoded), len);
+        }
+    }
+
+    #[test]
+    fn decode_request_body_accepts_canonical_padding() {
+        assert_eq!(decode_request_body("QQ==").expect("padded"), b"A");
+        assert_eq!(
+            decode_request_body("QUJD").expect("unpadded multiple"),
+            b"ABC"
+        );
+        assert_eq!(decode_request_body("").expect("empty"), b"");
+    }
+
+    #[test]
+    fn decode_request_body_rejects_missing_or_noncanonical_padding() {
+        assert!(decode_request_body("QQ").is_err());
+        assert!(decode_request_body("QQ=").is_err());
+        assert!(decode_request_body("QUJD====").is_err());
+        // Trailing bits set past the last full byte.
+        assert!(decode_request_body("QR==").is_err());
+    }
+
+    #[test]
+    fn decode_request_body_rejects_whitespace() {
+        for encoded in ["QU JD", "QUJD\n", "QU\r\nJD", " QUJD", "\tQUJD"] {
+            assert!(decode_request_body(encoded).is_err(), "{encoded:?}");
+        }
+    }
+
+    #[test]
+    fn validate_headers_rejects_crlf_in_value() {
+        let headers = vec![("X-Note".to_string(), "ok\r\nX-Injected: yes".to_string())];
+        let err = validate_headers(&headers).expect_err("expected invalid header");
+        assert!(err.contains("X-Note"));
+        assert!(!err.contains("Injected"));
+    }
+
+    #[test]
+    fn render_deny_body_escapes_values_for_json() {
+        let template = r#"{"error":{"type":"{code}","message":"{message}"}}"#;
+        let body = render_deny_body(template, "DENIED_BY_POLICY", r#"no "quotes" {code}"#);
+        let value: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(value["error"]["type"], "DENIED_BY_POLICY");
+        assert_eq!(value["error"]["message"], r#"no "quotes" {code}"#);
+    }
+
+    #[test]
+    fn deny_message_passes_sanitized_reason_through() {
+        let reason = "method DELETE not permitted\n for api.example.com";
+        assert_eq!(
+            deny_message(
+                Some(reason),
+                DenyReasonMode::Passthrough,
+                "denied by policy"
+            ),
+            "method DELETE not permitted for api.example.com"
+        );
+        assert_eq!(
+            deny_message(Some(reason), DenyReasonMode::Generic, "denied by policy"),
+            "denied by policy"
+        );
+        assert_eq!(
+            deny_message(Some(" \t"), DenyReasonMode::Passthrough, "denied by policy"),
+            "denied by policy"
+        );
+        let long = "x".repeat(MAX_DENY_REASON_CHARS * 2);
+        assert_eq!(
+            deny_message(Some(&long), DenyReasonMode::Passthrough, "").len(),
+            MAX_DENY_REASON_CHARS
+        );
+    }
+
+    #[test]
+    fn validate_headers_rejects_empty_name() {
+        let headers = vec![(String::new(), "value".to_string())];
+        assert!(validate_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn validate_headers_accepts_ordinary_headers() {
+        let headers = vec![
+            ("Accept".to_string(), "application/json".to_string()),
+            ("Authorization".to_string(), "Bearer abc\tdef".to_string()),
+        ];
+        assert!(validate_headers(&headers).is_ok());
+    }
+
+    #[test]
+    fn method_override_headers_are_detected_case_insensitively() {
+        assert!(is_method_override_header("X-HTTP-Method-Override"));
+        assert!(is_method_override_header("x-method-override"));
+        assert!(is_method_override_header("X-Http-Method"));
+        assert!(!is_method_override_header("X-Request-Id"));
+    }
+
+    #[test]
+    fn strip_method_override_headers_removes_override() {
+        let headers = vec![
+            ("Accept".to_string(), "*/*".to_string()),
+            ("X-HTTP-Method-Override".to_string(), "DELETE".to_string()),
+        ];
+        let forwarded = strip_method_override_headers(&headers);
+        assert_eq!(forwarded, vec![("Accept".to_string(), "*/*".to_string())]);
+    }
+
+    #[test]
+    fn stripped_headers_cover_defaults_and_custom_entries() {
+        let mut config = PepConfig::for_tests(std::path::PathBuf::from("audit.jsonl"));
+        config
+            .stripped_request_headers
+            .push("x-internal-auth".to_string());
+        let stripped = &config.stripped_request_headers;
+        assert!(is_stripped_header("X-Forwarded-For", stripped));
+        assert!(is_stripped_header("forwarded", stripped));
+        assert!(is_stripped_header("VIA", stripped));
+        assert!(is_stripped_header("X-Internal-Auth", stripped));
+        assert!(!is_stripped_header("Authorization", stripped));
+    }
+
+    #[test]
+    fn default_user_agent_fills_in_only_when_absent() {
+        let config = PepConfig::builder().default_user_agent("pexi/1").build();
+        let mut headers = Vec::new();
+        apply_user_agent(&mut headers, &config);
+        assert_eq!(headers, [("User-Agent".to_string(), "pexi/1".to_string())]);
+
+        let mut headers = vec![("user-agent".to_string(), "curl/8".to_string())];
+        apply_user_agent(&mut headers, &config);
+        assert_eq!(headers, [("user-agent".to_string(), "curl/8".to_string())]);
+    }
+
+    #[test]
+    fn forced_user_agent_replaces_client_values() {
+        let config = PepConfig::builder()
+            .default_user_agent("pexi/1")
+            .force_user_agent("pexi-egress/2")
+            .build();
+        let mut headers = vec![
+            ("User-Agent".to_string(), "curl/8".to_string()),
+            ("Accept".to_string(), "*/*".to_string()),
+            ("user-agent".to_string(), "again".to_string()),
+        ];
+        apply_user_agent(&mut headers, &config);
+        assert_eq!(
+            headers,
+            [
+                ("Accept".to_string(), "*/*".to_string()),
+                ("User-Agent".to_string(), "pexi-egress/2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn quota_for_matches_configured_entry_and_subdomains() {
+        let configured = vec![("example.com".to_string(), 100)];
+        assert_eq!(
+            quota_for("API.example.com", &configured, None),
+            Some(("example.com".to_string(), 100))
+        );
+        assert_eq!(quota_for("other.com", &configured, None), None);
+    }
+
+    #[test]
+    fn quota_for_prefers_policy_constraint() {
+        let configured = vec![("example.com".to_string(), 100)];
+        let constraints = Constraints {
+            max_bytes: None,
+            allowed_domains: None,
+            rate_limit_per_min: None,
+            quota_bytes: Some(5),
+            inject_headers: None,
+        };
+        assert_eq!(
+            quota_for("api.example.com", &configured, Some(&constraints)),
+            Some(("api.example.com".to_string(), 5))
+        );
+    }
+
+    #[test]
+    fn read_with_cap_accepts_body_exactly_at_cap() {
+        let payload = vec![7u8; 20_000];
+        let mut cursor = Cursor::new(payload.clone());
+        let body = read_with_cap(&mut cursor, 20_000).expect("at cap");
+        assert_eq!(body, payload);
+    }
+
+    #[test]
+    fn truncating_read_keeps_exactly_cap_bytes() {
+        let payload: Vec<u8> = (0..20_000u32).map(|n| n as u8).collect();
+        let mut hashed = Vec::new();
+        let (body, truncated) = read_capped(
+            &mut Cursor::new(payload.clone()),
+            10_000,
+            None,
+            true,
+            |chunk| hashed.extend_from_slice(chunk),
+        )
+        .expect("truncated");
+        assert!(truncated);
+        assert_eq!(body, payload[..10_000]);
+        assert_eq!(hashed, body);
+
+        let (body, truncated) = read_capped(
+            &mut Cursor::new(payload.clone()),
+            20_000,
+            None,
+            true,
+            |_| {},
+        )
+        .expect("fits");
+        assert!(!truncated);
+        assert_eq!(body, payload);
+    }
+
+    #[test]
+    fn read_with_cap_hint_presizes_and_never_exceeds_cap() {
+        let payload = vec![1u8; 20_000];
+        let mut cursor = Cursor::new(payload.clone());
+        let body = read_with_cap_hint(&mut cursor, 20_000, Some(20_000)).expect("read");
+        assert_eq!(body, payload);
+        assert_eq!(body.capacity(), 20_000);
+
+        let mut cursor = Cursor::new(payload.clone());
+        let body = read_with_cap_hint(&mut cursor, 25_000, None).expect("read");
+        assert!(body.capacity() <= 25_000);
+    }
+
+    #[test]
+    fn read_with_cap_hint_ignores_lying_content_length() {
+        let payload = vec![1u8; 10];
+        let mut cursor = Cursor::new(payload.clone());
+        let err = read_with_cap_hint(&mut cursor, 5, Some(1)).expect_err("cap");
+        assert!(err.contains("exceeds max bytes"));
+
+        let mut cursor = Cursor::new(payload);
+        let body = read_with_cap_hint(&mut cursor, 100, Some(u64::MAX)).expect("read");
+        assert_eq!(body.len(), 10);
+    }
+
+    #[test]
+    fn content_length_mismatch_flags_short_and_long_bodies() {
+        assert_eq!(content_length_mismatch(Some(10), 10), None);
+        assert_eq!(content_length_mismatch(None, 10), None);
+        let short = content_length_mismatch(Some(10), 4).expect("short");
+        assert!(short.contains("Content-Length 10 but sent 4"), "{short}");
+        let long = content_length_mismatch(Some(4), 10).expect("long");
+        assert!(long.contains("Content-Length 4 but sent 10"), "{long}");
+    }
+
+    #[test]
+    fn decompress_response_decodes_listed_encodings_and_passes_others_through() {
+        let gzipped = gzip_body(b"decoded").expect("gzip");
+        let encoded = |coding: &str| {
+            vec![
+                ("content-encoding".to_string(), coding.to_string()),
+                ("content-type".to_string(), "text/plain".to_string()),
+            ]
+        };
+
+        let mut headers = encoded("gzip");
+        let decoded = decompress_response(&mut headers, &gzipped, &[ContentCoding::Gzip], 1024);
+        assert_eq!(decoded, Ok(Some(b"decoded".to_vec())));
+        assert_eq!(
+            headers,
+            [("content-type".to_string(), "text/plain".to_string())]
+        );
+
+        // Not listed, unknown, or stacked: body and headers stay as sent.
+        for (coding, listed) in [
+            ("gzip", &[ContentCoding::Br][..]),
+            ("zstd", &[ContentCoding::Gzip][..]),
+            ("gzip, br", &[ContentCoding::Gzip, ContentCoding::Br][..]),
+        ] {
+            let mut headers = encoded(coding);
+            assert_eq!(
+                decompress_response(&mut headers, &gzipped, listed, 1024),
+                Ok(None)
+            );
+            assert_eq!(headers, encoded(coding));
+        }
+    }
+
+    #[test]
+    fn normalize_response_text_rewrites_headers() {
+        let mut headers = vec![
+            (
+                "content-type".to_string(),
+                "text/plain; charset=latin1".to_string(),
+            ),
+            ("content-length".to_string(), "4".to_string()),
+            ("etag".to_string(), "\"v1\"".to_string()),
+        ];
+        let body = normalize_response_text(&mut headers, b"caf\xe9".to_vec(), 1024).expect("ok");
+        assert_eq!(body, "café".as_bytes());
+        assert_eq!(
+            headers,
+            vec![
+                ("etag".to_string(), "\"v1\"".to_string()),
+                (
+                    "content-type".to_string(),
+                    "text/plain; charset=utf-8".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn framing_headers_describe_delivered_body() {
+        let mut headers = vec![
+            ("Transfer-Encoding".to_string(), "chunked".to_string()),
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("Content-Length".to_string(), "999".to_string()),
+        ];
+        set_body_framing_headers(&mut headers, 11);
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("content-length".to_string(), "11".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn gzip_body_round_trips() {
+        let body = br#"{"messages":[{"role":"user","content":"hello hello hello"}]}"#;
+        let compressed = gzip_body(body).expect("gzip");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .expect("gunzip");
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn compression_is_opt_in_per_host() {
+        let mut config = PepConfig::for_tests("audit.jsonl".into());
+        config.compress_request_hosts = vec!["api.example.com".to_string()];
+        let opted_in = Url::parse("https://api.example.com/v1").unwrap();
+        let other = Url::parse("https://example.com/").unwrap();
+        assert!(should_compress_request(&config, &opted_in, &[], b"x"));
+        assert!(!should_compress_request(&config, &other, &[], b"x"));
+        assert!(!should_compress_request(&config, &opted_in, &[], b""));
+        let encoded = vec![("Content-Encoding".to_string(), "br".to_string())];
+        assert!(!should_compress_request(&config, &opted_in, &encoded, b"x"));
+    }
+
+    #[test]
+    fn redirect_scheme_downgrade_is_blocked_with_its_own_code() {
+        for allow_upgrade in [false, true] {
+            assert_eq!(
+                redirect_scheme_error("https", "http", allow_upgrade).map(|(code, _)| code),
+                Some("redirect_downgrade_blocked")
+            );
+        }
+        assert_eq!(redirect_scheme_error("https", "https", false), None);
+    }
+
+    #[test]
+    fn redirect_scheme_upgrade_is_followed_only_when_allowed() {
+        assert_eq!(
+            redirect_scheme_error("http", "https", false).map(|(code, _)| code),
+            Some("redirect_blocked")
+        );
+        assert_eq!(redirect_scheme_error("http", "https", true), None);
+    }
+
+    #[test]
+    fn redirect_disposition_follows_in_follow_mode() {
+        assert_eq!(
+            redirect_disposition(RedirectMode::Follow, StatusCode::FOUND),
+            RedirectDisposition::Follow
+        );
+    }
+
+    #[test]
+    fn redirect_disposition_returns_in_return_mode() {
+        assert_eq!(
+            redirect_disposition(RedirectMode::Return, StatusCode::MOVED_PERMANENTLY),
+            RedirectDisposition::Return
+        );
+    }
+
+    #[test]
+    fn redirect_disposition_rejects_in_error_mode() {
+        assert_eq!(
+            redirect_disposition(RedirectMode::Error, StatusCode::TEMPORARY_REDIRECT),
+            RedirectDisposition::Reject
+        );
+    }
+
+    #[test]
+    fn redirect_disposition_ignores_non_redirects_in_every_mode() {
+        for mode in [
+            RedirectMode::Follow,
+            RedirectMode::Return,
+            RedirectMode::Error,
+        ] {
+            assert_eq!(
+                redirect_disposition(mode, StatusCode::OK),
+                RedirectDisposition::NotRedirect
+            );
+        }
+    }
+
+    #[test]
+    fn not_modified_and_other_3xx_pass_through_in_every_mode() {
+        for mode in [
+            RedirectMode::Follow,
+            RedirectMode::Return,
+            RedirectMode::Error,
+        ] {
+            for status in [300, 304, 305, 306] {
+                let status = StatusCode::from_u16(status).expect("status");
+                assert_eq!(
+                    redirect_disposition(mode, status),
+                    RedirectDisposition::NotRedirect
+                );
+            }
+        }
+        assert!(is_followable_redirect(StatusCode::SEE_OTHER));
+        assert!(is_followable_redirect(StatusCode::PERMANENT_REDIRECT));
+    }
+
+    #[test]
+    fn drain_redirect_body_stops_at_cap_for_huge_bodies() {
+        // An endless body must not be streamed; only cap + 1 bytes are read.
+        let mut endless = io::repeat(b'x');
+        assert!(!drain_redirect_body(&mut endless, 1024));
+
+        let mut counted = Cursor::new(vec![0u8; 10 * 1024 * 1024]);
+        assert!(!drain_redirect_body(&mut counted, 1024));
+        assert_eq!(counted.position(), 1025);
+    }
+
+    #[test]
+    fn drain_redirect_body_accepts_small_bodies() {
+        let mut small = Cursor::new(b"<a href=\"/next\">moved</a>".to_vec());
+        assert!(drain_redirect_body(&mut small, MAX_REDIRECT_BODY_BYTES));
+    }
+
+    #[test]
+    fn truncate_utf8_stops_on_char_boundary() {
+        assert_eq!(truncate_utf8("short", 10), "short");
+        assert_eq!(truncate_utf8("abcdef", 3), "abc");
+        // 'é' is two bytes; cutting inside it backs off to before it.
+        assert_eq!(truncate_utf8("aé", 2), "a");
+    }
+
     #[test]
     fn sanitize_url_string_removes_query_and_fragment() {
         let raw = "https://example.com/path?token=secret#frag";
         assert_eq!(sanitize_url_string(raw), "https://example.com/path");
     }
+
+    /// Minimal SOCKS5 proxy: accepts one CONNECT by domain name, answers the
+    /// tunneled HTTP request itself, and returns the requested host.
+    fn socks5_stub() -> (String, std::thread::JoinHandle<String>) {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let proxy = format!("socks5h://{}", listener.local_addr().expect("addr"));
+        let handle = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().expect("accept");
+            let mut greeting = [0u8; 2];
+            conn.read_exact(&mut greeting).expect("greeting");
+            let mut methods = vec![0u8; greeting[1] as usize];
+            conn.read_exact(&mut methods).expect("methods");
+            conn.write_all(&[5, 0]).expect("method reply");
+
+            // VER CMD RSV ATYP, then a length-prefixed domain and a port.
+            let mut head = [0u8; 5];
+            conn.read_exact(&mut head).expect("connect");
+            assert_eq!(head[3], 3, "expected a domain-name CONNECT");
+            let mut host = vec![0u8; head[4] as usize + 2];
+            conn.read_exact(&mut host).expect("host");
+            host.truncate(head[4] as usize);
+            conn.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .expect("connect reply");
+
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                conn.read_exact(&mut byte).expect("request");
+                request.push(byte[0]);
+            }
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .expect("response");
+            String::from_utf8(host).expect("host utf8")
+        });
+        (proxy, handle)
+    }
+
+    #[test]
+    fn socks_proxy_resolves_destination_on_the_proxy() {
+        let (proxy, handle) = socks5_stub();
+        let config = PepConfig::builder().socks_proxy(proxy).build();
+        let client = build_client(
+            &config,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            &Arc::default(),
+        )
+        .expect("client");
+        // `.invalid` never resolves locally; only the proxy sees the name.
+        let response = client
+            .get("http://pexi.invalid/")
+            .send()
+            .expect("via proxy");
+        assert_eq!(response.text().expect("body"), "ok");
+        assert_eq!(handle.join().expect("proxy"), "pexi.invalid");
+    }
+
+    #[test]
+    fn client_builder_sets_the_tls_version_floor() {
+        let config = PepConfig::builder().build();
+        let builder = with_tls_versions(Client::builder(), &config).expect("builder");
+        let debug = format!("{builder:?}");
+        assert!(
+            debug.contains("tls_version_min: Version(Tls1_2)"),
+            "{debug}"
+        );
+        assert!(!debug.contains("tls_version_max"), "{debug}");
+
+        let config = PepConfig::builder()
+            .min_tls_version(TlsVersion::Tls13)
+            .max_tls_version(TlsVersion::Tls12)
+            .build();
+        assert!(matches!(
+            with_tls_versions(Client::builder(), &config),
+            Err(PepError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn tls_version_errors_are_found_in_the_source_chain() {
+        let alert = io::Error::other("received fatal alert: ProtocolVersion");
+        let wrapped = io::Error::other(alert);
+        assert!(is_tls_version_error(&wrapped));
+        assert!(!is_tls_version_error(&io::Error::other(
+            "connection refused"
+        )));
+    }
+
+    #[test]
+    fn socks_proxy_must_be_a_socks_url() {
+        let config = PepConfig::builder()
+            .socks_proxy("http://127.0.0.1:1080")
+            .build();
+        let err = build_client(
+            &config,
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            &Arc::default(),
+        )
+        .expect_err("expected config error");
+        assert!(matches!(err, PepError::Config(_)));
+    }
 }
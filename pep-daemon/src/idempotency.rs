@@ -0,0 +1,195 @@
+//! Responses kept for client retries that carry an `idempotency_key`.
+//!
+//! A successful response is stored for `PEP_IDEMPOTENCY_TTL_SECS` under the
+//! peer CID, key, method, and a hash of the URL. A retry from the same VM
+//! matching all three gets the stored response, after its policy and SSRF
+//! checks, instead of reaching the upstream a second time. While the first
+//! request is still in flight the key is reserved, and a retry is refused
+//! as `idempotency_in_progress` rather than sent again.
+
+use crate::types::{HttpRequest, HttpResponse};
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most responses held at once; the oldest is dropped to make room.
+pub const MAX_IDEMPOTENT_RESPONSES: usize = 1024;
+
+#[derive(Debug)]
+enum Slot {
+    /// Reserved by a request still in flight.
+    Pending,
+    Done(Instant, Box<HttpResponse>),
+}
+
+#[derive(Debug, Default)]
+pub struct IdempotencyCache {
+    entries: Mutex<HashMap<String, Slot>>,
+}
+
+/// What `IdempotencyCache::begin` found for a key.
+#[derive(Debug)]
+pub enum Lookup<'a> {
+    /// The stored answer to an earlier request.
+    Replay(HttpResponse),
+    /// An earlier request with the key has not finished.
+    InProgress,
+    /// The key is now reserved for this request.
+    Reserved(Reservation<'a>),
+}
+
+/// A key reserved for an in-flight request. `complete` stores its response;
+/// dropping it unused frees the key for the next retry.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+}
+
+impl IdempotencyCache {
+    /// Cache key for `request` from `peer_cid`, or `None` when it has no
+    /// idempotency key.
+    pub fn key(request: &HttpRequest, peer_cid: Option<u32>) -> Option<String> {
+        let idempotency_key = request.idempotency_key.as_deref()?;
+        let url_hash = Sha256::digest(request.url.as_bytes());
+        let mut hasher = Sha256::new();
+        hasher.update(peer_cid.map(|cid| cid.to_string()).unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(idempotency_key.as_bytes());
+        hasher.update([0]);
+        hasher.update(request.method.to_uppercase().as_bytes());
+        hasher.update([0]);
+        hasher.update(url_hash);
+        Some(format!("{:x}", hasher.finalize()))
+    }
+
+    /// The response stored for `key` within the last `ttl`, or else a
+    /// reservation of `key` unless another request holds one.
+    pub fn begin(&self, key: String, ttl: Duration, now: Instant) -> Option<Lookup<'_>> {
+        let mut entries = self.entries.lock().ok()?;
+        match entries.get(&key) {
+            Some(Slot::Pending) => return Some(Lookup::InProgress),
+            Some(Slot::Done(stored_at, response)) if now.duration_since(*stored_at) < ttl => {
+                return Some(Lookup::Replay(HttpResponse::clone(response)));
+            }
+            _ => {}
+        }
+        entries.insert(key.clone(), Slot::Pending);
+        Some(Lookup::Reserved(Reservation { cache: self, key }))
+    }
+
+    fn store(&self, key: String, response: &HttpResponse, ttl: Duration, now: Instant) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.retain(|_, slot| match slot {
+            Slot::Pending => true,
+            Slot::Done(stored_at, _) => now.duration_since(*stored_at) < ttl,
+        });
+        if entries.len() >= MAX_IDEMPOTENT_RESPONSES
+            && let Some(oldest) = entries
+                .iter()
+                .filter_map(|(key, slot)| match slot {
+                    Slot::Pending => None,
+                    Slot::Done(stored_at, _) => Some((key, stored_at)),
+                })
+                .min_by_key(|(_, stored_at)| **stored_at)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&oldest);
+        }
+        entries.insert(key, Slot::Done(now, Box::new(response.clone())));
+    }
+}
+
+impl Reservation<'_> {
+    /// Store `response` for retries of the reserved key.
+    pub fn complete(self, response: &HttpResponse, ttl: Duration, now: Instant) {
+        self.cache.store(self.key.clone(), response, ttl, now);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut entries) = self.cache.entries.lock()
+            && matches!(entries.get(&self.key), Some(Slot::Pending))
+        {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::error_response;
+
+    fn request(key: Option<&str>, method: &str, url: &str) -> HttpRequest {
+        HttpRequest {
+            idempotency_key: key.map(str::to_string),
+            ..HttpRequest::new(method, url)
+        }
+    }
+
+    #[test]
+    fn key_covers_peer_idempotency_key_method_and_url() {
+        let url = "https://api.example.com/charges";
+        let key = |idempotency_key, method, url, cid| {
+            IdempotencyCache::key(&request(idempotency_key, method, url), cid)
+        };
+        let first = key(Some("k1"), "post", url, Some(3));
+        assert!(first.is_some());
+        assert_eq!(first, key(Some("k1"), "POST", url, Some(3)));
+        assert_ne!(first, key(Some("k2"), "POST", url, Some(3)));
+        assert_ne!(first, key(Some("k1"), "PUT", url, Some(3)));
+        assert_ne!(
+            first,
+            key(
+                Some("k1"),
+                "POST",
+                "https://api.example.com/refunds",
+                Some(3)
+            )
+        );
+        assert_ne!(first, key(Some("k1"), "POST", url, Some(4)));
+        assert_ne!(first, key(Some("k1"), "POST", url, None));
+        assert_eq!(key(None, "POST", url, Some(3)), None);
+    }
+
+    #[test]
+    fn a_reserved_key_is_in_progress_until_completed_or_dropped() {
+        let cache = IdempotencyCache::default();
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        let begin = || cache.begin("k".to_string(), ttl, now).expect("lock");
+
+        let Lookup::Reserved(reservation) = begin() else {
+            panic!("expected a reservation");
+        };
+        assert!(matches!(begin(), Lookup::InProgress));
+        drop(reservation);
+
+        let Lookup::Reserved(reservation) = begin() else {
+            panic!("a dropped reservation frees the key");
+        };
+        reservation.complete(&error_response("none", ""), ttl, now);
+        assert!(matches!(begin(), Lookup::Replay(_)));
+    }
+
+    #[test]
+    fn stored_responses_expire_after_ttl() {
+        let cache = IdempotencyCache::default();
+        let ttl = Duration::from_secs(60);
+        let now = Instant::now();
+        cache.store("k".to_string(), &error_response("none", ""), ttl, now);
+        let lookup = |key: &str, at| cache.begin(key.to_string(), ttl, at).expect("lock");
+        assert!(matches!(
+            lookup("k", now + Duration::from_secs(59)),
+            Lookup::Replay(_)
+        ));
+        assert!(matches!(lookup("k", now + ttl), Lookup::Reserved(_)));
+        assert!(matches!(lookup("other", now), Lookup::Reserved(_)));
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Request and response bytes reserved by requests being executed, for
+/// `PEP_MAX_TOTAL_INFLIGHT_BYTES`. Cloning shares the total, so Peps serving
+/// on several threads draw on one budget (see `Pep::with_inflight_bytes`).
+#[derive(Clone, Debug, Default)]
+pub struct InflightBytes {
+    reserved: Arc<AtomicUsize>,
+}
+
+impl InflightBytes {
+    /// Hold `bytes` of the budget until the guard is dropped, or `None` when
+    /// that would take the total past `ceiling` (0 means no ceiling).
+    pub fn try_reserve(&self, bytes: usize, ceiling: usize) -> Option<InflightGuard> {
+        self.reserved
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                let next = current.checked_add(bytes)?;
+                (ceiling == 0 || next <= ceiling).then_some(next)
+            })
+            .ok()?;
+        Some(InflightGuard {
+            reserved: Arc::clone(&self.reserved),
+            bytes,
+        })
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns its bytes to the budget on drop, so every exit from a request
+/// releases them.
+#[derive(Debug)]
+pub struct InflightGuard {
+    reserved: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_stop_at_ceiling_and_release_on_drop() {
+        let budget = InflightBytes::default();
+        let first = budget.try_reserve(600, 1000).expect("first");
+        assert!(budget.clone().try_reserve(500, 1000).is_none());
+        let second = budget.try_reserve(400, 1000).expect("fits exactly");
+        assert_eq!(budget.reserved(), 1000);
+
+        drop(first);
+        assert_eq!(budget.reserved(), 400);
+        assert!(budget.try_reserve(500, 1000).is_some());
+        drop(second);
+        assert_eq!(budget.reserved(), 0);
+        assert!(budget.try_reserve(usize::MAX, 0).is_some());
+    }
+}
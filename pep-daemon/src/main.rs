@@ -1,30 +1,52 @@
-mod audit;
-mod config;
-mod framing;
-mod health;
-mod http_exec;
-mod policy;
-mod ssrf;
-mod types;
-
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use clap::{Parser, Subcommand};
-use std::fs;
-use std::io::{self, Read, Write};
-use std::net::TcpListener;
-use std::path::PathBuf;
-use std::process::Command;
-use std::time::Duration;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+#[cfg(target_os = "macos")]
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
 #[cfg(not(target_os = "macos"))]
 use vsock::VsockListener;
 use vsock::{VMADDR_CID_ANY, VMADDR_CID_HOST, VsockStream};
 
-use config::PepConfig;
-use framing::{read_frame, write_frame};
-use health::health_check;
-use http_exec::execute_request;
-use policy::{NullEvaluator, PolicyEvaluator, RegorusEvaluator};
-use types::{HttpRequest, HttpResponse, PepError};
+use avf_vsock_host::audit::{
+    append_connection_refusal, append_invalid_request, verify_audit_writable,
+};
+use avf_vsock_host::config::AuditSinkKind;
+use avf_vsock_host::connections::ConnectionCounter;
+#[cfg(not(target_os = "macos"))]
+use avf_vsock_host::connections::PeerGuard;
+use avf_vsock_host::framing::{
+    FrameCodec, decode_request, encode_payload, frame_too_large, is_timeout, read_frame,
+    read_frame_with_limit, write_frame, write_frame_too_large,
+};
+use avf_vsock_host::health::health_check;
+#[cfg(target_os = "macos")]
+use avf_vsock_host::health::{
+    MAX_HTTP_HEAD_BYTES, http_health_response, looks_like_http, read_http_head,
+};
+use avf_vsock_host::http_exec::{build_client, build_connect_timeout_clients};
+use avf_vsock_host::probe::probe;
+use avf_vsock_host::remote_allowlist::extend_allowlist;
+#[cfg(not(target_os = "macos"))]
+use avf_vsock_host::sockopt::set_buffer_sizes;
+#[cfg(target_os = "macos")]
+use avf_vsock_host::sockopt::tune_tcp_stream;
+use avf_vsock_host::types::{HEALTH_METHOD, Heartbeat, error_response};
+use avf_vsock_host::{
+    AddrHealth, FrameSink, HttpRequest, HttpResponse, NullEvaluator, Pep, PepConfig, PepError,
+    PolicyEvaluator, PolicyInput, RegorusEvaluator, RequestContext,
+};
+use reqwest::Url;
+#[cfg(not(target_os = "macos"))]
+use socket2::SockRef;
 
 #[derive(Debug, Parser)]
 #[command(name = "pep-daemon")]
@@ -64,8 +86,43 @@ enum Commands {
         #[arg(long, default_value_t = false)]
         body_stdin: bool,
     },
-    /// Check PEP daemon health.
-    Health,
+    /// Check PEP daemon health. With `--cid`, ask the daemon listening there
+    /// over vsock and exit non-zero if it is unreachable or unhealthy;
+    /// otherwise report on the local configuration.
+    Health {
+        #[arg(long)]
+        cid: Option<u32>,
+        #[arg(long, default_value_t = 4040)]
+        port: u32,
+    },
+    /// Print the configuration resolved from PEP_* variables as JSON, with
+    /// credentials redacted.
+    Config,
+    /// Load a policy directory or bundle and smoke-test it with synthetic
+    /// inputs.
+    PolicyCheck {
+        #[command(flatten)]
+        policy: PolicyArgs,
+        /// A URL the policy is expected to allow (optional).
+        #[arg(long)]
+        allow_url: Option<String>,
+    },
+    /// Evaluate an input JSON file against a policy directory or bundle.
+    PolicyEval {
+        #[command(flatten)]
+        policy: PolicyArgs,
+        /// `PolicyInput` JSON (or any JSON object) to use as `input`.
+        #[arg(long)]
+        input_file: PathBuf,
+    },
+    /// Report whether a request would be allowed, without sending it.
+    /// Uses the same PEP_* environment as the stub.
+    Probe {
+        #[arg(long)]
+        url: String,
+        #[arg(long, default_value = "GET")]
+        method: String,
+    },
     /// Boot a VM by running a Swift AVF helper.
     BootVm {
         #[arg(long)]
@@ -98,9 +155,31 @@ enum Commands {
         efi_vars: Option<PathBuf>,
         #[arg(long)]
         shared_dir: Option<PathBuf>,
+        /// Kill the runner if it is still running after this many seconds.
+        #[arg(long)]
+        boot_timeout_secs: Option<u64>,
     },
 }
 
+/// The policy `policy-check` and `policy-eval` load, as `PEP_POLICY_DIR`,
+/// `PEP_POLICY_BUNDLE` and `PEP_POLICY_QUERY` name it for the stub.
+#[derive(Debug, clap::Args)]
+struct PolicyArgs {
+    #[arg(
+        long,
+        required_unless_present = "policy_bundle",
+        conflicts_with = "policy_bundle"
+    )]
+    policy_dir: Option<PathBuf>,
+    /// OPA-style `.tar.gz` bundle, instead of `--policy-dir`.
+    #[arg(long)]
+    policy_bundle: Option<PathBuf>,
+    /// Rule to evaluate; defaults to `PEP_POLICY_QUERY`, then the stub's
+    /// default.
+    #[arg(long)]
+    policy_query: Option<String>,
+}
+
 fn main() -> Result<(), PepError> {
     let cli = Cli::parse();
 
@@ -120,7 +199,11 @@ fn main() -> Result<(), PepError> {
             body_file,
             body_stdin,
         } => run_client(cid, port, method, url, header, body_file, body_stdin),
-        Commands::Health => run_health(),
+        Commands::Health { cid, port } => run_health(cid, port),
+        Commands::Config => run_config(),
+        Commands::PolicyCheck { policy, allow_url } => run_policy_check(policy, allow_url),
+        Commands::PolicyEval { policy, input_file } => run_policy_eval(policy, input_file),
+        Commands::Probe { url, method } => run_probe(&url, &method),
         Commands::BootVm {
             swift_script,
             kernel,
@@ -137,6 +220,7 @@ fn main() -> Result<(), PepError> {
             efi,
             efi_vars,
             shared_dir,
+            boot_timeout_secs,
         } => run_boot_vm(
             swift_script,
             kernel,
@@ -153,6 +237,7 @@ fn main() -> Result<(), PepError> {
             efi,
             efi_vars,
             shared_dir,
+            boot_timeout_secs,
         ),
     }
 }
@@ -160,18 +245,74 @@ fn main() -> Result<(), PepError> {
 // ── Stub server ──────────────────────────────────────────────────────────
 
 fn build_evaluator(config: &PepConfig) -> Result<Box<dyn PolicyEvaluator>, PepError> {
-    if let Some(dir) = &config.policy_dir {
+    if let Some(eval) = load_policy(config)? {
+        return Ok(Box::new(eval));
+    }
+    eprintln!(
+        "no PEP_POLICY_DIR set; using static allowlist ({} domains)",
+        config.allowed_domains.len(),
+    );
+    Ok(Box::new(NullEvaluator::new(config.allowed_domains.clone())))
+}
+
+/// The Rego evaluator for `config`'s policy bundle or directory, answering
+/// `config.policy_query`; `None` when it names neither.
+fn load_policy(config: &PepConfig) -> Result<Option<RegorusEvaluator>, PepError> {
+    let eval = if let Some(bundle) = &config.policy_bundle {
+        eprintln!("loading OPA bundle from {}", bundle.display());
+        RegorusEvaluator::from_bundle(bundle)?
+    } else if let Some(dir) = &config.policy_dir {
         eprintln!("loading OPA policies from {}", dir.display());
-        let eval = RegorusEvaluator::from_dir(dir)?;
-        eprintln!("policy hash: {}", eval.policy_hash());
-        Ok(Box::new(eval))
+        RegorusEvaluator::from_dir(dir)?
     } else {
-        eprintln!(
-            "no PEP_POLICY_DIR set; using static allowlist ({} domains)",
-            config.allowed_domains.len(),
-        );
-        Ok(Box::new(NullEvaluator::new(config.allowed_domains.clone())))
+        return Ok(None);
+    };
+    let eval = eval.with_query(&config.policy_query)?;
+    eprintln!("policy hash: {}", eval.policy_hash());
+    Ok(Some(eval))
+}
+
+/// The policy named on the command line, loaded as the stub would load it
+/// with `PEP_POLICY_DIR` or `PEP_POLICY_BUNDLE` and `PEP_POLICY_QUERY`.
+fn load_cli_policy(policy: PolicyArgs) -> Result<RegorusEvaluator, PepError> {
+    let mut builder = PepConfig::builder();
+    if let Some(bundle) = policy.policy_bundle {
+        builder = builder.policy_bundle(bundle);
     }
+    if let Some(dir) = policy.policy_dir {
+        builder = builder.policy_dir(dir);
+    }
+    if let Some(query) = policy
+        .policy_query
+        .or_else(|| std::env::var("PEP_POLICY_QUERY").ok())
+    {
+        builder = builder.policy_query(query);
+    }
+    load_policy(&builder.build())?
+        .ok_or_else(|| PepError::Config("--policy-dir or --policy-bundle is required".to_string()))
+}
+
+fn build_shadow_evaluator(
+    config: &PepConfig,
+) -> Result<Option<Box<dyn PolicyEvaluator>>, PepError> {
+    let eval = if let Some(bundle) = &config.shadow_policy_bundle {
+        RegorusEvaluator::from_bundle(bundle)?
+    } else if let Some(dir) = &config.shadow_policy_dir {
+        RegorusEvaluator::from_dir(dir)?
+    } else {
+        return Ok(None);
+    };
+    let eval = eval.with_query(&config.policy_query)?;
+    eprintln!("shadow policy hash: {}", eval.policy_hash());
+    Ok(Some(Box::new(eval)))
+}
+
+/// The `PEP_*` config with the `PEP_ALLOWLIST_URL` entries (or their cached
+/// copy) added, as the stub enforces it.
+fn config_with_remote_allowlist() -> Result<PepConfig, PepError> {
+    let mut config = PepConfig::from_env()?;
+    extend_allowlist(&mut config);
+    Ok(config)
 }
 
 fn run_stub(
@@ -180,12 +321,31 @@ fn run_stub(
     connect_timeout_secs: u64,
     request_timeout_secs: u64,
 ) -> Result<(), PepError> {
-    let client = reqwest::blocking::Client::builder()
-        .connect_timeout(Duration::from_secs(connect_timeout_secs))
-        .timeout(Duration::from_secs(request_timeout_secs))
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-    let config = PepConfig::from_env();
+    let config = config_with_remote_allowlist()?;
+    let addr_health = Arc::new(AddrHealth::default());
+    let client = build_client(
+        &config,
+        Duration::from_secs(connect_timeout_secs),
+        Duration::from_secs(request_timeout_secs),
+        &addr_health,
+    )?;
+    config.ensure_policy_configured()?;
+    if config.has_empty_policy() {
+        eprintln!(
+            "WARNING: no allowlist or policy dir configured; \
+             PEP_ALLOW_EMPTY_POLICY is set, so every request will be denied"
+        );
+    }
+    if config.allow_private_hosts {
+        eprintln!(
+            "WARNING: PEP_ALLOW_PRIVATE_HOSTS is set; the SSRF guard is OFF and the VM \
+             can reach loopback, private, and link-local addresses. INSECURE: local \
+             testing only"
+        );
+    }
+    if config.audit_sink == AuditSinkKind::File {
+        verify_audit_writable(&config.audit_log_path)?;
+    }
     let evaluator = build_evaluator(&config)?;
 
     eprintln!(
@@ -193,18 +353,31 @@ fn run_stub(
         env!("CARGO_PKG_VERSION"),
         config.max_response_bytes,
     );
+    let shadow = build_shadow_evaluator(&config)?;
+    let connect_clients = build_connect_timeout_clients(
+        &config,
+        Duration::from_secs(request_timeout_secs),
+        &addr_health,
+    )?;
+    let mut pep = Pep::new(client, config, evaluator)?
+        .with_addr_health(addr_health)
+        .with_connect_timeout_clients(connect_clients);
+    if let Some(shadow) = shadow {
+        pep = pep.with_shadow_evaluator(shadow);
+    }
+    let pep = Arc::new(pep);
+    #[cfg(unix)]
+    {
+        let pep = Arc::clone(&pep);
+        watch_signal(SignalKind::hangup(), move || reload_config(&pep))?;
+    }
 
     #[cfg(target_os = "macos")]
     {
         let addr = format!("127.0.0.1:{port}");
         let listener = TcpListener::bind(&addr)?;
         eprintln!("tcp stub listening on {addr} (macOS; vsock forwarded by AVF)");
-        for conn in listener.incoming() {
-            let mut stream = conn?;
-            if let Err(err) = handle_connection(&mut stream, &client, &config, evaluator.as_ref()) {
-                eprintln!("connection error: {err}");
-            }
-        }
+        serve_incoming(listener.incoming(), &pep, serve_tcp)?;
         Ok(())
     }
 
@@ -212,53 +385,368 @@ fn run_stub(
     {
         let listener = VsockListener::bind_with_cid_port(_cid, port)?;
         eprintln!("vsock stub listening on cid={_cid} port={port}");
-        for conn in listener.incoming() {
-            let mut stream = conn?;
-            if let Err(err) = handle_connection(&mut stream, &client, &config, evaluator.as_ref()) {
-                eprintln!("connection error: {err}");
-            }
-        }
+        serve_incoming(listener.incoming(), &pep, serve_vsock)?;
         Ok(())
     }
 }
 
+/// Hand each accepted connection to `serve` on its own thread, so a VM
+/// holding a connection open does not stall the others. Past
+/// `PEP_MAX_CONNECTIONS` open at once, a connection is refused on the accept
+/// thread instead of getting a thread of its own.
+fn serve_incoming<S, I>(incoming: I, pep: &Arc<Pep>, serve: fn(S, &Pep)) -> io::Result<()>
+where
+    S: Write + PeerCid + Send + 'static,
+    I: Iterator<Item = io::Result<S>>,
+{
+    for conn in incoming {
+        let mut stream = conn?;
+        let limit = pep.config().max_connections;
+        let Some(guard) = pep.state().connections.try_open(limit) else {
+            let ctx = RequestContext {
+                peer_cid: stream.peer_cid(),
+                ..RequestContext::default()
+            };
+            eprintln!("refusing connection: {limit} already open");
+            refuse_connection(
+                &mut stream,
+                pep,
+                &ctx,
+                &format!("connection limit of {limit} reached"),
+            );
+            continue;
+        };
+        let pep = Arc::clone(pep);
+        if let Err(err) = thread::Builder::new()
+            .name("pep-conn".to_string())
+            .spawn(move || {
+                let _conn = guard;
+                serve(stream, &pep)
+            })
+        {
+            eprintln!("connection dropped: no handler thread: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// The vsock peer CID of an accepted stream, when it has one.
+trait PeerCid {
+    fn peer_cid(&self) -> Option<u32>;
+}
+
+impl PeerCid for std::net::TcpStream {
+    fn peer_cid(&self) -> Option<u32> {
+        None
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+impl PeerCid for VsockStream {
+    fn peer_cid(&self) -> Option<u32> {
+        self.peer_addr().ok().map(|addr| addr.cid())
+    }
+}
+
+/// Answer a connection with one `too_many_connections` frame and audit the
+/// refusal; the caller then drops the stream.
+fn refuse_connection<W: Write>(stream: &mut W, pep: &Pep, ctx: &RequestContext, message: &str) {
+    append_connection_refusal(pep.state().audit.as_ref(), ctx, "too_many_connections");
+    let response = error_response("too_many_connections", message);
+    if let Err(err) = serde_json::to_vec(&response)
+        .map_err(io::Error::from)
+        .and_then(|bytes| write_frame(stream, &bytes))
+    {
+        eprintln!("refusal not delivered: {err}");
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn serve_tcp(mut stream: TcpStream, pep: &Pep) {
+    if let Err(err) = stream.set_read_timeout(pep.config().conn_idle_timeout()) {
+        eprintln!("connection error: {err}");
+        return;
+    }
+    if let Err(err) = tune_tcp_stream(&stream, &pep.config()) {
+        eprintln!("socket options not applied: {err}");
+    }
+    // Plain `GET /healthz` probes share the port with framed clients.
+    let mut prefix = [0u8; 4];
+    if stream
+        .peek(&mut prefix)
+        .is_ok_and(|read| read == prefix.len())
+        && looks_like_http(&prefix)
+    {
+        if let Err(err) = serve_http_health(&mut stream, pep) {
+            eprintln!("health probe error: {err}");
+        }
+        return;
+    }
+    if let Err(err) = handle_connection(&mut stream, pep, &RequestContext::default()) {
+        eprintln!("connection error: {err}");
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn serve_vsock(stream: VsockStream, pep: &Pep) {
+    if let Err(err) = stream.set_read_timeout(pep.config().conn_idle_timeout()) {
+        eprintln!("connection error: {err}");
+        return;
+    }
+    if let Err(err) = set_buffer_sizes(SockRef::from(&stream), &pep.config()) {
+        eprintln!("socket options not applied: {err}");
+    }
+    let peer_cid = stream.peer_cid();
+    serve_peer(stream, peer_cid, pep);
+}
+
+/// Serve a VM connection from `peer_cid` within its `PEP_MAX_CONN_PER_CID`
+/// budget.
+#[cfg(not(target_os = "macos"))]
+fn serve_peer<S: Read + Write>(mut stream: S, peer_cid: Option<u32>, pep: &Pep) {
+    let ctx = RequestContext {
+        peer_cid,
+        ..RequestContext::default()
+    };
+    let _peer = match peer_cid {
+        Some(cid) => match admit_peer(&mut stream, pep, &ctx, cid) {
+            Some(guard) => Some(guard),
+            None => return,
+        },
+        None => None,
+    };
+    if let Err(err) = handle_connection(&mut stream, pep, &ctx) {
+        eprintln!("connection error: {err}");
+    }
+}
+
+/// Take one of `cid`'s `PEP_MAX_CONN_PER_CID` slots, or answer the connection
+/// with `too_many_connections`, audit the refusal, and return `None`.
+#[cfg(not(target_os = "macos"))]
+fn admit_peer<W: Write>(
+    stream: &mut W,
+    pep: &Pep,
+    ctx: &RequestContext,
+    cid: u32,
+) -> Option<PeerGuard> {
+    let limit = pep.config().max_conn_per_cid;
+    if let Some(guard) = pep.state().peer_connections.try_open(cid, limit) {
+        return Some(guard);
+    }
+    eprintln!("refusing connection from cid {cid}: {limit} already open");
+    refuse_connection(
+        stream,
+        pep,
+        ctx,
+        &format!("connection limit of {limit} reached for this VM"),
+    );
+    None
+}
+
+/// Serve framed requests until the peer hangs up. Pings are answered in
+/// place and do not count towards `max_requests_per_conn`.
 fn handle_connection<S: Read + Write>(
     stream: &mut S,
-    client: &reqwest::blocking::Client,
-    config: &PepConfig,
-    evaluator: &dyn PolicyEvaluator,
+    pep: &Pep,
+    ctx: &RequestContext,
 ) -> Result<(), PepError> {
+    let max_requests = pep.config().max_requests_per_conn;
+    let mut served: u64 = 0;
     loop {
-        let request_frame = match read_frame(stream) {
+        if max_requests > 0 && served >= max_requests {
+            eprintln!("closing connection after {served} requests");
+            return Ok(());
+        }
+        let request_frame = match read_frame_with_limit(stream, pep.config().max_frame_bytes()) {
             Ok(frame) => frame,
             Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
-            Err(err) => return Err(PepError::Io(err)),
+            Err(err) if is_timeout(&err) => {
+                eprintln!(
+                    "closing idle connection after {}s without a frame",
+                    pep.config().conn_idle_timeout_secs
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                if let Some(too_large) = frame_too_large(&err) {
+                    eprintln!("closing connection: {too_large}");
+                    return write_frame_too_large(stream, too_large);
+                }
+                return Err(PepError::Io(err));
+            }
+        };
+        if Heartbeat::is_ping(&request_frame) {
+            write_frame(stream, &serde_json::to_vec(&Heartbeat::Pong)?)?;
+            continue;
+        }
+        served += 1;
+        let codec = FrameCodec::of(&request_frame);
+        let request = match decode_request(&request_frame) {
+            Ok(request) => request,
+            Err(message) => {
+                append_invalid_request(pep.state().audit.as_ref(), ctx);
+                let response = error_response("invalid_request", &message);
+                write_frame(stream, &encode_payload(&response, None, codec)?)?;
+                continue;
+            }
         };
-        let request: HttpRequest = serde_json::from_slice(&request_frame)?;
 
         // Handle health check requests in-band
-        if request.method == "HEALTH" {
-            let health = health_check(config);
-            let response_bytes = serde_json::to_vec(&health)?;
-            write_frame(stream, &response_bytes)?;
+        if request.method == HEALTH_METHOD {
+            let health = health_check(&pep.config(), &pep.state().connections);
+            write_frame(stream, &encode_payload(&health, None, codec)?)?;
             continue;
         }
 
-        let response = execute_request(client, request, config, evaluator)?;
-        let response_bytes = serde_json::to_vec(&response)?;
-        write_frame(stream, &response_bytes)?;
+        let ctx = &RequestContext {
+            raw_body: codec == FrameCodec::Binary,
+            ..ctx.clone()
+        };
+        let response = pep.execute_streaming(request, ctx, &mut FrameSink::new(stream, codec));
+        write_frame(
+            stream,
+            &encode_payload(&response, response.body.as_deref(), codec)?,
+        )?;
+    }
+}
+
+/// Rebuild the config and evaluator after SIGHUP and swap them in, keeping
+/// the current ones if they do not load. The process environment cannot
+/// change, so what a reload picks up is `PEP_ALLOWED_DOMAINS_FILE`,
+/// `PEP_ALLOWLIST_URL` and the policy directory or bundle.
+fn reload_config(pep: &Pep) {
+    let loaded = config_with_remote_allowlist().and_then(|config| {
+        config.ensure_policy_configured()?;
+        let evaluator = build_evaluator(&config)?;
+        Ok((config, evaluator))
+    });
+    let after = loaded
+        .as_ref()
+        .map_or(0, |(config, _)| config.allowed_domains.len());
+    match loaded.and_then(|(config, evaluator)| pep.reload(config, evaluator)) {
+        Ok(before) => eprintln!(
+            "config reloaded: allowlist {} -> {after} domains \
+             (PEP_ALLOWED_DOMAINS_FILE, PEP_ALLOWLIST_URL)",
+            before.allowed_domains.len()
+        ),
+        Err(err) => eprintln!("config reload failed, keeping current config: {err}"),
     }
 }
 
 // ── Health check ─────────────────────────────────────────────────────────
 
-fn run_health() -> Result<(), PepError> {
-    let config = PepConfig::from_env();
-    let health = health_check(&config);
+#[cfg(target_os = "macos")]
+fn serve_http_health(stream: &mut TcpStream, pep: &Pep) -> Result<(), PepError> {
+    let head = read_http_head(stream, MAX_HTTP_HEAD_BYTES)?;
+    let response = http_health_response(
+        &head,
+        &pep.config(),
+        &pep.state().connections,
+        &pep.state().metrics,
+    )?;
+    stream.write_all(&response)?;
+    Ok(())
+}
+
+fn run_health(cid: Option<u32>, port: u32) -> Result<(), PepError> {
+    if let Some(cid) = cid {
+        let mut stream = VsockStream::connect_with_cid_port(cid, port)?;
+        let health = query_health(&mut stream)?;
+        println!("{}", serde_json::to_string_pretty(&health)?);
+        return Ok(());
+    }
+    let config = PepConfig::from_env()?;
+    // A separate process: connection counts are only meaningful in-band.
+    let health = health_check(&config, &ConnectionCounter::default());
     println!("{}", serde_json::to_string_pretty(&health)?);
     Ok(())
 }
 
+/// Send an in-band health request and return the daemon's `HealthStatus`,
+/// or an error unless it reports `ok`.
+fn query_health<S: Read + Write>(stream: &mut S) -> Result<serde_json::Value, PepError> {
+    let request = HttpRequest::new(HEALTH_METHOD, String::new());
+    let reply = exchange_frame(stream, &serde_json::to_vec(&request)?)?;
+    let health: serde_json::Value = serde_json::from_slice(&reply)?;
+    if health["status"] != "ok" {
+        return Err(PepError::Io(io::Error::other(format!(
+            "daemon is not healthy: {health}"
+        ))));
+    }
+    Ok(health)
+}
+
+fn run_config() -> Result<(), PepError> {
+    let config = PepConfig::from_env()?;
+    println!("{}", serde_json::to_string_pretty(&config.redacted())?);
+    Ok(())
+}
+
+// ── Policy check ─────────────────────────────────────────────────────────
+
+/// Host under the reserved `.invalid` TLD; a deny-by-default policy must
+/// never allow it.
+const POLICY_CHECK_DENY_URL: &str = "https://pep-policy-check.invalid/";
+
+fn run_policy_check(policy: PolicyArgs, allow_url: Option<String>) -> Result<(), PepError> {
+    let eval = load_cli_policy(policy)?;
+    println!("policy hash: {}", eval.policy_hash());
+
+    let mut samples = vec![(POLICY_CHECK_DENY_URL.to_string(), false)];
+    if let Some(url) = allow_url {
+        samples.push((url, true));
+    }
+
+    let mut failures = Vec::new();
+    for (raw_url, expect_allow) in samples {
+        let url = Url::parse(&raw_url)
+            .map_err(|err| PepError::Policy(format!("invalid sample url {raw_url}: {err}")))?;
+        let input = PolicyInput::from_http_url(&url, "GET");
+        if !eval.decision_defined(&input)? {
+            return Err(PepError::Policy(format!(
+                "{} is undefined for {raw_url}",
+                eval.query()
+            )));
+        }
+        let decision = eval.evaluate(&input)?;
+        let expected = if expect_allow { "allow" } else { "deny" };
+        println!("GET {raw_url} (expect {expected})");
+        println!("{}", serde_json::to_string_pretty(&decision)?);
+        if decision.allow != expect_allow {
+            failures.push(format!("expected {expected} for {raw_url}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(PepError::Policy(failures.join("; ")));
+    }
+    Ok(())
+}
+
+fn run_policy_eval(policy: PolicyArgs, input_file: PathBuf) -> Result<(), PepError> {
+    let eval = load_cli_policy(policy)?;
+    let raw = fs::read_to_string(&input_file)?;
+    // Parse first so malformed input fails with a JSON error, not a policy one.
+    let input: serde_json::Value = serde_json::from_str(&raw)?;
+    let decision = eval.evaluate_json(&input.to_string())?;
+
+    eprintln!(
+        "{}: {}",
+        if decision.allow { "allow" } else { "deny" },
+        decision.reason.as_deref().unwrap_or("(no reason)"),
+    );
+    println!("{}", serde_json::to_string_pretty(&decision)?);
+    Ok(())
+}
+
+fn run_probe(url: &str, method: &str) -> Result<(), PepError> {
+    let config = config_with_remote_allowlist()?;
+    let evaluator = build_evaluator(&config)?;
+    let verdict = probe(url, method, &config, evaluator.as_ref())?;
+    println!("{}", serde_json::to_string_pretty(&verdict)?);
+    Ok(())
+}
+
 // ── Vsock client ─────────────────────────────────────────────────────────
 
 fn run_client(
@@ -292,21 +780,25 @@ fn run_client(
     };
 
     let request = HttpRequest {
-        method: method.unwrap_or_else(|| "GET".to_string()),
-        url,
         headers,
         body_base64,
+        ..HttpRequest::new(method.as_deref().unwrap_or("GET"), url)
     };
     let payload = serde_json::to_vec(&request)?;
 
     let mut stream = VsockStream::connect_with_cid_port(cid, port)?;
-    write_frame(&mut stream, &payload)?;
-    let response_bytes = read_frame(&mut stream)?;
+    let response_bytes = exchange_frame(&mut stream, &payload)?;
     let response: HttpResponse = serde_json::from_slice(&response_bytes)?;
     println!("{}", serde_json::to_string_pretty(&response)?);
     Ok(())
 }
 
+/// Write one request frame and read the reply frame.
+fn exchange_frame<S: Read + Write>(stream: &mut S, payload: &[u8]) -> Result<Vec<u8>, PepError> {
+    write_frame(stream, payload)?;
+    Ok(read_frame(stream)?)
+}
+
 // ── Boot VM ──────────────────────────────────────────────────────────────
 
 #[allow(clippy::too_many_arguments)]
@@ -326,6 +818,7 @@ fn run_boot_vm(
     efi: bool,
     efi_vars: Option<PathBuf>,
     shared_dir: Option<PathBuf>,
+    boot_timeout_secs: Option<u64>,
 ) -> Result<(), PepError> {
     if !swift_script.exists() {
         return Err(PepError::Io(io::Error::new(
@@ -393,6 +886,7 @@ fn run_boot_vm(
             "swift runner must be a compiled binary, not a .swift script",
         )));
     }
+    ensure_runner_executable(&swift_script)?;
     let mut cmd = Command::new(&swift_script);
     if let Some(kernel) = kernel {
         cmd.arg("--kernel").arg(kernel);
@@ -415,9 +909,6 @@ fn run_boot_vm(
     if let Some(cmdline) = cmdline {
         cmd.arg("--cmdline").arg(cmdline);
     }
-    if let Some(console_log) = console_log {
-        cmd.arg("--console-log").arg(console_log);
-    }
     if let Some(status_log) = status_log {
         cmd.arg("--status-log").arg(status_log);
     }
@@ -430,7 +921,16 @@ fn run_boot_vm(
     if let Some(shared_dir) = shared_dir {
         cmd.arg("--shared-dir").arg(shared_dir);
     }
-    let status = cmd.status()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        let shutdown = Arc::clone(&shutdown);
+        watch_signal(SignalKind::terminate(), move || {
+            shutdown.store(true, Ordering::SeqCst);
+        })?;
+    }
+    let timeout = boot_timeout_secs.map(Duration::from_secs);
+    let status = run_runner(cmd, console_log.as_deref(), timeout, &shutdown)?;
     if !status.success() {
         return Err(PepError::Io(io::Error::other(format!(
             "swift runner exited with {status}"
@@ -438,3 +938,641 @@ fn run_boot_vm(
     }
     Ok(())
 }
+
+/// Prefix for runner output re-emitted on the daemon's stderr.
+const VM_OUTPUT_PREFIX: &str = "[vm]";
+
+/// How often the runner is polled for exit, timeout, and shutdown.
+const RUNNER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Spawn the runner with piped stdout/stderr and re-emit each line prefixed
+/// on stderr; the raw lines are also written to `console_log` when given.
+fn run_runner(
+    mut cmd: Command,
+    console_log: Option<&Path>,
+    timeout: Option<Duration>,
+    shutdown: &AtomicBool,
+) -> Result<ExitStatus, PepError> {
+    let console = console_log.map(File::create).transpose()?.map(Mutex::new);
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let status = thread::scope(|scope| {
+        let console = console.as_ref();
+        if let Some(stdout) = stdout {
+            scope.spawn(move || relay_runner_output(stdout, console));
+        }
+        if let Some(stderr) = stderr {
+            scope.spawn(move || relay_runner_output(stderr, console));
+        }
+        wait_runner(&mut child, timeout, shutdown)
+    })?;
+    Ok(status)
+}
+
+/// Wait for the runner, forwarding a daemon shutdown as SIGTERM and killing
+/// (and reaping) it once `timeout` has passed.
+fn wait_runner(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    shutdown: &AtomicBool,
+) -> Result<ExitStatus, PepError> {
+    let started = Instant::now();
+    let mut forwarded = false;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if !forwarded && shutdown.load(Ordering::SeqCst) {
+            forward_sigterm(child);
+            forwarded = true;
+        }
+        if let Some(timeout) = timeout
+            && started.elapsed() >= timeout
+        {
+            child.kill()?;
+            child.wait()?;
+            return Err(PepError::BootTimeout(timeout));
+        }
+        thread::sleep(RUNNER_POLL_INTERVAL);
+    }
+}
+
+/// `Child::kill` sends SIGKILL; SIGTERM lets the runner stop the VM cleanly.
+fn forward_sigterm(child: &Child) {
+    eprintln!("forwarding SIGTERM to swift runner (pid {})", child.id());
+    let sent = Command::new("kill")
+        .arg("-TERM")
+        .arg(child.id().to_string())
+        .status();
+    if !sent.is_ok_and(|status| status.success()) {
+        eprintln!("failed to forward SIGTERM to swift runner");
+    }
+}
+
+/// Run `on_signal` on a watcher thread for each delivery of `kind` instead of
+/// taking the default action. SIGTERM lets the runner be stopped and reaped
+/// first; SIGHUP reloads the stub's config without holding up any request.
+/// Deliveries that arrive while `on_signal` runs are coalesced into one.
+#[cfg(unix)]
+fn watch_signal(kind: SignalKind, on_signal: impl Fn() + Send + 'static) -> Result<(), PepError> {
+    use tokio::signal::unix::signal;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let mut signals = {
+        let _guard = runtime.enter();
+        signal(kind)?
+    };
+    // `on_signal` runs outside the runtime: a reload fetches the remote
+    // allowlist with a blocking client, which cannot run inside one.
+    thread::spawn(move || {
+        while runtime.block_on(signals.recv()).is_some() {
+            on_signal();
+        }
+    });
+    Ok(())
+}
+
+fn relay_runner_output(reader: impl Read, console: Option<&Mutex<File>>) {
+    if let Err(err) = prefix_lines(reader, &mut io::stderr(), console) {
+        eprintln!("{VM_OUTPUT_PREFIX} output relay stopped: {err}");
+    }
+}
+
+/// Copy `reader` line by line to `out` with `VM_OUTPUT_PREFIX`, teeing the
+/// unprefixed bytes to `tee`.
+fn prefix_lines<W: Write, T: Write>(
+    reader: impl Read,
+    out: &mut W,
+    tee: Option<&Mutex<T>>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if let Some(tee) = tee {
+            let mut tee = tee
+                .lock()
+                .map_err(|_| io::Error::other("console log lock poisoned"))?;
+            tee.write_all(&line)?;
+            tee.flush()?;
+        }
+        let text = String::from_utf8_lossy(&line);
+        writeln!(
+            out,
+            "{VM_OUTPUT_PREFIX} {}",
+            text.trim_end_matches(['\r', '\n'])
+        )?;
+    }
+}
+
+/// The runner is spawned directly; catch a non-executable artifact here
+/// rather than surfacing a bare spawn error.
+fn ensure_runner_executable(path: &Path) -> Result<(), PepError> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Err(PepError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("swift runner is not a file: {}", path.display()),
+        )));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(PepError::Io(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "swift runner is not executable: {} (build it or chmod +x)",
+                    path.display()
+                ),
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avf_vsock_host::framing::split_payload;
+    use reqwest::blocking::Client;
+    use std::io::Cursor;
+
+    /// Scripted client: reads come from `input`, writes land in `output`.
+    struct MemStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for MemStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn health_subcommand_queries_a_serving_daemon() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let audit_path = dir.path().join("audit.jsonl");
+        let server = thread::spawn(move || {
+            let config = PepConfig::builder().audit_log_path(audit_path).build();
+            let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+            let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+            let (mut stream, _) = listener.accept().expect("accept");
+            handle_connection(&mut stream, &pep, &RequestContext::default())
+        });
+
+        let mut stream = std::net::TcpStream::connect(addr).expect("connect");
+        let health = query_health(&mut stream).expect("healthy");
+        drop(stream);
+        server.join().expect("server").expect("connection");
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["version"], env!("CARGO_PKG_VERSION"));
+
+        let mut input = Vec::new();
+        let refusal = error_response("too_many_connections", "full");
+        write_frame(&mut input, &serde_json::to_vec(&refusal).expect("json")).expect("frame");
+        let mut refused = MemStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        assert!(query_health(&mut refused).is_err());
+    }
+
+    #[test]
+    fn connection_closes_after_max_requests() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let config = PepConfig::builder()
+            .audit_log_path(dir.path().join("audit.jsonl"))
+            .max_requests_per_conn(2)
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+
+        let health = br#"{"method":"HEALTH","url":"","headers":[]}"#;
+        let mut input = Vec::new();
+        for _ in 0..3 {
+            write_frame(&mut input, health).expect("frame");
+        }
+        let mut stream = MemStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        handle_connection(&mut stream, &pep, &RequestContext::default()).expect("connection");
+
+        let mut output = Cursor::new(stream.output);
+        assert!(read_frame(&mut output).is_ok());
+        assert!(read_frame(&mut output).is_ok());
+        assert!(read_frame(&mut output).is_err());
+        assert_eq!(stream.input.position() as usize, 2 * (4 + health.len()));
+    }
+
+    #[test]
+    fn ping_gets_pong_without_counting_as_a_request() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let audit = dir.path().join("audit.jsonl");
+        let config = PepConfig::builder()
+            .audit_log_path(&audit)
+            .max_requests_per_conn(1)
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+
+        let mut input = Vec::new();
+        write_frame(&mut input, br#"{"type":"ping"}"#).expect("frame");
+        write_frame(&mut input, br#"{"type":"ping"}"#).expect("frame");
+        write_frame(&mut input, br#"{"method":"HEALTH","url":"","headers":[]}"#).expect("frame");
+        let mut stream = MemStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        handle_connection(&mut stream, &pep, &RequestContext::default()).expect("connection");
+
+        let mut output = Cursor::new(stream.output);
+        for _ in 0..2 {
+            let pong: Heartbeat =
+                serde_json::from_slice(&read_frame(&mut output).expect("pong")).expect("json");
+            assert_eq!(pong, Heartbeat::Pong);
+        }
+        // The health request is still served within the one-request limit.
+        let health: serde_json::Value =
+            serde_json::from_slice(&read_frame(&mut output).expect("health")).expect("json");
+        assert!(health.get("status").is_some());
+        pep.state().audit.flush();
+        assert!(fs::read_to_string(&audit).unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn binary_request_gets_binary_response() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let config = PepConfig::builder()
+            .audit_log_path(dir.path().join("audit.jsonl"))
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+
+        let denied = HttpRequest::new("POST", "https://denied.example/");
+        let mut input = Vec::new();
+        write_frame(
+            &mut input,
+            &encode_payload(&denied, Some(b"payload"), FrameCodec::Binary).expect("encode"),
+        )
+        .expect("frame");
+        let mut stream = MemStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        handle_connection(&mut stream, &pep, &RequestContext::default()).expect("connection");
+
+        let frame = read_frame(&mut Cursor::new(stream.output)).expect("frame");
+        assert_eq!(FrameCodec::of(&frame), FrameCodec::Binary);
+        let (meta, body) = split_payload(&frame).expect("decode");
+        assert!(body.is_none());
+        let response: HttpResponse = serde_json::from_slice(meta).expect("response");
+        assert_eq!(response.error.expect("denied").code, "DENIED_BY_POLICY");
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn cid_over_connection_budget_is_refused_and_audited() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let audit = dir.path().join("audit.jsonl");
+        let config = PepConfig::builder()
+            .audit_log_path(&audit)
+            .max_conn_per_cid(2)
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+        let ctx = RequestContext {
+            peer_cid: Some(7),
+            ..RequestContext::default()
+        };
+
+        let mut output = Vec::new();
+        let first = admit_peer(&mut output, &pep, &ctx, 7).expect("first");
+        let _second = admit_peer(&mut output, &pep, &ctx, 7).expect("second");
+        assert!(output.is_empty());
+        assert!(admit_peer(&mut output, &pep, &ctx, 7).is_none());
+        // Another VM is unaffected.
+        assert!(admit_peer(&mut Vec::new(), &pep, &ctx, 8).is_some());
+
+        let response: HttpResponse =
+            serde_json::from_slice(&read_frame(&mut Cursor::new(output)).expect("frame"))
+                .expect("response");
+        assert_eq!(
+            response.error.expect("refused").code,
+            "too_many_connections"
+        );
+
+        drop(first);
+        assert!(admit_peer(&mut Vec::new(), &pep, &ctx, 7).is_some());
+
+        pep.state().audit.flush();
+        let log = fs::read_to_string(&audit).expect("audit log");
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains("too_many_connections"));
+        assert!(log.contains("vsock://7"));
+    }
+
+    /// Serve connections to a loopback port the way `run_stub` serves the
+    /// vsock listener, returning the port's address.
+    #[cfg(not(target_os = "macos"))]
+    fn serve_on_loopback(
+        pep: &Arc<Pep>,
+        serve: fn(std::net::TcpStream, &Pep),
+    ) -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let pep = Arc::clone(pep);
+        thread::spawn(move || serve_incoming(listener.incoming(), &pep, serve));
+        addr
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn connect(addr: std::net::SocketAddr) -> std::net::TcpStream {
+        let stream = std::net::TcpStream::connect(addr).expect("connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("timeout");
+        stream
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn ping(stream: &mut std::net::TcpStream) {
+        write_frame(stream, br#"{"type":"ping"}"#).expect("ping");
+        let pong: Heartbeat =
+            serde_json::from_slice(&read_frame(stream).expect("pong")).expect("json");
+        assert_eq!(pong, Heartbeat::Pong);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn concurrent_connections_from_one_cid_share_its_budget() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let audit = dir.path().join("audit.jsonl");
+        let config = PepConfig::builder()
+            .audit_log_path(&audit)
+            .max_conn_per_cid(1)
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Arc::new(Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep"));
+        // Every TCP client stands in for the same VM.
+        let addr = serve_on_loopback(&pep, |stream, pep| serve_peer(stream, Some(7), pep));
+        let mut first = connect(addr);
+        ping(&mut first);
+        let mut second = connect(addr);
+        let refusal: HttpResponse =
+            serde_json::from_slice(&read_frame(&mut second).expect("refusal")).expect("json");
+        assert_eq!(refusal.error.expect("refused").code, "too_many_connections");
+        // The first connection is still being served.
+        ping(&mut first);
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pep.state().peer_connections.open_for(7) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        ping(&mut connect(addr));
+
+        pep.state().audit.flush();
+        let log = fs::read_to_string(&audit).expect("audit log");
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains("too_many_connections"));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn connections_past_the_daemon_limit_are_refused_and_audited() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let audit = dir.path().join("audit.jsonl");
+        let config = PepConfig::builder()
+            .audit_log_path(&audit)
+            .max_connections(1)
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Arc::new(Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep"));
+        // Each client is a different VM, so only the daemon-wide limit applies.
+        let addr = serve_on_loopback(&pep, |stream, pep| {
+            let cid = u32::from(stream.peer_addr().expect("peer").port());
+            serve_peer(stream, Some(cid), pep)
+        });
+        let mut first = connect(addr);
+        ping(&mut first);
+        let mut second = connect(addr);
+        let refusal: HttpResponse =
+            serde_json::from_slice(&read_frame(&mut second).expect("refusal")).expect("json");
+        assert_eq!(refusal.error.expect("refused").code, "too_many_connections");
+        ping(&mut first);
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pep.state().connections.active() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        ping(&mut connect(addr));
+
+        pep.state().audit.flush();
+        let log = fs::read_to_string(&audit).expect("audit log");
+        assert_eq!(log.lines().count(), 1);
+        assert!(log.contains("too_many_connections"));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn health_counts_connections_open_at_once() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let config = PepConfig::builder()
+            .audit_log_path(dir.path().join("audit.jsonl"))
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Arc::new(Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep"));
+        let addr = serve_on_loopback(&pep, |stream, pep| serve_peer(stream, None, pep));
+
+        let mut first = connect(addr);
+        ping(&mut first);
+        let mut second = connect(addr);
+        let health = query_health(&mut second).expect("health");
+        assert_eq!(health["active_connections"], 2);
+        assert_eq!(health["peak_connections"], 2);
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pep.state().connections.active() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let health = query_health(&mut second).expect("health");
+        assert_eq!(health["active_connections"], 1);
+        assert_eq!(health["peak_connections"], 2);
+    }
+
+    #[test]
+    fn policy_commands_take_a_directory_or_a_bundle_and_a_query() {
+        let cli = Cli::try_parse_from([
+            "pep-daemon",
+            "policy-eval",
+            "--policy-bundle",
+            "policy.tar.gz",
+            "--policy-query",
+            "data.custom.decision",
+            "--input-file",
+            "input.json",
+        ])
+        .expect("bundle");
+        let Commands::PolicyEval { policy, .. } = cli.command else {
+            panic!("expected policy-eval");
+        };
+        assert_eq!(policy.policy_bundle, Some(PathBuf::from("policy.tar.gz")));
+        assert_eq!(policy.policy_query.as_deref(), Some("data.custom.decision"));
+
+        assert!(Cli::try_parse_from(["pep-daemon", "policy-check", "--policy-dir", "p"]).is_ok());
+        assert!(Cli::try_parse_from(["pep-daemon", "policy-check"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "pep-daemon",
+                "policy-check",
+                "--policy-dir",
+                "p",
+                "--policy-bundle",
+                "b.tar.gz",
+            ])
+            .is_err()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watched_signal_runs_its_handler_on_the_watcher_thread() {
+        let (sender, received) = std::sync::mpsc::channel();
+        watch_signal(SignalKind::user_defined1(), move || {
+            let _ = sender.send(thread::current().id());
+        })
+        .expect("watch");
+        let status = Command::new("kill")
+            .arg("-USR1")
+            .arg(std::process::id().to_string())
+            .status()
+            .expect("kill");
+        assert!(status.success());
+        let handler_thread = received
+            .recv_timeout(Duration::from_secs(5))
+            .expect("handler ran");
+        assert_ne!(handler_thread, thread::current().id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_executable_runner_is_rejected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let runner = dir.path().join("avf-runner");
+        fs::write(&runner, b"#!/bin/sh\n").expect("write runner");
+        fs::set_permissions(&runner, fs::Permissions::from_mode(0o644)).expect("chmod");
+        let err = ensure_runner_executable(&runner).expect_err("expected not executable");
+        assert!(err.to_string().contains("not executable"));
+
+        fs::set_permissions(&runner, fs::Permissions::from_mode(0o755)).expect("chmod");
+        assert!(ensure_runner_executable(&runner).is_ok());
+        assert!(ensure_runner_executable(dir.path()).is_err());
+    }
+
+    #[test]
+    fn runner_output_is_prefixed_and_teed() {
+        let console = Mutex::new(Vec::new());
+        let mut out = Vec::new();
+        prefix_lines(
+            Cursor::new(b"boot ok\r\nlogin:".to_vec()),
+            &mut out,
+            Some(&console),
+        )
+        .expect("relay");
+        assert_eq!(
+            String::from_utf8(out).expect("utf8"),
+            "[vm] boot ok\n[vm] login:\n"
+        );
+        assert_eq!(console.into_inner().expect("console"), b"boot ok\r\nlogin:");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_runner_is_killed_after_boot_timeout() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("30");
+        let started = Instant::now();
+        let err = run_runner(
+            cmd,
+            None,
+            Some(Duration::from_millis(200)),
+            &AtomicBool::new(false),
+        )
+        .expect_err("expected boot timeout");
+        assert!(matches!(err, PepError::BootTimeout(_)));
+        assert!(err.to_string().starts_with("boot_timeout"));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shutdown_is_forwarded_to_runner() {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("30");
+        let started = Instant::now();
+        let status = run_runner(cmd, None, None, &AtomicBool::new(true)).expect("runner");
+        assert!(!status.success());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn malformed_frame_gets_invalid_request_and_connection_continues() {
+        let dir = tempfile::TempDir::new().expect("tempdir");
+        let config = PepConfig::builder()
+            .audit_log_path(dir.path().join("audit.jsonl"))
+            .build();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(Client::new(), config, Box::new(evaluator)).expect("pep");
+
+        let mut input = Vec::new();
+        write_frame(&mut input, br#"{"method":"GET","headers":[]}"#).expect("frame");
+        write_frame(&mut input, br#"{"method":"HEALTH","url":"","headers":[]}"#).expect("frame");
+        let mut stream = MemStream {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        handle_connection(&mut stream, &pep, &RequestContext::default()).expect("connection");
+
+        let mut output = Cursor::new(stream.output);
+        let response: HttpResponse =
+            serde_json::from_slice(&read_frame(&mut output).expect("first")).expect("json");
+        let error = response.error.expect("error");
+        assert_eq!(error.code, "invalid_request");
+        assert!(error.message.contains("url"));
+        assert!(read_frame(&mut output).is_ok());
+
+        pep.state().audit.flush();
+        let log = fs::read_to_string(dir.path().join("audit.jsonl")).expect("audit log");
+        assert_eq!(log.lines().count(), 1);
+        let entry: serde_json::Value = serde_json::from_str(log.trim()).expect("entry");
+        assert_eq!(entry["decision"], "deny");
+        assert_eq!(entry["error_code"], "invalid_request");
+        assert_eq!(entry["method"], "FRAME");
+    }
+}
@@ -0,0 +1,138 @@
+//! In-process metrics, rendered in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the `pep_policy_eval_seconds` buckets.
+const POLICY_EVAL_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+];
+
+/// Upper bounds, in seconds, of the `pep_upstream_response_seconds` buckets.
+const UPSTREAM_RESPONSE_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// Time spent in `PolicyEvaluator::evaluate`, active policy only.
+    pub policy_eval: Histogram,
+    /// Time from sending an upstream request to its response head.
+    pub upstream_response: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            policy_eval: Histogram::new(&POLICY_EVAL_BUCKETS),
+            upstream_response: Histogram::new(&UPSTREAM_RESPONSE_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.policy_eval.render(
+            &mut out,
+            "pep_policy_eval_seconds",
+            "Policy evaluation latency.",
+        );
+        self.upstream_response.render(
+            &mut out,
+            "pep_upstream_response_seconds",
+            "Upstream time to response head.",
+        );
+        out
+    }
+}
+
+/// Fixed-bucket latency histogram.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative; the last is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+        if let Some(bucket) = self.buckets.get(bucket) {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Mean observation, or `None` before the first.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed) / count))
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match self.bounds.get(index) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+                }
+                None => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+                }
+            }
+        }
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {}", self.count());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_renders_cumulative_buckets() {
+        let histogram = Histogram::new(&[0.001, 0.01]);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_secs(1));
+        assert_eq!(histogram.count(), 3);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "eval_seconds", "Eval.");
+        assert!(out.contains("# TYPE eval_seconds histogram\n"));
+        assert!(out.contains("eval_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(out.contains("eval_seconds_bucket{le=\"0.01\"} 2\n"));
+        assert!(out.contains("eval_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("eval_seconds_sum 1.0055\n"));
+        assert!(out.contains("eval_seconds_count 3\n"));
+    }
+}
@@ -1,50 +1,61 @@
 #![forbid(unsafe_code)]
 
+use crate::clock::{UtcOffset, rfc3339, unix_secs};
 use crate::ssrf::is_host_allowed;
 use crate::types::PepError;
 
+use flate2::read::GzDecoder;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::Read;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use std::time::SystemTime;
 use uuid::Uuid;
 
 // ── Policy input types (structured input for OPA evaluation) ────────────
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PolicyInput {
     pub action: ActionInput,
     pub subject: SubjectInput,
     pub context: ContextInput,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ActionInput {
     #[serde(rename = "type")]
     pub action_type: String,
     pub resource: ResourceInput,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ResourceInput {
     pub url: String,
     pub host: String,
     pub path: String,
     pub method: String,
     pub scheme: String,
+    /// Address the SSRF guard vetted for `host`; absent on the pre-DNS pass.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SubjectInput {
     pub user_id: String,
     pub workspace_id: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ContextInput {
+    /// Unix seconds as a string; kept for existing policies.
     pub time: String,
+    pub time_unix: u64,
+    /// RFC 3339 in `PEP_POLICY_UTC_OFFSET`, for `time.parse_rfc3339_ns`.
+    pub time_iso: String,
     pub stage: String,
     pub mode: String,
 }
@@ -65,17 +76,30 @@ pub struct Constraints {
     pub max_bytes: Option<usize>,
     pub allowed_domains: Option<Vec<String>>,
     pub rate_limit_per_min: Option<u32>,
+    /// Per-host byte budget per quota window; overrides `PEP_HOST_BYTE_QUOTAS`.
+    pub quota_bytes: Option<u64>,
+    /// Headers set on the upstream request, replacing any the client sent
+    /// under the same name. Never serialized: values may be credentials.
+    #[serde(default, skip_serializing)]
+    pub inject_headers: Option<Vec<(String, String)>>,
 }
 
 // ── PolicyInput construction helpers ────────────────────────────────────
 
 impl PolicyInput {
     pub fn from_http_url(url: &reqwest::Url, method: &str) -> Self {
-        let ts = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_secs().to_string())
-            .unwrap_or_else(|_| "0".to_string());
+        Self::from_http_url_at(url, method, SystemTime::now(), UtcOffset::UTC)
+    }
 
+    /// As `from_http_url`, with the request time and the offset `time_iso`
+    /// is written in given explicitly.
+    pub fn from_http_url_at(
+        url: &reqwest::Url,
+        method: &str,
+        now: SystemTime,
+        offset: UtcOffset,
+    ) -> Self {
+        let time_unix = unix_secs(now);
         Self {
             action: ActionInput {
                 action_type: "http.request".to_string(),
@@ -85,6 +109,7 @@ impl PolicyInput {
                     path: url.path().to_string(),
                     method: method.to_uppercase(),
                     scheme: url.scheme().to_string(),
+                    ip: None,
                 },
             },
             subject: SubjectInput {
@@ -92,17 +117,99 @@ impl PolicyInput {
                 workspace_id: "default".to_string(),
             },
             context: ContextInput {
-                time: ts,
+                time: time_unix.to_string(),
+                time_unix,
+                time_iso: rfc3339(now, offset),
                 stage: "default".to_string(),
                 mode: "interactive".to_string(),
             },
         }
     }
+
+    /// The input as a Rego value, built field by field rather than through
+    /// a JSON string. Must stay in step with the `Serialize` derives above.
+    pub fn to_regorus_value(&self) -> regorus::Value {
+        let ActionInput {
+            action_type,
+            resource,
+        } = &self.action;
+        let mut resource_fields = vec![
+            ("url", resource.url.as_str().into()),
+            ("host", resource.host.as_str().into()),
+            ("path", resource.path.as_str().into()),
+            ("method", resource.method.as_str().into()),
+            ("scheme", resource.scheme.as_str().into()),
+        ];
+        if let Some(ip) = &resource.ip {
+            resource_fields.push(("ip", ip.as_str().into()));
+        }
+        let context = &self.context;
+        rego_object(vec![
+            (
+                "action",
+                rego_object(vec![
+                    ("type", action_type.as_str().into()),
+                    ("resource", rego_object(resource_fields)),
+                ]),
+            ),
+            (
+                "subject",
+                rego_object(vec![
+                    ("user_id", self.subject.user_id.as_str().into()),
+                    ("workspace_id", self.subject.workspace_id.as_str().into()),
+                ]),
+            ),
+            (
+                "context",
+                rego_object(vec![
+                    ("time", context.time.as_str().into()),
+                    ("time_unix", context.time_unix.into()),
+                    ("time_iso", context.time_iso.as_str().into()),
+                    ("stage", context.stage.as_str().into()),
+                    ("mode", context.mode.as_str().into()),
+                ]),
+            ),
+        ])
+    }
+}
+
+fn rego_object(fields: Vec<(&str, regorus::Value)>) -> regorus::Value {
+    fields
+        .into_iter()
+        .map(|(key, value)| (regorus::Value::from(key), value))
+        .collect::<BTreeMap<_, _>>()
+        .into()
+}
+
+/// Where a shadow ("observe") policy disagreed with the enforced one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShadowDivergence {
+    pub active_allow: bool,
+    pub active_reason: Option<String>,
+    pub shadow_allow: bool,
+    pub shadow_reason: Option<String>,
+    pub shadow_policy_hash: String,
+}
+
+/// Compare an enforced decision with a shadow one. Only the verdict and
+/// reason count; decision ids and hashes always differ.
+pub fn shadow_divergence(
+    active: &PolicyDecision,
+    shadow: &PolicyDecision,
+) -> Option<ShadowDivergence> {
+    (active.allow != shadow.allow || active.reason != shadow.reason).then(|| ShadowDivergence {
+        active_allow: active.allow,
+        active_reason: active.reason.clone(),
+        shadow_allow: shadow.allow,
+        shadow_reason: shadow.reason.clone(),
+        shadow_policy_hash: shadow.policy_hash.clone(),
+    })
 }
 
 // ── Evaluator trait (seam for testing) ──────────────────────────────────
 
-pub trait PolicyEvaluator {
+/// Shared by every connection thread, so implementations must be `Sync`.
+pub trait PolicyEvaluator: Send + Sync {
     fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, PepError>;
     fn policy_hash(&self) -> &str;
 }
@@ -147,9 +254,13 @@ impl PolicyEvaluator for NullEvaluator {
 
 // ── RegorusEvaluator (embedded Rego evaluation via regorus) ─────────────
 
+/// Rule evaluated for a decision unless `PEP_POLICY_QUERY` names another.
+pub const DEFAULT_POLICY_QUERY: &str = "data.pep.decision";
+
 pub struct RegorusEvaluator {
-    engine: RefCell<regorus::Engine>,
+    engine: Mutex<regorus::Engine>,
     hash: String,
+    query: String,
 }
 
 impl RegorusEvaluator {
@@ -210,25 +321,175 @@ impl RegorusEvaluator {
         let hash = format!("{:x}", hasher.finalize());
 
         Ok(Self {
-            engine: RefCell::new(engine),
+            engine: Mutex::new(engine),
             hash,
+            query: DEFAULT_POLICY_QUERY.to_string(),
+        })
+    }
+
+    /// Load an OPA-style bundle: a `.tar.gz` of `.rego` policies and
+    /// `data.json` documents, each mounted at its directory path
+    /// (`config/data.json` becomes `data.config`). Documents are merged in
+    /// path order into a single data tree; two documents that set the same
+    /// non-object value fail with an error naming the colliding path.
+    pub fn from_bundle(bundle_path: &Path) -> Result<Self, PepError> {
+        let file = fs::File::open(bundle_path)
+            .map_err(|e| PepError::Policy(format!("opening bundle: {e}")))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+        let mut policies: Vec<(String, String)> = Vec::new();
+        let mut documents: Vec<(Vec<String>, serde_json::Value)> = Vec::new();
+        let entries = archive
+            .entries()
+            .map_err(|e| PepError::Policy(format!("reading bundle: {e}")))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|e| PepError::Policy(format!("reading bundle: {e}")))?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = entry
+                .path()
+                .map_err(|e| PepError::Policy(format!("reading bundle path: {e}")))?
+                .into_owned();
+            let components: Vec<String> = path
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                    _ => None,
+                })
+                .collect();
+            let Some((file_name, dirs)) = components.split_last() else {
+                continue;
+            };
+            let is_policy = file_name.ends_with(".rego") && !file_name.contains("_test");
+            if !is_policy && file_name != "data.json" {
+                continue;
+            }
+
+            let mut content = String::new();
+            entry.read_to_string(&mut content).map_err(|e| {
+                PepError::Policy(format!("reading {} from bundle: {e}", path.display()))
+            })?;
+            if is_policy {
+                policies.push((components.join("/"), content));
+            } else {
+                let doc = serde_json::from_str(&content).map_err(|e| {
+                    PepError::Policy(format!("parsing {} from bundle: {e}", path.display()))
+                })?;
+                documents.push((dirs.to_vec(), doc));
+            }
+        }
+        policies.sort_by(|a, b| a.0.cmp(&b.0));
+        documents.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if policies.is_empty() {
+            return Err(PepError::Policy(
+                "no .rego files found in bundle".to_string(),
+            ));
+        }
+
+        let mut engine = regorus::Engine::new();
+        let mut hasher = Sha256::new();
+        for (name, content) in &policies {
+            hasher.update(content.as_bytes());
+            engine
+                .add_policy(name.clone(), content.clone())
+                .map_err(|e| PepError::Policy(format!("parsing {name}: {e}")))?;
+        }
+
+        let mut merged = serde_json::Value::Object(serde_json::Map::new());
+        for (mount, doc) in documents {
+            let mounted = mount.iter().rev().fold(doc, |acc, key| {
+                serde_json::Value::Object(serde_json::Map::from_iter([(key.clone(), acc)]))
+            });
+            merge_data(&mut merged, mounted, &mut Vec::new())?;
+        }
+        let data = regorus::Value::from_json_str(&merged.to_string())
+            .map_err(|e| PepError::Policy(format!("loading bundle data: {e}")))?;
+        engine
+            .add_data(data)
+            .map_err(|e| PepError::Policy(format!("adding bundle data: {e}")))?;
+
+        let hash = format!("{:x}", hasher.finalize());
+
+        Ok(Self {
+            engine: Mutex::new(engine),
+            hash,
+            query: DEFAULT_POLICY_QUERY.to_string(),
         })
     }
 }
 
-impl PolicyEvaluator for RegorusEvaluator {
-    fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, PepError> {
-        let decision_id = Uuid::new_v4().to_string();
-        let input_json = serde_json::to_string(input)?;
-        let input_value = regorus::Value::from_json_str(&input_json)
-            .map_err(|e| PepError::Policy(format!("building input value: {e}")))?;
+/// Deep-merge `incoming` into `target`. Objects merge key by key; any other
+/// overlap is a conflict reported as `data.<path>`.
+fn merge_data(
+    target: &mut serde_json::Value,
+    incoming: serde_json::Value,
+    path: &mut Vec<String>,
+) -> Result<(), PepError> {
+    match (target, incoming) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(incoming)) => {
+            for (key, value) in incoming {
+                match target.get_mut(&key) {
+                    Some(existing) => {
+                        path.push(key);
+                        merge_data(existing, value, path)?;
+                        path.pop();
+                    }
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+            Ok(())
+        }
+        _ => {
+            let mut full = vec!["data".to_string()];
+            full.extend(path.iter().cloned());
+            Err(PepError::Policy(format!(
+                "conflicting bundle data at {}",
+                full.join(".")
+            )))
+        }
+    }
+}
+
+impl RegorusEvaluator {
+    /// Evaluate `query` (e.g. `data.egress.allow`) for decisions instead of
+    /// `data.pep.decision`. Fails unless the rule is defined for an empty
+    /// input, i.e. it has a default.
+    pub fn with_query(mut self, query: &str) -> Result<Self, PepError> {
+        self.query = query.to_string();
+        if self.eval_decision(regorus::Value::new_object())? == regorus::Value::Undefined {
+            return Err(PepError::Policy(format!(
+                "policy query {query} is undefined for an empty input; \
+                 give it a default rule"
+            )));
+        }
+        Ok(self)
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
 
-        let mut engine = self.engine.borrow_mut();
-        engine.set_input(input_value);
+    /// Whether the policy query produces a value for `input` (as opposed
+    /// to Undefined, which `evaluate` silently maps to deny).
+    pub fn decision_defined(&self, input: &PolicyInput) -> Result<bool, PepError> {
+        Ok(self.eval_decision(input.to_regorus_value())? != regorus::Value::Undefined)
+    }
 
-        let result = engine
-            .eval_rule("data.pep.decision".to_string())
-            .map_err(|e| PepError::Policy(format!("evaluating rule: {e}")))?;
+    /// Evaluate raw input JSON, which need not match `PolicyInput` (e.g. a
+    /// captured or hand-edited input being replayed).
+    pub fn evaluate_json(&self, input_json: &str) -> Result<PolicyDecision, PepError> {
+        let input = regorus::Value::from_json_str(input_json)
+            .map_err(|e| PepError::Policy(format!("building input value: {e}")))?;
+        self.evaluate_value(input)
+    }
+
+    fn evaluate_value(&self, input: regorus::Value) -> Result<PolicyDecision, PepError> {
+        let decision_id = Uuid::new_v4().to_string();
+        let result = self.eval_decision(input)?;
 
         // If the rule evaluates to Undefined, treat as deny.
         if result == regorus::Value::Undefined {
@@ -241,7 +502,11 @@ impl PolicyEvaluator for RegorusEvaluator {
             });
         }
 
-        let allow = result["allow"] == regorus::Value::from(true);
+        // A bare boolean rule is the decision itself.
+        let allow = match result.as_bool() {
+            Ok(allow) => *allow,
+            Err(_) => result["allow"] == regorus::Value::from(true),
+        };
 
         let reason = result["reason"]
             .as_string()
@@ -255,6 +520,18 @@ impl PolicyEvaluator for RegorusEvaluator {
                     max_bytes: c["max_bytes"].as_i64().ok().map(|n| n as usize),
                     allowed_domains: None,
                     rate_limit_per_min: c["rate_limit_per_min"].as_i64().ok().map(|n| n as u32),
+                    quota_bytes: c["quota_bytes"].as_i64().ok().map(|n| n as u64),
+                    inject_headers: c["inject_headers"].as_object().ok().map(|headers| {
+                        headers
+                            .iter()
+                            .filter_map(|(name, value)| {
+                                Some((
+                                    name.as_string().ok()?.to_string(),
+                                    value.as_string().ok()?.to_string(),
+                                ))
+                            })
+                            .collect()
+                    }),
                 })
             } else {
                 None
@@ -270,11 +547,149 @@ impl PolicyEvaluator for RegorusEvaluator {
         })
     }
 
+    fn eval_decision(&self, input: regorus::Value) -> Result<regorus::Value, PepError> {
+        let mut engine = self
+            .engine
+            .lock()
+            .map_err(|_| PepError::Policy("policy engine lock poisoned".to_string()))?;
+        engine.set_input(input);
+
+        engine
+            .eval_rule(self.query.clone())
+            .map_err(|e| PepError::Policy(format!("evaluating rule: {e}")))
+    }
+}
+
+impl PolicyEvaluator for RegorusEvaluator {
+    fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, PepError> {
+        self.evaluate_value(input.to_regorus_value())
+    }
+
     fn policy_hash(&self) -> &str {
         &self.hash
     }
 }
 
+// ── CombinedEvaluator (stacked policies, all must allow) ────────────────
+
+/// Policies layered on one another, e.g. a platform baseline under team
+/// policies. A request is allowed only when every component allows it; the
+/// first deny is returned with its reason. Constraints merge to the most
+/// restrictive of each.
+pub struct CombinedEvaluator {
+    evaluators: Vec<Box<dyn PolicyEvaluator>>,
+    hash: String,
+}
+
+impl CombinedEvaluator {
+    pub fn new(evaluators: Vec<Box<dyn PolicyEvaluator>>) -> Self {
+        let mut hasher = Sha256::new();
+        for evaluator in &evaluators {
+            hasher.update(evaluator.policy_hash().as_bytes());
+            hasher.update([0]);
+        }
+        let hash = format!("{:x}", hasher.finalize());
+        Self { evaluators, hash }
+    }
+}
+
+impl PolicyEvaluator for CombinedEvaluator {
+    fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, PepError> {
+        let mut reasons = Vec::new();
+        let mut constraints = None;
+        for evaluator in &self.evaluators {
+            let decision = evaluator.evaluate(input)?;
+            if !decision.allow {
+                return Ok(PolicyDecision {
+                    policy_hash: self.hash.clone(),
+                    ..decision
+                });
+            }
+            reasons.extend(decision.reason);
+            constraints = match (constraints, decision.constraints) {
+                (Some(merged), Some(next)) => Some(restrict_constraints(merged, next)),
+                (merged, next) => merged.or(next),
+            };
+        }
+        // Nothing combined allows nothing.
+        let allow = !self.evaluators.is_empty();
+        let reason = if allow {
+            (!reasons.is_empty()).then(|| reasons.join("; "))
+        } else {
+            Some("no policies combined".to_string())
+        };
+        Ok(PolicyDecision {
+            allow,
+            reason,
+            constraints: constraints.filter(|_| allow),
+            decision_id: Uuid::new_v4().to_string(),
+            policy_hash: self.hash.clone(),
+        })
+    }
+
+    fn policy_hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// The tighter of two sets of constraints: smaller limits, domains both
+/// allow, and `a`'s injected header where both set one.
+fn restrict_constraints(a: Constraints, b: Constraints) -> Constraints {
+    fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+    let allowed_domains = match (a.allowed_domains, b.allowed_domains) {
+        (Some(a), Some(b)) => {
+            let mut both: Vec<String> = a
+                .iter()
+                .filter(|entry| entry_within(entry, &b))
+                .chain(b.iter().filter(|entry| entry_within(entry, &a)))
+                .cloned()
+                .collect();
+            both.sort();
+            both.dedup();
+            Some(both)
+        }
+        (a, b) => a.or(b),
+    };
+    let inject_headers = match (a.inject_headers, b.inject_headers) {
+        (Some(mut a), Some(b)) => {
+            let extra: Vec<_> = b
+                .into_iter()
+                .filter(|(name, _)| !a.iter().any(|(set, _)| set.eq_ignore_ascii_case(name)))
+                .collect();
+            a.extend(extra);
+            Some(a)
+        }
+        (a, b) => a.or(b),
+    };
+    Constraints {
+        max_bytes: min(a.max_bytes, b.max_bytes),
+        allowed_domains,
+        rate_limit_per_min: min(a.rate_limit_per_min, b.rate_limit_per_min),
+        quota_bytes: min(a.quota_bytes, b.quota_bytes),
+        inject_headers,
+    }
+}
+
+/// Whether every host allowlist `entry` admits is also admitted by `list`.
+fn entry_within(entry: &str, list: &[String]) -> bool {
+    match entry.strip_prefix('=') {
+        Some(exact) => is_host_allowed(exact, list),
+        None => {
+            let suffixes: Vec<String> = list
+                .iter()
+                .filter(|other| !other.starts_with('='))
+                .cloned()
+                .collect();
+            is_host_allowed(entry, &suffixes)
+        }
+    }
+}
+
 // ── Tests ───────────────────────────────────────────────────────────────
 
 #[cfg(test)]
@@ -335,6 +750,7 @@ host_allowed(host) if {
                     path: "/".to_string(),
                     method: "GET".to_string(),
                     scheme: scheme.to_string(),
+                    ip: None,
                 },
             },
             subject: SubjectInput {
@@ -343,6 +759,8 @@ host_allowed(host) if {
             },
             context: ContextInput {
                 time: "0".to_string(),
+                time_unix: 0,
+                time_iso: "1970-01-01T00:00:00Z".to_string(),
                 stage: "test".to_string(),
                 mode: "test".to_string(),
             },
@@ -369,6 +787,19 @@ host_allowed(host) if {
         assert_eq!(json["action"]["resource"]["host"], "example.com");
     }
 
+    #[test]
+    fn policy_input_value_matches_its_json_form() {
+        let mut input = make_input("example.com", "https");
+        for ip in [None, Some("93.184.216.34".to_string())] {
+            input.action.resource.ip = ip;
+            let json = serde_json::to_string(&input).expect("serialize");
+            assert_eq!(
+                input.to_regorus_value(),
+                regorus::Value::from_json_str(&json).expect("parse")
+            );
+        }
+    }
+
     // ── RegorusEvaluator ────────────────────────────────────────────
 
     #[test]
@@ -413,6 +844,95 @@ host_allowed(host) if {
         assert_eq!(constraints.max_bytes, Some(1_048_576));
     }
 
+    #[test]
+    fn regorus_parses_injected_headers_without_serializing_them() {
+        let dir = TempDir::new().expect("tempdir");
+        fs::write(dir.path().join("pep.rego"), sample_policy()).expect("write policy");
+        let data = sample_data().replace(
+            r#""max_bytes": 1048576"#,
+            r#""inject_headers": { "X-Api-Version": "2024-06", "X-Tenant": "t-42" }"#,
+        );
+        fs::write(dir.path().join("data.json"), data).expect("write data");
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+        let decision = eval
+            .evaluate(&make_input("example.com", "https"))
+            .expect("evaluate");
+        let constraints = decision.constraints.as_ref().expect("constraints");
+        assert_eq!(
+            constraints.inject_headers,
+            Some(vec![
+                ("X-Api-Version".to_string(), "2024-06".to_string()),
+                ("X-Tenant".to_string(), "t-42".to_string()),
+            ])
+        );
+        let logged = serde_json::to_string(&decision).expect("serialize");
+        assert!(!logged.contains("t-42"), "{logged}");
+    }
+
+    #[test]
+    fn regorus_evaluates_raw_json_input() {
+        let (_dir, eval) = setup_evaluator();
+        let input = serde_json::to_string(&make_input("example.com", "https")).expect("serialize");
+        let decision = eval.evaluate_json(&input).expect("evaluate");
+        assert!(decision.allow);
+
+        let decision = eval
+            .evaluate_json(r#"{"action": {"type": "fs.write"}}"#)
+            .expect("evaluate");
+        assert!(!decision.allow);
+    }
+
+    #[test]
+    fn regorus_denies_outside_time_window() {
+        let dir = TempDir::new().expect("tempdir");
+        let policy = r#"package pep
+import rego.v1
+
+default decision := {"allow": false, "reason": "outside business hours"}
+
+decision := {"allow": true, "reason": "ok"} if {
+    [hour, _, _] := time.clock(time.parse_rfc3339_ns(input.context.time_iso))
+    hour >= 9
+    hour < 17
+}
+"#;
+        fs::write(dir.path().join("pep.rego"), policy).expect("write policy");
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+        let url = reqwest::Url::parse("https://example.com/").expect("url");
+        // 2024-03-01 at 10:00 and 20:00 UTC.
+        let at = |secs| {
+            let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            PolicyInput::from_http_url_at(&url, "GET", now, UtcOffset::UTC)
+        };
+
+        assert!(eval.evaluate(&at(1_709_287_200)).expect("evaluate").allow);
+        let decision = eval.evaluate(&at(1_709_323_200)).expect("evaluate");
+        assert!(!decision.allow);
+        assert_eq!(decision.reason.as_deref(), Some("outside business hours"));
+    }
+
+    #[test]
+    fn regorus_denies_on_resolved_ip() {
+        let dir = TempDir::new().expect("tempdir");
+        let policy = r#"package pep
+import rego.v1
+
+default decision := {"allow": false, "reason": "denied by default policy"}
+
+decision := {"allow": true, "reason": "ok"} if {
+    input.action.resource.host == "example.com"
+    not input.action.resource.ip == "203.0.113.7"
+}
+"#;
+        fs::write(dir.path().join("pep.rego"), policy).expect("write policy");
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+
+        let mut input = make_input("example.com", "https");
+        assert!(eval.evaluate(&input).expect("evaluate").allow);
+        input.action.resource.ip = Some("203.0.113.7".to_string());
+        assert!(!eval.evaluate(&input).expect("evaluate").allow);
+    }
+
     #[test]
     fn regorus_decision_has_unique_id() {
         let (_dir, eval) = setup_evaluator();
@@ -432,6 +952,70 @@ host_allowed(host) if {
         assert_eq!(e1.policy_hash(), e2.policy_hash());
     }
 
+    #[test]
+    fn regorus_decision_defined_for_basic_input() {
+        let (_dir, eval) = setup_evaluator();
+        let input = make_input("evil.com", "https");
+        assert!(eval.decision_defined(&input).expect("evaluate"));
+    }
+
+    #[test]
+    fn regorus_decision_undefined_without_default() {
+        let dir = TempDir::new().expect("tempdir");
+        let policy = r#"package pep
+import rego.v1
+
+decision := {"allow": true} if {
+    input.action.resource.host == "only.example"
+}
+"#;
+        fs::write(dir.path().join("pep.rego"), policy).expect("write policy");
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+        let input = make_input("evil.com", "https");
+        assert!(!eval.decision_defined(&input).expect("evaluate"));
+    }
+
+    #[test]
+    fn regorus_evaluates_custom_query_path() {
+        let dir = TempDir::new().expect("tempdir");
+        let policy = r#"package egress
+import rego.v1
+
+default allow := false
+
+allow if {
+    input.action.resource.host == "example.com"
+}
+"#;
+        fs::write(dir.path().join("egress.rego"), policy).expect("write policy");
+        let eval = RegorusEvaluator::from_dir(dir.path())
+            .expect("from_dir")
+            .with_query("data.egress.allow")
+            .expect("query");
+        assert!(
+            eval.evaluate(&make_input("example.com", "https"))
+                .expect("evaluate")
+                .allow
+        );
+        assert!(
+            !eval
+                .evaluate(&make_input("evil.com", "https"))
+                .expect("evaluate")
+                .allow
+        );
+
+        // The package has no `pep.decision`, and `allow` needs a default.
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+        assert!(eval.with_query(DEFAULT_POLICY_QUERY).is_err());
+        fs::write(
+            dir.path().join("egress.rego"),
+            policy.replace("default allow := false\n", ""),
+        )
+        .expect("write policy");
+        let eval = RegorusEvaluator::from_dir(dir.path()).expect("from_dir");
+        assert!(eval.with_query("data.egress.allow").is_err());
+    }
+
     #[test]
     fn regorus_rejects_empty_policy_dir() {
         let dir = TempDir::new().expect("tempdir");
@@ -439,6 +1023,88 @@ host_allowed(host) if {
         assert!(result.is_err());
     }
 
+    // ── Bundles ─────────────────────────────────────────────────────
+
+    fn write_bundle(dir: &TempDir, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let path = dir.path().join("bundle.tar.gz");
+        let file = fs::File::create(&path).expect("create bundle");
+        let encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .expect("append");
+        }
+        builder.into_inner().expect("tar").finish().expect("gzip");
+        path
+    }
+
+    #[test]
+    fn bundle_merges_mounted_data_documents() {
+        let dir = TempDir::new().expect("tempdir");
+        let bundle = write_bundle(
+            &dir,
+            &[
+                ("pep.rego", sample_policy()),
+                (
+                    "data.json",
+                    r#"{"config": {"constraints": {"max_bytes": 1}}}"#,
+                ),
+                (
+                    "config/data.json",
+                    r#"{"allowed_domains": ["example.com"]}"#,
+                ),
+                ("pep_test.rego", "not rego at all"),
+            ],
+        );
+        assert!(RegorusEvaluator::from_bundle(&bundle).is_ok());
+    }
+
+    #[test]
+    fn bundle_rejects_conflicting_keys_with_path() {
+        let dir = TempDir::new().expect("tempdir");
+        let bundle = write_bundle(
+            &dir,
+            &[
+                ("pep.rego", sample_policy()),
+                ("data.json", r#"{"config": {"allowed_domains": ["a.com"]}}"#),
+                ("config/data.json", r#"{"allowed_domains": ["b.com"]}"#),
+            ],
+        );
+        let err = match RegorusEvaluator::from_bundle(&bundle) {
+            Ok(_) => panic!("expected conflict"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains("data.config.allowed_domains"), "{err}");
+    }
+
+    #[test]
+    fn bundle_without_policies_is_rejected() {
+        let dir = TempDir::new().expect("tempdir");
+        let bundle = write_bundle(&dir, &[("data.json", "{}")]);
+        assert!(RegorusEvaluator::from_bundle(&bundle).is_err());
+    }
+
+    #[test]
+    fn regorus_bundle_evaluates_like_directory() {
+        let dir = TempDir::new().expect("tempdir");
+        let bundle = write_bundle(
+            &dir,
+            &[("pep.rego", sample_policy()), ("data.json", sample_data())],
+        );
+        let eval = RegorusEvaluator::from_bundle(&bundle).expect("from_bundle");
+        let decision = eval
+            .evaluate(&make_input("api.openai.com", "https"))
+            .expect("evaluate");
+        assert!(decision.allow);
+        let (_dir, from_dir) = setup_evaluator();
+        assert_eq!(eval.policy_hash(), from_dir.policy_hash());
+    }
+
     // ── NullEvaluator ───────────────────────────────────────────────
 
     #[test]
@@ -456,4 +1122,102 @@ host_allowed(host) if {
         let decision = eval.evaluate(&input).expect("evaluate");
         assert!(!decision.allow);
     }
+
+    // ── CombinedEvaluator ───────────────────────────────────────────
+
+    /// Allows everything with fixed constraints.
+    struct ConstrainedEvaluator(Constraints, &'static str);
+
+    impl PolicyEvaluator for ConstrainedEvaluator {
+        fn evaluate(&self, _input: &PolicyInput) -> Result<PolicyDecision, PepError> {
+            Ok(PolicyDecision {
+                allow: true,
+                reason: None,
+                constraints: Some(self.0.clone()),
+                decision_id: Uuid::new_v4().to_string(),
+                policy_hash: self.1.to_string(),
+            })
+        }
+
+        fn policy_hash(&self) -> &str {
+            self.1
+        }
+    }
+
+    fn constraints(max_bytes: Option<usize>, domains: &[&str]) -> Constraints {
+        Constraints {
+            max_bytes,
+            allowed_domains: Some(domains.iter().map(|d| d.to_string()).collect()),
+            rate_limit_per_min: None,
+            quota_bytes: None,
+            inject_headers: None,
+        }
+    }
+
+    #[test]
+    fn combined_evaluator_denies_what_the_baseline_denies() {
+        let baseline = NullEvaluator::new(vec!["example.com".to_string()]);
+        let team = NullEvaluator::new(vec!["example.com".to_string(), "evil.com".to_string()]);
+        let combined = CombinedEvaluator::new(vec![Box::new(baseline), Box::new(team)]);
+
+        let decision = combined
+            .evaluate(&make_input("evil.com", "https"))
+            .expect("evaluate");
+        assert!(!decision.allow);
+        assert_eq!(decision.reason.as_deref(), Some("domain not allowlisted"));
+        assert_eq!(decision.policy_hash, combined.policy_hash());
+        let decision = combined
+            .evaluate(&make_input("api.example.com", "https"))
+            .expect("evaluate");
+        assert!(decision.allow);
+
+        let empty = CombinedEvaluator::new(Vec::new());
+        let decision = empty
+            .evaluate(&make_input("api.example.com", "https"))
+            .expect("evaluate");
+        assert!(!decision.allow);
+    }
+
+    #[test]
+    fn combined_evaluator_keeps_the_tightest_constraints() {
+        let combined = CombinedEvaluator::new(vec![
+            Box::new(ConstrainedEvaluator(
+                constraints(Some(1024), &["example.com", "=other.org"]),
+                "baseline",
+            )),
+            Box::new(ConstrainedEvaluator(
+                constraints(None, &["api.example.com", "other.org", "evil.com"]),
+                "team",
+            )),
+        ]);
+        let decision = combined
+            .evaluate(&make_input("api.example.com", "https"))
+            .expect("evaluate");
+        let merged = decision.constraints.expect("constraints");
+        assert_eq!(merged.max_bytes, Some(1024));
+        assert_eq!(
+            merged.allowed_domains,
+            Some(vec![
+                "=other.org".to_string(),
+                "api.example.com".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn combined_policy_hash_depends_on_components_and_order() {
+        let hash = |names: [&'static str; 2]| {
+            let evaluators: Vec<Box<dyn PolicyEvaluator>> = names
+                .into_iter()
+                .map(|name| {
+                    Box::new(ConstrainedEvaluator(constraints(None, &[]), name))
+                        as Box<dyn PolicyEvaluator>
+                })
+                .collect();
+            CombinedEvaluator::new(evaluators).policy_hash().to_string()
+        };
+        assert_eq!(hash(["a", "b"]), hash(["a", "b"]));
+        assert_ne!(hash(["a", "b"]), hash(["b", "a"]));
+        assert_ne!(hash(["a", "b"]), hash(["a", "c"]));
+    }
 }
@@ -0,0 +1,135 @@
+//! Dry-run of the request checks: would this URL be allowed? Runs the same
+//! gates as `execute_request` in the same order but never fetches anything
+//! (DNS is still resolved for the SSRF guard).
+
+use reqwest::Url;
+use serde::Serialize;
+use std::time::SystemTime;
+
+use crate::config::PepConfig;
+use crate::policy::{PolicyDecision, PolicyEvaluator, PolicyInput};
+use crate::ssrf::{is_host_allowed, is_ip_allowed, is_port_allowed, is_scheme_allowed, vet_host};
+use crate::types::PepError;
+
+#[derive(Debug, Serialize)]
+pub struct ProbeVerdict {
+    pub allowed: bool,
+    /// First check that failed: `url`, `scheme`, `port`, `allowlist`,
+    /// `policy`, or `ssrf`.
+    pub failed_check: Option<String>,
+    pub reason: Option<String>,
+    /// Present once policy evaluation has run.
+    pub decision: Option<PolicyDecision>,
+}
+
+impl ProbeVerdict {
+    fn denied(check: &str, reason: String, decision: Option<PolicyDecision>) -> Self {
+        Self {
+            allowed: false,
+            failed_check: Some(check.to_string()),
+            reason: Some(reason),
+            decision,
+        }
+    }
+}
+
+pub fn probe(
+    raw_url: &str,
+    method: &str,
+    config: &PepConfig,
+    evaluator: &dyn PolicyEvaluator,
+) -> Result<ProbeVerdict, PepError> {
+    let url = match Url::parse(raw_url) {
+        Ok(url) => url,
+        Err(err) => return Ok(ProbeVerdict::denied("url", err.to_string(), None)),
+    };
+
+    if !is_scheme_allowed(url.scheme()) {
+        let reason = format!("unsupported URL scheme {}", url.scheme());
+        return Ok(ProbeVerdict::denied("scheme", reason, None));
+    }
+
+    if !is_port_allowed(&url, config.allowed_ports.as_deref()) {
+        let reason = "destination port not in PEP_ALLOWED_PORTS".to_string();
+        return Ok(ProbeVerdict::denied("port", reason, None));
+    }
+
+    // Without a policy the static allowlist is the whole policy; name it as
+    // such so a miss is not reported as an opaque policy deny.
+    let host = url.host_str().unwrap_or("");
+    if config.policy_dir.is_none()
+        && config.policy_bundle.is_none()
+        && !is_host_allowed(host, &config.allowed_domains)
+    {
+        let reason = format!("host {host} is not in PEP_ALLOWED_DOMAINS");
+        return Ok(ProbeVerdict::denied("allowlist", reason, None));
+    }
+
+    let input =
+        PolicyInput::from_http_url_at(&url, method, SystemTime::now(), config.policy_utc_offset);
+    let decision = evaluator.evaluate(&input)?;
+    if !decision.allow {
+        let reason = decision
+            .reason
+            .clone()
+            .unwrap_or_else(|| "denied by policy".to_string());
+        return Ok(ProbeVerdict::denied("policy", reason, Some(decision)));
+    }
+
+    match vet_host(&url, config.dns_timeout(), config.allow_private_hosts) {
+        Err(err) => {
+            return Ok(ProbeVerdict::denied(
+                "ssrf",
+                err.to_string(),
+                Some(decision),
+            ));
+        }
+        Ok(ip) if !is_ip_allowed(ip, &config.allowed_cidrs) => {
+            let reason = format!("ip {ip} is not in PEP_ALLOWED_CIDRS");
+            return Ok(ProbeVerdict::denied("ssrf", reason, Some(decision)));
+        }
+        Ok(_) => {}
+    }
+
+    Ok(ProbeVerdict {
+        allowed: true,
+        failed_check: None,
+        reason: decision.reason.clone(),
+        decision: Some(decision),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::NullEvaluator;
+    use std::path::PathBuf;
+
+    fn run(url: &str, allowed_domains: &[&str]) -> ProbeVerdict {
+        let mut config = PepConfig::for_tests(PathBuf::from("/nonexistent/audit.jsonl"));
+        config.allowed_domains = allowed_domains.iter().map(|d| d.to_string()).collect();
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        probe(url, "GET", &config, &evaluator).expect("probe")
+    }
+
+    #[test]
+    fn probe_reports_first_failing_check() {
+        let verdict = run("ftp://example.com/", &["example.com"]);
+        assert_eq!(verdict.failed_check.as_deref(), Some("scheme"));
+
+        let verdict = run("https://evil.com/", &["example.com"]);
+        assert_eq!(verdict.failed_check.as_deref(), Some("allowlist"));
+        assert!(verdict.decision.is_none());
+
+        let verdict = run("https://10.0.0.1/", &["10.0.0.1"]);
+        assert_eq!(verdict.failed_check.as_deref(), Some("ssrf"));
+        assert!(verdict.decision.is_some_and(|d| d.allow));
+    }
+
+    #[test]
+    fn probe_allows_public_allowlisted_host() {
+        let verdict = run("https://93.184.216.34/", &["93.184.216.34"]);
+        assert!(verdict.allowed);
+        assert!(verdict.failed_check.is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Fixed-window byte accounting keyed by host (or allowlist entry).
+#[derive(Debug, Default)]
+pub struct ByteQuotas {
+    windows: Mutex<HashMap<String, QuotaWindow>>,
+}
+
+#[derive(Debug)]
+struct QuotaWindow {
+    started: Instant,
+    used: u64,
+}
+
+impl ByteQuotas {
+    /// Returns `Err` with a reason when `key` has already used `limit` bytes
+    /// in the current window.
+    pub fn check(
+        &self,
+        key: &str,
+        limit: u64,
+        window: Duration,
+        now: Instant,
+    ) -> Result<(), String> {
+        let mut windows = self
+            .windows
+            .lock()
+            .map_err(|_| "quota store poisoned".to_string())?;
+        let entry = windows.entry(key.to_string()).or_insert(QuotaWindow {
+            started: now,
+            used: 0,
+        });
+        if now.duration_since(entry.started) >= window {
+            entry.started = now;
+            entry.used = 0;
+        }
+        if entry.used >= limit {
+            return Err(format!(
+                "byte quota exhausted for {key} ({} of {limit} bytes used)",
+                entry.used
+            ));
+        }
+        Ok(())
+    }
+
+    /// Charge `bytes` against `key`'s current window.
+    pub fn record(&self, key: &str, bytes: u64, window: Duration, now: Instant) {
+        let Ok(mut windows) = self.windows.lock() else {
+            return;
+        };
+        let entry = windows.entry(key.to_string()).or_insert(QuotaWindow {
+            started: now,
+            used: 0,
+        });
+        if now.duration_since(entry.started) >= window {
+            entry.started = now;
+            entry.used = 0;
+        }
+        entry.used = entry.used.saturating_add(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn quota_allows_until_budget_used() {
+        let quotas = ByteQuotas::default();
+        let now = Instant::now();
+        assert!(quotas.check("api.example.com", 100, HOUR, now).is_ok());
+        quotas.record("api.example.com", 60, HOUR, now);
+        assert!(quotas.check("api.example.com", 100, HOUR, now).is_ok());
+        quotas.record("api.example.com", 60, HOUR, now);
+        let err = quotas
+            .check("api.example.com", 100, HOUR, now)
+            .expect_err("expected quota exceeded");
+        assert!(err.contains("api.example.com"));
+    }
+
+    #[test]
+    fn quota_is_tracked_per_key() {
+        let quotas = ByteQuotas::default();
+        let now = Instant::now();
+        quotas.record("a.example.com", 500, HOUR, now);
+        assert!(quotas.check("a.example.com", 100, HOUR, now).is_err());
+        assert!(quotas.check("b.example.com", 100, HOUR, now).is_ok());
+    }
+
+    #[test]
+    fn quota_resets_after_window() {
+        let quotas = ByteQuotas::default();
+        let start = Instant::now();
+        quotas.record("api.example.com", 500, HOUR, start);
+        assert!(quotas.check("api.example.com", 100, HOUR, start).is_err());
+        let later = start + HOUR;
+        assert!(quotas.check("api.example.com", 100, HOUR, later).is_ok());
+    }
+}
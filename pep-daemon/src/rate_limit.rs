@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Window for a policy's `rate_limit_per_min` constraint.
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Fixed-window request counting keyed by host.
+#[derive(Debug, Default)]
+pub struct RequestRateLimits {
+    windows: Mutex<HashMap<String, RateWindow>>,
+}
+
+#[derive(Debug)]
+struct RateWindow {
+    started: Instant,
+    admitted: u32,
+}
+
+/// Limiter state for a key, returned to the client with a `rate_limited`
+/// denial so it knows when to retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Whole seconds, rounded up, until the window resets.
+    pub reset_secs: u64,
+}
+
+impl RequestRateLimits {
+    /// Admit one request for `key`, or return `Err` with the limiter state
+    /// when `limit` requests were already admitted in the current window.
+    pub fn acquire(
+        &self,
+        key: &str,
+        limit: u32,
+        window: Duration,
+        now: Instant,
+    ) -> Result<RateLimitStatus, RateLimitStatus> {
+        let Ok(mut windows) = self.windows.lock() else {
+            // Deny by default: a poisoned limiter admits nothing.
+            return Err(RateLimitStatus {
+                limit,
+                remaining: 0,
+                reset_secs: window.as_secs(),
+            });
+        };
+        let entry = windows.entry(key.to_string()).or_insert(RateWindow {
+            started: now,
+            admitted: 0,
+        });
+        if now.duration_since(entry.started) >= window {
+            entry.started = now;
+            entry.admitted = 0;
+        }
+        let left = window.saturating_sub(now.duration_since(entry.started));
+        let reset_secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        if entry.admitted >= limit {
+            return Err(RateLimitStatus {
+                limit,
+                remaining: 0,
+                reset_secs,
+            });
+        }
+        entry.admitted += 1;
+        Ok(RateLimitStatus {
+            limit,
+            remaining: limit - entry.admitted,
+            reset_secs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_admitted_until_limit() {
+        let limits = RequestRateLimits::default();
+        let now = Instant::now();
+        let first = limits
+            .acquire("api.example.com", 2, RATE_LIMIT_WINDOW, now)
+            .expect("first");
+        assert_eq!(first.remaining, 1);
+        limits
+            .acquire("api.example.com", 2, RATE_LIMIT_WINDOW, now)
+            .expect("second");
+
+        let later = now + Duration::from_millis(20_500);
+        let denied = limits
+            .acquire("api.example.com", 2, RATE_LIMIT_WINDOW, later)
+            .expect_err("expected limit");
+        assert_eq!(
+            denied,
+            RateLimitStatus {
+                limit: 2,
+                remaining: 0,
+                reset_secs: 40,
+            }
+        );
+        assert!(
+            limits
+                .acquire("other.example.com", 2, RATE_LIMIT_WINDOW, later)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn limit_resets_after_window() {
+        let limits = RequestRateLimits::default();
+        let start = Instant::now();
+        limits
+            .acquire("api.example.com", 1, RATE_LIMIT_WINDOW, start)
+            .expect("first");
+        assert!(
+            limits
+                .acquire("api.example.com", 1, RATE_LIMIT_WINDOW, start)
+                .is_err()
+        );
+        let later = start + RATE_LIMIT_WINDOW;
+        assert!(
+            limits
+                .acquire("api.example.com", 1, RATE_LIMIT_WINDOW, later)
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Allowlist entries fetched from `PEP_ALLOWLIST_URL` at startup and on
+//! reload, for fleets that manage the allowlist centrally.
+//!
+//! The document is
+//! `{"allowed_domains": ["example.com"], "allowed_cidrs": ["203.0.113.0/24"]}`,
+//! entries in `PEP_ALLOWED_DOMAINS` and `PEP_ALLOWED_CIDRS` syntax;
+//! `allowed_cidrs` is optional. The URL's host must be in
+//! `PEP_ALLOWLIST_BOOTSTRAP_DOMAINS` and pass the SSRF guard, and the fetch is
+//! pinned to the vetted address. A fetch that fails falls back to the copy in
+//! `PEP_ALLOWLIST_CACHE`, then to the static list alone.
+
+use crate::config::{PepConfig, parse_domain_list};
+use crate::http_exec::read_with_cap;
+use crate::ssrf::{IpCidr, is_host_allowed, is_scheme_allowed, vet_host};
+
+use reqwest::Url;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Largest allowlist document accepted.
+const MAX_ALLOWLIST_BYTES: usize = 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct RemoteAllowlist {
+    allowed_domains: Vec<String>,
+    #[serde(default)]
+    allowed_cidrs: Vec<String>,
+}
+
+/// Add the remote entries to `config.allowed_domains` and
+/// `config.allowed_cidrs`, returning how many were added. Failures are
+/// logged, never fatal: the static lists still hold.
+pub fn extend_allowlist(config: &mut PepConfig) -> usize {
+    let Some(url) = config.allowlist_url.as_deref() else {
+        return 0;
+    };
+    let entries = match fetch(url, config) {
+        Ok(raw) => {
+            if let Some(path) = &config.allowlist_cache_path
+                && let Err(err) = std::fs::write(path, &raw)
+            {
+                eprintln!("PEP_ALLOWLIST_CACHE {}: {err}", path.display());
+            }
+            parse(&raw)
+        }
+        Err(err) => {
+            eprintln!("PEP_ALLOWLIST_URL fetch failed: {err}");
+            match &config.allowlist_cache_path {
+                Some(path) => std::fs::read(path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|raw| parse(&raw))
+                    .map_err(|err| format!("PEP_ALLOWLIST_CACHE {}: {err}", path.display())),
+                None => Err("no PEP_ALLOWLIST_CACHE to fall back to".to_string()),
+            }
+        }
+    };
+    match entries {
+        Ok((domains, cidrs)) => {
+            let added = domains.len() + cidrs.len();
+            config.allowed_domains.extend(domains);
+            config.allowed_cidrs.extend(cidrs);
+            added
+        }
+        Err(err) => {
+            eprintln!("remote allowlist unavailable, using the static list: {err}");
+            0
+        }
+    }
+}
+
+/// The raw document at `raw_url`, once it parses.
+fn fetch(raw_url: &str, config: &PepConfig) -> Result<Vec<u8>, String> {
+    let url = Url::parse(raw_url).map_err(|err| err.to_string())?;
+    if !is_scheme_allowed(url.scheme()) {
+        return Err(format!("unsupported scheme {}", url.scheme()));
+    }
+    let host = url.host_str().ok_or("missing host")?;
+    if !is_host_allowed(host, &config.allowlist_bootstrap_domains) {
+        return Err(format!("{host} is not in PEP_ALLOWLIST_BOOTSTRAP_DOMAINS"));
+    }
+    let ip = vet_host(&url, config.dns_timeout(), config.allow_private_hosts)
+        .map_err(|err| err.to_string())?;
+    let client = Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(host, SocketAddr::new(ip, 0))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let mut response = client.get(url).send().map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()));
+    }
+    let raw = read_with_cap(&mut response, MAX_ALLOWLIST_BYTES)?;
+    parse(&raw)?;
+    Ok(raw)
+}
+
+/// The domain and CIDR entries. A CIDR entry that does not parse rejects the
+/// document, as it does `PEP_ALLOWED_CIDRS`.
+fn parse(raw: &[u8]) -> Result<(Vec<String>, Vec<IpCidr>), String> {
+    let document: RemoteAllowlist = serde_json::from_slice(raw).map_err(|err| err.to_string())?;
+    let domains = document
+        .allowed_domains
+        .iter()
+        .flat_map(|entry| parse_domain_list(entry))
+        .collect();
+    let cidrs = document
+        .allowed_cidrs
+        .iter()
+        .map(|entry| entry.trim().parse())
+        .collect::<Result<_, String>>()?;
+    Ok((domains, cidrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serve `body` as JSON to one connection.
+    fn serve_once(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        format!("http://{addr}/allowlist.json")
+    }
+
+    fn config(url: &str, cache: &std::path::Path) -> PepConfig {
+        PepConfig::builder()
+            .allowed_domains(vec!["static.example.com".to_string()])
+            .allowlist_url(url)
+            .allowlist_bootstrap_domains(vec!["=127.0.0.1".to_string()])
+            .allowlist_cache_path(cache)
+            .allow_private_hosts(true)
+            .build()
+    }
+
+    #[test]
+    fn fetched_entries_are_added_and_cached_for_a_failed_fetch() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let cache = dir.path().join("allowlist.json");
+        let url = serve_once(
+            r#"{"allowed_domains":["API.example.org", "=cdn.example.net"],
+                "allowed_cidrs":["203.0.113.0/24"]}"#,
+        );
+
+        let mut fetched = config(&url, &cache);
+        assert_eq!(extend_allowlist(&mut fetched), 3);
+        assert_eq!(
+            fetched.allowed_domains,
+            ["static.example.com", "api.example.org", "=cdn.example.net"]
+        );
+        assert_eq!(
+            fetched.allowed_cidrs,
+            ["203.0.113.0/24".parse().expect("cidr")]
+        );
+        assert!(cache.exists());
+
+        // The one-shot server is gone; the cached copy stands in.
+        let mut fallback = config(&url, &cache);
+        assert_eq!(extend_allowlist(&mut fallback), 3);
+        assert_eq!(fallback.allowed_domains, fetched.allowed_domains);
+        assert_eq!(fallback.allowed_cidrs, fetched.allowed_cidrs);
+    }
+
+    #[test]
+    fn a_malformed_cidr_rejects_the_whole_document() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let url = serve_once(
+            r#"{"allowed_domains":["api.example.org"], "allowed_cidrs":["203.0.113.0/99"]}"#,
+        );
+        let mut config = config(&url, &dir.path().join("allowlist.json"));
+        assert_eq!(extend_allowlist(&mut config), 0);
+        assert_eq!(config.allowed_domains, ["static.example.com"]);
+        assert!(config.allowed_cidrs.is_empty());
+    }
+
+    #[test]
+    fn hosts_outside_the_bootstrap_list_are_not_fetched() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let url = serve_once(r#"{"allowed_domains":["evil.example.com"]}"#);
+        let mut config = config(&url, &dir.path().join("allowlist.json"));
+        config.allowlist_bootstrap_domains = vec!["=allowlists.example.com".to_string()];
+        assert_eq!(extend_allowlist(&mut config), 0);
+        assert_eq!(config.allowed_domains, ["static.example.com"]);
+    }
+}
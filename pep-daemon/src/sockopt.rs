@@ -0,0 +1,54 @@
+//! Socket options for accepted connections. `TCP_NODELAY` only means
+//! something on the macOS TCP path; buffer sizes apply to both transports.
+
+use socket2::SockRef;
+use std::io;
+use std::net::TcpStream;
+
+use crate::config::PepConfig;
+
+/// `TCP_NODELAY` (when `PEP_TCP_NODELAY` is set) plus buffer sizes.
+pub fn tune_tcp_stream(stream: &TcpStream, config: &PepConfig) -> io::Result<()> {
+    if config.tcp_nodelay {
+        stream.set_nodelay(true)?;
+    }
+    set_buffer_sizes(SockRef::from(stream), config)
+}
+
+/// `SO_SNDBUF`/`SO_RCVBUF` from `PEP_SOCKET_SEND_BUFFER` and
+/// `PEP_SOCKET_RECV_BUFFER`. The kernel may round or double the value.
+pub fn set_buffer_sizes(socket: SockRef<'_>, config: &PepConfig) -> io::Result<()> {
+    if let Some(bytes) = config.socket_send_buffer_bytes {
+        socket.set_send_buffer_size(bytes)?;
+    }
+    if let Some(bytes) = config.socket_recv_buffer_bytes {
+        socket.set_recv_buffer_size(bytes)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn accepted_stream_gets_configured_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let _client = TcpStream::connect(listener.local_addr().expect("addr")).expect("connect");
+        let (accepted, _) = listener.accept().expect("accept");
+        assert!(!accepted.nodelay().expect("nodelay"));
+
+        let config = PepConfig::builder()
+            .tcp_nodelay(true)
+            .socket_send_buffer_bytes(64 * 1024)
+            .socket_recv_buffer_bytes(64 * 1024)
+            .build();
+        tune_tcp_stream(&accepted, &config).expect("tune");
+
+        assert!(accepted.nodelay().expect("nodelay"));
+        let socket = SockRef::from(&accepted);
+        assert!(socket.send_buffer_size().expect("sndbuf") >= 64 * 1024);
+        assert!(socket.recv_buffer_size().expect("rcvbuf") >= 64 * 1024);
+    }
+}
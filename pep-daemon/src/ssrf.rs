@@ -1,49 +1,367 @@
+use crate::addr_health::AddrHealth;
+
 use reqwest::Url;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub fn is_scheme_allowed(scheme: &str) -> bool {
     matches!(scheme, "http" | "https")
 }
 
+/// With a port list (`PEP_ALLOWED_PORTS`), only those ports are reachable;
+/// a URL without an explicit port uses its scheme's default. `None` allows
+/// any port.
+pub fn is_port_allowed(url: &Url, allowed: Option<&[u16]>) -> bool {
+    match allowed {
+        None => true,
+        Some(ports) => url
+            .port_or_known_default()
+            .is_some_and(|port| ports.contains(&port)),
+    }
+}
+
+/// An address range in CIDR notation (`203.0.113.0/24`, `2001:db8::/32`). A
+/// bare address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpCidr {
+    /// Whether `ip` is in the range. IPv4-mapped IPv6 addresses match as
+    /// their IPv4 form.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32 - self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(network), u128::from(ip), 128 - self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `a` and `b` agree above their low `host_bits` bits.
+fn same_prefix(a: u128, b: u128, host_bits: u8) -> bool {
+    // A /0 shifts by the full width, which `checked_shr` refuses.
+    let shift = u32::from(host_bits);
+    a.checked_shr(shift).unwrap_or(0) == b.checked_shr(shift).unwrap_or(0)
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match raw.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (raw, None),
+        };
+        let network = address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid CIDR {raw}"))?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("invalid CIDR {raw}"))?,
+            None => width,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Comma-separated CIDR ranges. One that does not parse fails the whole
+/// list: skipping it could leave the list empty, which allows every address.
+pub fn parse_cidr_list(raw: &str) -> Result<Vec<IpCidr>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// With CIDR rules (`PEP_ALLOWED_CIDRS`), only addresses inside one of them
+/// are reachable. No rules allows any address the SSRF guard passed.
+pub fn is_ip_allowed(ip: IpAddr, allowed: &[IpCidr]) -> bool {
+    allowed.is_empty() || allowed.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Match `host` against allowlist entries. Plain entries match the domain and
+/// its subdomains; entries with a leading `=` (e.g. `=example.com`) match only
+/// that exact host.
 pub fn is_host_allowed(host: &str, allowlist: &[String]) -> bool {
     if allowlist.is_empty() {
         return false;
     }
     let host = host.trim_end_matches('.').to_lowercase();
     allowlist.iter().any(|entry| {
+        if let Some(exact) = entry.strip_prefix('=') {
+            return host == exact.trim_end_matches('.').to_lowercase();
+        }
         let entry = entry.trim_end_matches('.').to_lowercase();
         host == entry || host.ends_with(&format!(".{entry}"))
     })
 }
 
-pub fn ensure_public_host(url: &Url) -> Result<(), String> {
-    let host = url.host_str().ok_or_else(|| "missing host".to_string())?;
+/// Largest edit distance at which an allowlist entry counts as a near match.
+const MAX_HINT_DISTANCE: usize = 2;
+
+/// The allowlist entry closest to `host`, for a denial hint. Each entry is
+/// compared with the whole host and with the host's trailing labels, so
+/// `api.exampel.com` is within two edits of `example.com`.
+pub fn closest_allowlist_entry<'a>(host: &str, allowlist: &'a [String]) -> Option<&'a str> {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let host_labels: Vec<&str> = host.split('.').collect();
+    allowlist
+        .iter()
+        .filter_map(|entry| {
+            let name = entry.trim_start_matches('=').trim_end_matches('.');
+            let lowered = name.to_lowercase();
+            let labels = lowered.split('.').count();
+            let suffix = host_labels[host_labels.len().saturating_sub(labels)..].join(".");
+            let distance = edit_distance(&host, &lowered).min(edit_distance(&suffix, &lowered));
+            (distance <= MAX_HINT_DISTANCE).then_some((distance, name))
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
 
-    if let Ok(ip) = host.parse::<IpAddr>() {
+/// Levenshtein distance over chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Why `ensure_public_host` refused a host. `code` is the envelope error code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsrfError {
+    Blocked(String),
+    DnsTimeout(Duration),
+}
+
+impl SsrfError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SsrfError::Blocked(_) => "ssrf_blocked",
+            SsrfError::DnsTimeout(_) => "dns_timeout",
+        }
+    }
+}
+
+impl fmt::Display for SsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SsrfError::Blocked(reason) => f.write_str(reason),
+            SsrfError::DnsTimeout(limit) => {
+                write!(f, "dns resolution exceeded {}ms", limit.as_millis())
+            }
+        }
+    }
+}
+
+/// Check that `url`'s host is, and resolves only to, public addresses, and
+/// return the vetted address.
+pub fn ensure_public_host(url: &Url, dns_timeout: Duration) -> Result<IpAddr, SsrfError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| SsrfError::Blocked("missing host".to_string()))?;
+
+    if let Some(ip) = ip_literal(host)? {
         if !is_public_ip(ip) {
-            return Err(format!("blocked ip {ip}"));
+            return Err(SsrfError::Blocked(format!("blocked ip {ip}")));
         }
-        return Ok(());
+        return Ok(ip);
     }
 
+    let addrs = lookup_url_host(url, host, dns_timeout)?;
+    select_public_addr(&addrs)
+        .map(|addr| addr.ip())
+        .map_err(SsrfError::Blocked)
+}
+
+/// `ensure_public_host`, or with `allow_private` (`PEP_ALLOW_PRIVATE_HOSTS`)
+/// a plain lookup that accepts any address. Only for local testing.
+pub fn vet_host(
+    url: &Url,
+    dns_timeout: Duration,
+    allow_private: bool,
+) -> Result<IpAddr, SsrfError> {
+    if !allow_private {
+        return ensure_public_host(url, dns_timeout);
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| SsrfError::Blocked("missing host".to_string()))?;
+    if let Some(ip) = ip_literal(host)? {
+        return Ok(ip);
+    }
+    lookup_url_host(url, host, dns_timeout)?
+        .first()
+        .map(SocketAddr::ip)
+        .ok_or_else(|| SsrfError::Blocked("dns returned no addresses".to_string()))
+}
+
+/// `host` as an IP address, bare or as a bracketed IPv6 literal (the form
+/// `Url::host_str` gives), or `None` for a name to resolve. A zone id
+/// (`[fe80::1%eth0]`) is refused: zones only scope link-local addresses,
+/// which are never public, and no lookup should be tried for one.
+fn ip_literal(host: &str) -> Result<Option<IpAddr>, SsrfError> {
+    let Some(inner) = host
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+    else {
+        return Ok(host.parse::<IpAddr>().ok());
+    };
+    if inner.contains('%') {
+        return Err(SsrfError::Blocked(format!("ipv6 zone id in {host}")));
+    }
+    inner
+        .parse::<Ipv6Addr>()
+        .map(|ip| Some(IpAddr::V6(ip)))
+        .map_err(|_| SsrfError::Blocked(format!("invalid ipv6 literal {host}")))
+}
+
+fn lookup_url_host(
+    url: &Url,
+    host: &str,
+    dns_timeout: Duration,
+) -> Result<Vec<SocketAddr>, SsrfError> {
     let port = url
         .port_or_known_default()
-        .ok_or_else(|| "missing port".to_string())?;
+        .ok_or_else(|| SsrfError::Blocked("missing port".to_string()))?;
 
-    let addrs = (host, port)
-        .to_socket_addrs()
-        .map_err(|err| format!("dns failed: {err}"))?;
+    let host = host.to_string();
+    resolve_with_timeout(
+        move || {
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map(Iterator::collect)
+        },
+        dns_timeout,
+    )
+}
 
-    for addr in addrs {
-        let ip = addr.ip();
-        if !is_public_ip(ip) {
-            return Err(format!("blocked ip {ip}"));
-        }
+/// Run a blocking lookup on its own thread, giving up after `timeout`. The
+/// OS resolver cannot be cancelled, so a late lookup finishes on its thread,
+/// fails to send to the dropped receiver, and the thread exits.
+pub fn resolve_with_timeout<F>(resolve: F, timeout: Duration) -> Result<Vec<SocketAddr>, SsrfError>
+where
+    F: FnOnce() -> io::Result<Vec<SocketAddr>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::Builder::new()
+        .name("pep-dns".to_string())
+        .spawn(move || {
+            let _ = tx.send(resolve());
+        })
+        .map_err(|err| SsrfError::Blocked(format!("dns failed: {err}")))?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(addrs)) => Ok(addrs),
+        Ok(Err(err)) => Err(SsrfError::Blocked(format!("dns failed: {err}"))),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(SsrfError::DnsTimeout(timeout)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(SsrfError::Blocked(
+            "dns failed: resolver exited".to_string(),
+        )),
     }
+}
 
+/// Invariant: every resolved address must be public. A single private record
+/// rejects the host, since a connector trying candidates in turn (or racing
+/// them, happy eyeballs) could otherwise land on it.
+pub fn vet_public_addrs(addrs: &[SocketAddr]) -> Result<(), String> {
+    if addrs.is_empty() {
+        return Err("dns returned no addresses".to_string());
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        return Err(format!("blocked ip {}", addr.ip()));
+    }
     Ok(())
 }
 
+/// Pick the address to connect to from a resolved set, which must pass
+/// `vet_public_addrs`. Among vetted addresses IPv6 is preferred, then IPv4.
+pub fn select_public_addr(addrs: &[SocketAddr]) -> Result<SocketAddr, String> {
+    vet_public_addrs(addrs)?;
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv6())
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| "dns returned no addresses".to_string())
+}
+
+/// DNS resolver for the upstream client that only ever hands the connector
+/// vetted public addresses, so a rebinding answer between the SSRF check and
+/// the connect cannot reach a private address. When a host has several, they
+/// are ordered by `AddrHealth` and the connector moves on to the next when
+/// one refuses.
+#[derive(Debug, Default)]
+pub struct PublicAddrResolver {
+    health: Arc<AddrHealth>,
+}
+
+impl PublicAddrResolver {
+    pub fn new(health: Arc<AddrHealth>) -> Self {
+        Self { health }
+    }
+}
+
+impl Resolve for PublicAddrResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let health = Arc::clone(&self.health);
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+            vet_public_addrs(&addrs)?;
+            let pinned: Addrs = Box::new(health.order(&host, &addrs, Instant::now()).into_iter());
+            Ok(pinned)
+        })
+    }
+}
+
 pub fn is_public_ip(ip: IpAddr) -> bool {
     match ip {
         IpAddr::V4(addr) => is_public_ipv4(addr),
@@ -88,6 +406,61 @@ mod tests {
     use super::*;
     use std::net::IpAddr;
 
+    #[test]
+    fn slow_resolution_times_out() {
+        let started = std::time::Instant::now();
+        let err = resolve_with_timeout(
+            || {
+                thread::sleep(Duration::from_millis(500));
+                Ok(Vec::new())
+            },
+            Duration::from_millis(20),
+        )
+        .expect_err("expected timeout");
+        assert_eq!(err.code(), "dns_timeout");
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn fast_resolution_returns_addresses() {
+        let addr: SocketAddr = "93.184.216.34:443".parse().expect("addr");
+        let addrs =
+            resolve_with_timeout(move || Ok(vec![addr]), Duration::from_secs(5)).expect("resolved");
+        assert_eq!(addrs, vec![addr]);
+    }
+
+    #[test]
+    fn port_allowlist_applies_scheme_defaults() {
+        let url = |raw: &str| Url::parse(raw).expect("url");
+        let ports: &[u16] = &[443];
+        assert!(is_port_allowed(&url("https://example.com/"), Some(ports)));
+        assert!(is_port_allowed(
+            &url("https://example.com:443/"),
+            Some(ports)
+        ));
+        assert!(!is_port_allowed(&url("http://example.com/"), Some(ports)));
+        assert!(!is_port_allowed(
+            &url("https://example.com:8443/"),
+            Some(ports)
+        ));
+        assert!(is_port_allowed(&url("https://example.com:8443/"), None));
+    }
+
+    #[test]
+    fn closest_allowlist_entry_finds_near_misses_only() {
+        let allowlist = vec!["example.com".to_string(), "=api.github.com".to_string()];
+        assert_eq!(
+            closest_allowlist_entry("api.exampel.com", &allowlist),
+            Some("example.com")
+        );
+        assert_eq!(
+            closest_allowlist_entry("api.githb.com", &allowlist),
+            Some("api.github.com")
+        );
+        assert_eq!(closest_allowlist_entry("evil.test", &allowlist), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
     #[test]
     fn host_allowlist_accepts_exact_and_subdomain() {
         let allowlist = vec!["example.com".to_string()];
@@ -97,6 +470,22 @@ mod tests {
         assert!(!is_host_allowed("example.com.evil", &allowlist));
     }
 
+    #[test]
+    fn host_allowlist_anchored_entry_matches_exact_host_only() {
+        let allowlist = vec!["=example.com".to_string()];
+        assert!(is_host_allowed("example.com", &allowlist));
+        assert!(is_host_allowed("Example.com.", &allowlist));
+        assert!(!is_host_allowed("api.example.com", &allowlist));
+        assert!(!is_host_allowed("evil-example.com", &allowlist));
+    }
+
+    #[test]
+    fn host_allowlist_mixes_anchored_and_unanchored_entries() {
+        let allowlist = vec!["=example.com".to_string(), "example.org".to_string()];
+        assert!(!is_host_allowed("www.example.com", &allowlist));
+        assert!(is_host_allowed("www.example.org", &allowlist));
+    }
+
     #[test]
     fn host_allowlist_is_case_insensitive() {
         let allowlist = vec!["Example.COM".to_string()];
@@ -120,6 +509,38 @@ mod tests {
         assert!(is_public_ip(public));
     }
 
+    #[test]
+    fn select_public_addr_rejects_mixed_resolution() {
+        let addrs: Vec<SocketAddr> = vec![
+            "93.184.216.34:443".parse().unwrap(),
+            "10.0.0.5:443".parse().unwrap(),
+        ];
+        let err = select_public_addr(&addrs).expect_err("expected mixed set to be blocked");
+        assert!(err.contains("10.0.0.5"));
+    }
+
+    #[test]
+    fn select_public_addr_prefers_ipv6() {
+        let addrs: Vec<SocketAddr> = vec![
+            "93.184.216.34:443".parse().unwrap(),
+            "[2606:2800:220:1::1]:443".parse().unwrap(),
+        ];
+        let addr = select_public_addr(&addrs).expect("all public");
+        assert!(addr.is_ipv6());
+    }
+
+    #[test]
+    fn select_public_addr_falls_back_to_ipv4() {
+        let addrs: Vec<SocketAddr> = vec!["93.184.216.34:443".parse().unwrap()];
+        let addr = select_public_addr(&addrs).expect("all public");
+        assert_eq!(addr, addrs[0]);
+    }
+
+    #[test]
+    fn select_public_addr_rejects_empty() {
+        assert!(select_public_addr(&[]).is_err());
+    }
+
     #[test]
     fn public_ipv6_blocks_private_ranges() {
         let private_ips = ["::1", "fe80::1", "fc00::1"];
@@ -130,4 +551,66 @@ mod tests {
         let public: IpAddr = "2001:4860:4860::8888".parse().unwrap();
         assert!(is_public_ip(public));
     }
+
+    #[test]
+    fn bracketed_ipv6_hosts_are_classified_without_dns() {
+        let timeout = Duration::from_millis(1);
+        let public = Url::parse("https://[2001:4860:4860::8888]:8443/").unwrap();
+        assert_eq!(
+            ensure_public_host(&public, timeout).unwrap(),
+            "2001:4860:4860::8888".parse::<IpAddr>().unwrap()
+        );
+        for private in ["http://[::1]/", "http://[fe80::1]/", "http://[fc00::1]/"] {
+            let err = ensure_public_host(&Url::parse(private).unwrap(), timeout).unwrap_err();
+            assert!(
+                err.to_string().starts_with("blocked ip"),
+                "{private}: {err}"
+            );
+        }
+        assert_eq!(
+            vet_host(&Url::parse("http://[::1]/").unwrap(), timeout, true).unwrap(),
+            "::1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn ipv6_zone_ids_are_blocked() {
+        for host in ["[fe80::1%eth0]", "[fe80::1%25eth0]", "[::1%lo]"] {
+            let err = ip_literal(host).unwrap_err();
+            assert!(err.to_string().contains("zone id"), "{host}: {err}");
+        }
+        assert!(ip_literal("[not-an-ip]").is_err());
+        assert_eq!(ip_literal("example.com").unwrap(), None);
+        assert_eq!(
+            ip_literal("10.0.0.1").unwrap(),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn cidr_rules_parse_and_match() {
+        let cidrs = parse_cidr_list("203.0.113.0/24, 2001:db8::/32 ,198.51.100.7").expect("cidrs");
+        assert_eq!(
+            cidrs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["203.0.113.0/24", "2001:db8::/32", "198.51.100.7/32"]
+        );
+        let ip = |raw: &str| raw.parse::<IpAddr>().expect("ip");
+        assert!(is_ip_allowed(ip("203.0.113.200"), &cidrs));
+        assert!(is_ip_allowed(ip("::ffff:203.0.113.9"), &cidrs));
+        assert!(is_ip_allowed(ip("2001:db8:1::1"), &cidrs));
+        assert!(is_ip_allowed(ip("198.51.100.7"), &cidrs));
+        assert!(!is_ip_allowed(ip("198.51.100.8"), &cidrs));
+        assert!(!is_ip_allowed(ip("203.0.114.1"), &cidrs));
+        assert!(is_ip_allowed(ip("203.0.114.1"), &[]));
+        assert!(parse_cidr_list("0.0.0.0/0").expect("any")[0].contains(ip("8.8.8.8")));
+
+        for bad in [
+            "10.0.0.0/33",
+            "example.com/8",
+            "10.0.0.0/",
+            "10.0.0.0/8,nope",
+        ] {
+            assert!(parse_cidr_list(bad).is_err(), "{bad}");
+        }
+    }
 }
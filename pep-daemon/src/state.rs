@@ -0,0 +1,73 @@
+use crate::addr_health::AddrHealth;
+use crate::audit::{AuditSink, audit_sink_for};
+use crate::breaker::CircuitBreakers;
+use crate::cache::ResponseCache;
+use crate::clock::{Clock, SystemClock};
+use crate::config::PepConfig;
+use crate::connections::{ConnectionCounter, PeerConnections};
+use crate::fair_queue::Dispatcher;
+use crate::host_concurrency::HostConcurrency;
+use crate::idempotency::IdempotencyCache;
+use crate::inflight::InflightBytes;
+use crate::metrics::Metrics;
+use crate::quota::ByteQuotas;
+use crate::rate_limit::RequestRateLimits;
+
+use reqwest::blocking::Client;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Cross-request runtime state shared by every connection handled by one PEP.
+#[derive(Debug)]
+pub struct PepState {
+    pub quotas: ByteQuotas,
+    pub rate_limits: RequestRateLimits,
+    pub breakers: CircuitBreakers,
+    pub connections: ConnectionCounter,
+    pub peer_connections: PeerConnections,
+    pub cache: ResponseCache,
+    pub idempotency: IdempotencyCache,
+    pub audit: Box<dyn AuditSink>,
+    /// Shared with the client's resolver; see `Pep::with_addr_health`.
+    pub addr_health: Arc<AddrHealth>,
+    /// Source of `context.time*` in policy input.
+    pub clock: Box<dyn Clock>,
+    /// Upstream clients by connect timeout, for hosts with a
+    /// `PEP_CONNECT_TIMEOUTS` entry; see `Pep::with_connect_timeout_clients`.
+    pub connect_clients: HashMap<Duration, Client>,
+    pub metrics: Metrics,
+    /// See `Pep::with_inflight_bytes`.
+    pub inflight: InflightBytes,
+    /// See `Pep::with_dispatcher`.
+    pub dispatcher: Dispatcher,
+    /// See `Pep::with_host_concurrency`.
+    pub host_concurrency: HostConcurrency,
+}
+
+impl PepState {
+    /// Fresh state with the audit sink `config` selects.
+    pub fn new(config: &PepConfig) -> io::Result<Self> {
+        Ok(Self {
+            quotas: ByteQuotas::default(),
+            rate_limits: RequestRateLimits::default(),
+            breakers: CircuitBreakers::default(),
+            connections: ConnectionCounter::default(),
+            peer_connections: PeerConnections::default(),
+            cache: ResponseCache::new(
+                config.response_cache_max_bytes,
+                Duration::from_secs(config.response_cache_ttl_secs),
+            ),
+            idempotency: IdempotencyCache::default(),
+            audit: audit_sink_for(config)?,
+            addr_health: Arc::default(),
+            clock: Box::new(SystemClock),
+            connect_clients: HashMap::new(),
+            metrics: Metrics::default(),
+            inflight: InflightBytes::default(),
+            dispatcher: Dispatcher::default(),
+            host_concurrency: HostConcurrency::default(),
+        })
+    }
+}
@@ -1,5 +1,8 @@
+use crate::policy::ShadowDivergence;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use serde::{Deserialize, Serialize};
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -8,22 +11,218 @@ pub struct HttpRequest {
     pub url: String,
     pub headers: Vec<(String, String)>,
     pub body_base64: Option<String>,
+    /// The body as raw bytes, from a binary frame's body section; set
+    /// instead of `body_base64`.
+    #[serde(skip)]
+    pub body: Option<Vec<u8>>,
+    /// Correlation id echoed in the response and audit log; the daemon
+    /// generates one when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Key shared by retries of one logical request; a retry within
+    /// `PEP_IDEMPOTENCY_TTL_SECS` gets the first successful response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Workspace the request is made for. Requests waiting for a worker are
+    /// served round-robin across workspaces; absent means the VM's CID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace_id: Option<String>,
+    /// Wire protocol version the client speaks; absent means
+    /// `PROTOCOL_VERSION`.
+    #[serde(default = "current_protocol_version")]
+    pub protocol_version: u32,
+    /// Ask for a `text/event-stream` response to be forwarded as it arrives
+    /// (a `head` frame, `StreamChunk` frames, then an `end` frame) instead of
+    /// buffered. Other responses are buffered either way.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    /// Run every check but do not contact the upstream: an allowed request
+    /// gets status `100`, a denied one its usual error. Lets a client learn
+    /// the outcome before sending a large body.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preflight: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl HttpRequest {
+    /// A request with no headers or body, in the current protocol version,
+    /// with every option off.
+    pub fn new(method: &str, url: impl Into<String>) -> Self {
+        Self {
+            method: method.to_string(),
+            url: url.into(),
+            headers: Vec::new(),
+            body_base64: None,
+            body: None,
+            request_id: None,
+            idempotency_key: None,
+            workspace_id: None,
+            protocol_version: PROTOCOL_VERSION,
+            stream: false,
+            preflight: false,
+        }
+    }
+
+    /// Parse a request frame, rejecting the shapes `serde` accepts but the
+    /// daemon cannot act on. Header names and values are checked later, with
+    /// the request audited, as `invalid_header`.
+    pub fn from_frame(frame: &[u8]) -> Result<Self, String> {
+        let request: Self =
+            serde_json::from_slice(frame).map_err(|err| format!("malformed request: {err}"))?;
+        if request.method.trim().is_empty() {
+            return Err("method must not be empty".to_string());
+        }
+        // In-band health checks carry no URL.
+        if request.method != HEALTH_METHOD && request.url.trim().is_empty() {
+            return Err("url must not be empty".to_string());
+        }
+        Ok(request)
+    }
+}
+
+/// Method of an in-band health check frame.
+pub const HEALTH_METHOD: &str = "HEALTH";
+
+/// Keepalive frames: a `{"type":"ping"}` from the client is answered with
+/// `{"type":"pong"}` and has no other effect, so an idle connection can be
+/// kept open and a dead one noticed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Heartbeat {
+    Ping,
+    Pong,
+}
+
+impl Heartbeat {
+    pub fn is_ping(frame: &[u8]) -> bool {
+        matches!(serde_json::from_slice(frame), Ok(Heartbeat::Ping))
+    }
+}
+
+/// Wire protocol version this daemon speaks, sent on every response.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Request protocol versions still served; anything else gets
+/// `unsupported_protocol`.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+fn current_protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+/// Longest client-supplied `request_id` accepted.
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HttpResponse {
     pub status: u16,
+    /// Canonical reason phrase for `status` (e.g. "Not Found"), when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
     pub headers: Vec<(String, String)>,
     pub body_base64: Option<String>,
+    /// The body as raw bytes, for a binary-frame client
+    /// (`RequestContext::raw_body`); set instead of `body_base64`.
+    #[serde(skip)]
+    pub body: Option<Vec<u8>>,
     pub error: Option<ErrorEnvelope>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Limiter state, set on `rate_limited` denials only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_limit: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_remaining: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_reset_secs: Option<u64>,
+    /// Advisory wait before retrying, set on `overloaded` and `host_busy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    #[serde(default = "current_protocol_version")]
+    pub protocol_version: u32,
+    /// Set on the first and last frames of a streamed response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<StreamPhase>,
+    /// The body is the first `max_response_bytes` of a longer upstream body
+    /// (`PEP_TRUNCATE_OVERSIZED`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl HttpResponse {
+    /// Carry `body` raw when `raw` is set, otherwise as `body_base64`.
+    pub fn set_body(&mut self, body: Vec<u8>, raw: bool) {
+        if raw {
+            self.body_base64 = None;
+            self.body = Some(body);
+        } else {
+            self.body_base64 = Some(BASE64.encode(&body));
+            self.body = None;
+        }
+    }
+
+    /// Take the body out in whichever form it is carried; a `body_base64`
+    /// that does not decode counts as no body.
+    pub fn take_body(&mut self) -> Option<Vec<u8>> {
+        let encoded = self.body_base64.take();
+        self.body
+            .take()
+            .or_else(|| encoded.and_then(|encoded| BASE64.decode(encoded).ok()))
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamPhase {
+    /// Status and headers; body chunks follow.
+    Head,
+    /// No more chunks. `error` is set when the stream was cut short.
+    End,
+}
+
+/// One body chunk of a streamed response, between its `head` and `end`
+/// frames.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct StreamChunk {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub chunk_base64: String,
+    /// The chunk as raw bytes, for a binary-frame client; `chunk_base64` is
+    /// left empty.
+    #[serde(skip)]
+    pub chunk: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ErrorEnvelope {
     pub code: String,
     pub message: String,
 }
 
+/// Daemon-side facts about a request, recorded in its audit entries. Never
+/// taken from the request payload, so a VM cannot spoof them.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    /// vsock CID of the connecting VM (Linux only).
+    pub peer_cid: Option<u32>,
+    /// Set when the shadow policy disagreed with the enforced decision.
+    pub shadow: Option<ShadowDivergence>,
+    /// Set once `PEP_ALLOW_PRIVATE_HOSTS` has skipped the SSRF guard.
+    pub ssrf_bypassed: bool,
+    /// Category of the `PEP_DLP_PATTERNS` entry the request body matched.
+    pub dlp_category: Option<String>,
+    /// Set when the delivered body did not match the upstream's
+    /// `Content-Length`.
+    pub content_length_mismatch: Option<String>,
+    /// Set from `PEP_AUDIT_HEADERS`: entries carry the request's headers,
+    /// and the upstream's once there are some, with secrets masked.
+    pub audit_headers: bool,
+    pub response_headers: Option<Vec<(String, String)>>,
+    /// Set for a binary-frame client: response bodies are carried raw in
+    /// `HttpResponse::body` and `StreamChunk::chunk`, never base64-encoded.
+    pub raw_body: bool,
+}
+
 #[derive(Debug, Error)]
 pub enum PepError {
     #[error("io error: {0}")]
@@ -34,16 +233,88 @@ pub enum PepError {
     Http(#[from] reqwest::Error),
     #[error("policy error: {0}")]
     Policy(String),
+    #[error("config error: {0}")]
+    Config(String),
+    #[error("boot_timeout: swift runner still running after {0:?}; killed")]
+    BootTimeout(Duration),
 }
 
 pub fn error_response(code: &str, message: &str) -> HttpResponse {
     HttpResponse {
         status: 0,
+        status_text: None,
         headers: Vec::new(),
         body_base64: None,
+        body: None,
         error: Some(ErrorEnvelope {
             code: code.to_string(),
             message: message.to_string(),
         }),
+        request_id: None,
+        rate_limit_limit: None,
+        rate_limit_remaining: None,
+        rate_limit_reset_secs: None,
+        retry_after_ms: None,
+        protocol_version: PROTOCOL_VERSION,
+        stream: None,
+        truncated: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_response_without_status_text_deserializes() {
+        let raw = r#"{"status":200,"headers":[],"body_base64":null,"error":null}"#;
+        let response: HttpResponse = serde_json::from_str(raw).expect("parse");
+        assert_eq!(response.status, 200);
+        assert!(response.status_text.is_none());
+    }
+
+    #[test]
+    fn http_response_omits_absent_status_text() {
+        let json = serde_json::to_string(&error_response("http_error", "boom")).expect("json");
+        assert!(!json.contains("status_text"));
+    }
+
+    #[test]
+    fn absent_protocol_version_means_current() {
+        let frame = br#"{"method":"GET","url":"https://example.com/","headers":[]}"#;
+        let request = HttpRequest::from_frame(frame).expect("request");
+        assert_eq!(request.protocol_version, PROTOCOL_VERSION);
+
+        let frame =
+            br#"{"method":"GET","url":"https://example.com/","headers":[],"protocol_version":7}"#;
+        let request = HttpRequest::from_frame(frame).expect("request");
+        assert_eq!(request.protocol_version, 7);
+    }
+
+    #[test]
+    fn malformed_request_frames_are_rejected() {
+        let cases: &[&[u8]] = &[
+            b"not json",
+            br#"{"url":"https://example.com/","headers":[]}"#,
+            br#"{"method":" ","url":"https://example.com/","headers":[]}"#,
+            br#"{"method":"GET","url":"","headers":[]}"#,
+            br#"{"method":"GET","url":"https://example.com/","headers":{"a":"b"}}"#,
+            br#"{"method":"GET","url":"https://example.com/","headers":[["a"]]}"#,
+            br#"{"method":"GET","url":7,"headers":[]}"#,
+        ];
+        for frame in cases {
+            assert!(
+                HttpRequest::from_frame(frame).is_err(),
+                "accepted {}",
+                String::from_utf8_lossy(frame)
+            );
+        }
+
+        let request = HttpRequest::from_frame(
+            br#"{"method":"GET","url":"https://example.com/","headers":[["accept","*/*"]]}"#,
+        )
+        .expect("valid frame");
+        assert_eq!(request.headers.len(), 1);
+        assert!(HttpRequest::from_frame(br#"{"method":"HEALTH","url":"","headers":[]}"#).is_ok());
     }
 }
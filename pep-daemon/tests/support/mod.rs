@@ -0,0 +1,95 @@
+//! Loopback mock upstream for the end-to-end tests.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How the mock answers a path.
+#[derive(Clone)]
+pub enum Reply {
+    /// `200 OK` with this body.
+    Ok(&'static str),
+    /// `200 OK` with a body of this many bytes.
+    Sized(usize),
+    /// `302 Found` to this `Location`.
+    Redirect(&'static str),
+    /// `200 OK` with this body, after holding the request this long.
+    Slow(Duration, &'static str),
+}
+
+/// An HTTP/1.1 server on `127.0.0.1` answering each path from a fixed route
+/// table (`404` otherwise), one thread per connection. It runs until the test
+/// process exits.
+pub struct MockUpstream {
+    base: String,
+    hits: Arc<AtomicUsize>,
+}
+
+impl MockUpstream {
+    pub fn start(routes: &[(&'static str, Reply)]) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let base = format!("http://{}", listener.local_addr().expect("addr"));
+        let hits = Arc::new(AtomicUsize::new(0));
+        let routes: Arc<[(&'static str, Reply)]> = routes.into();
+        let counter = Arc::clone(&hits);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let routes = Arc::clone(&routes);
+                let counter = Arc::clone(&counter);
+                thread::spawn(move || serve(stream, &routes, &counter));
+            }
+        });
+        Self { base, hits }
+    }
+
+    /// Absolute URL of `path` on the mock.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    /// Requests received so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+fn serve(mut stream: TcpStream, routes: &[(&'static str, Reply)], hits: &AtomicUsize) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => head.extend_from_slice(&buf[..read]),
+        }
+    }
+    hits.fetch_add(1, Ordering::SeqCst);
+    let head = String::from_utf8_lossy(&head);
+    let path = head.split_whitespace().nth(1).unwrap_or("/");
+    let reply = routes
+        .iter()
+        .find(|(route, _)| *route == path)
+        .map(|(_, reply)| reply.clone());
+    let (status, extra, body) = match reply {
+        Some(Reply::Ok(body)) => ("200 OK", String::new(), body.to_string()),
+        Some(Reply::Sized(len)) => ("200 OK", String::new(), "x".repeat(len)),
+        Some(Reply::Redirect(location)) => (
+            "302 Found",
+            format!("Location: {location}\r\n"),
+            String::new(),
+        ),
+        Some(Reply::Slow(delay, body)) => {
+            thread::sleep(delay);
+            ("200 OK", String::new(), body.to_string())
+        }
+        None => ("404 Not Found", String::new(), String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\n{extra}Content-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
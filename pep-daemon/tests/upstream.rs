@@ -0,0 +1,136 @@
+//! End-to-end requests through `Pep` to a real HTTP server on loopback, with
+//! the SSRF guard's private-host escape hatch on so it can be reached.
+
+mod support;
+
+use avf_vsock_host::addr_health::AddrHealth;
+use avf_vsock_host::http_exec::build_client;
+use avf_vsock_host::{HttpRequest, HttpResponse, NullEvaluator, Pep, PepConfig};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use support::{MockUpstream, Reply};
+
+struct Harness {
+    pep: Pep,
+    _dir: tempfile::TempDir,
+}
+
+impl Harness {
+    /// A Pep allowing only `allowed`, through the daemon's own client.
+    fn new(allowed: &str, request_timeout: Duration) -> Self {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let config = PepConfig::builder()
+            .allowed_domains(vec![allowed.to_string()])
+            .max_response_bytes(4096)
+            .max_redirects(3)
+            .allow_private_hosts(true)
+            .audit_log_path(dir.path().join("audit.jsonl"))
+            .build();
+        let client = build_client(
+            &config,
+            Duration::from_secs(5),
+            request_timeout,
+            &Arc::new(AddrHealth::default()),
+        )
+        .expect("client");
+        let evaluator = NullEvaluator::new(config.allowed_domains.clone());
+        let pep = Pep::new(client, config, Box::new(evaluator)).expect("pep");
+        Self { pep, _dir: dir }
+    }
+
+    fn get(&self, url: &str) -> HttpResponse {
+        self.pep.execute(HttpRequest::new("GET", url))
+    }
+}
+
+fn body(response: &HttpResponse) -> Vec<u8> {
+    BASE64
+        .decode(response.body_base64.as_deref().unwrap_or_default())
+        .expect("base64 body")
+}
+
+fn error_code(response: &HttpResponse) -> &str {
+    response
+        .error
+        .as_ref()
+        .map(|error| error.code.as_str())
+        .unwrap_or("none")
+}
+
+#[test]
+fn allowed_request_returns_the_upstream_response() {
+    let upstream = MockUpstream::start(&[("/hello", Reply::Ok("hello from upstream"))]);
+    let harness = Harness::new("=127.0.0.1", Duration::from_secs(5));
+
+    let response = harness.get(&upstream.url("/hello"));
+    assert!(response.error.is_none(), "{:?}", response.error);
+    assert_eq!(response.status, 200);
+    assert_eq!(body(&response), b"hello from upstream");
+    assert_eq!(upstream.hits(), 1);
+}
+
+#[test]
+fn denied_request_never_reaches_the_upstream() {
+    let upstream = MockUpstream::start(&[("/hello", Reply::Ok("hello"))]);
+    let harness = Harness::new("=allowed.example", Duration::from_secs(5));
+
+    let response = harness.get(&upstream.url("/hello"));
+    assert_eq!(error_code(&response), "DENIED_BY_POLICY");
+    assert_eq!(upstream.hits(), 0);
+}
+
+#[test]
+fn redirects_within_the_allowlist_are_followed() {
+    let upstream = MockUpstream::start(&[
+        ("/start", Reply::Redirect("/middle")),
+        ("/middle", Reply::Redirect("/final")),
+        ("/final", Reply::Ok("arrived")),
+    ]);
+    let harness = Harness::new("=127.0.0.1", Duration::from_secs(5));
+
+    let response = harness.get(&upstream.url("/start"));
+    assert!(response.error.is_none(), "{:?}", response.error);
+    assert_eq!(body(&response), b"arrived");
+    assert_eq!(upstream.hits(), 3);
+}
+
+#[test]
+fn redirect_off_the_allowlist_is_blocked() {
+    let upstream = MockUpstream::start(&[(
+        "/start",
+        Reply::Redirect("http://denied.example/exfiltrate"),
+    )]);
+    let harness = Harness::new("=127.0.0.1", Duration::from_secs(5));
+
+    let response = harness.get(&upstream.url("/start"));
+    assert_eq!(error_code(&response), "redirect_blocked");
+    assert_eq!(upstream.hits(), 1);
+}
+
+#[test]
+fn response_over_the_body_cap_is_refused() {
+    let upstream = MockUpstream::start(&[
+        ("/small", Reply::Ok("fits")),
+        ("/large", Reply::Sized(4097)),
+    ]);
+    let harness = Harness::new("=127.0.0.1", Duration::from_secs(5));
+
+    assert!(harness.get(&upstream.url("/small")).error.is_none());
+    let response = harness.get(&upstream.url("/large"));
+    assert_eq!(error_code(&response), "constraint_violation");
+    assert!(response.body_base64.is_none());
+}
+
+#[test]
+fn slow_upstream_times_out() {
+    let upstream =
+        MockUpstream::start(&[("/slow", Reply::Slow(Duration::from_secs(5), "too late"))]);
+    let harness = Harness::new("=127.0.0.1", Duration::from_millis(200));
+
+    let started = Instant::now();
+    let response = harness.get(&upstream.url("/slow"));
+    assert_eq!(error_code(&response), "http_error");
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
@@ -21,14 +21,15 @@ decision := result if {
 	}
 }
 
-# Exact domain match.
+# Exact domain match. A leading "=" anchors the entry (exact match only).
 host_allowed(host) if {
 	some domain in data.config.allowed_domains
-	host == domain
+	host == trim_prefix(domain, "=")
 }
 
 # Subdomain match (e.g. api.example.com matches example.com).
 host_allowed(host) if {
 	some domain in data.config.allowed_domains
+	not startswith(domain, "=")
 	endswith(host, concat("", [".", domain]))
 }
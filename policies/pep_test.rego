@@ -72,6 +72,52 @@ test_allow_subdomain if {
 	result.allow == true
 }
 
+# Anchored ("=") entries allow the exact host.
+test_allow_anchored_exact_host if {
+	result := pep.decision with input as {
+		"action": {
+			"type": "http.request",
+			"resource": {
+				"host": "example.com",
+				"scheme": "https",
+				"url": "https://example.com/",
+				"method": "GET",
+				"path": "/",
+			},
+		},
+		"subject": {"user_id": "test", "workspace_id": "test"},
+		"context": {"time": "0", "stage": "test", "mode": "test"},
+	}
+		with data.config as {
+			"allowed_domains": ["=example.com"],
+			"constraints": {},
+		}
+	result.allow == true
+}
+
+# Anchored ("=") entries do not match subdomains.
+test_deny_subdomain_of_anchored_entry if {
+	result := pep.decision with input as {
+		"action": {
+			"type": "http.request",
+			"resource": {
+				"host": "api.example.com",
+				"scheme": "https",
+				"url": "https://api.example.com/",
+				"method": "GET",
+				"path": "/",
+			},
+		},
+		"subject": {"user_id": "test", "workspace_id": "test"},
+		"context": {"time": "0", "stage": "test", "mode": "test"},
+	}
+		with data.config as {
+			"allowed_domains": ["=example.com"],
+			"constraints": {},
+		}
+	result.allow == false
+}
+
 # Non-HTTP schemes are denied even for allowlisted domains.
 test_deny_non_http_scheme if {
 	result := pep.decision with input as {